@@ -4,8 +4,31 @@ fn main() {
     println!("cargo:rerun-if-changed=../common/protobuf/system_types.proto");
     println!("Change in build !!!!!!!!");
 
-    prost_build
-        ::compile_protos(&["../common/protobuf/system_types.proto"], &["../common/protobuf"])
+    // Serde is used to compare versions of a node field by field and to read and write bundles as JSON or YAML. The timestamp type doesn't implement it, so the one field using it is skipped.
+    let mut config = prost_build::Config::new();
+    config
+        .type_attribute(".", "#[derive(serde::Serialize, serde::Deserialize)]")
+        .field_attribute(".skynet.types.Log.timestamp", "#[serde(skip)]");
+
+    // Fields missing from JSON or YAML get their protobuf default, like they do when decoding protobuf. Only messages can have a default, so they are picked out of the proto file. The attribute would also land on the oneofs inside of a message, so messages with a oneof are left out (a missing oneof is still read as None).
+    let proto = std::fs::read_to_string("../common/protobuf/system_types.proto")
+        .expect("Failed to read the proto file");
+    let mut messages: Vec<(String, bool)> = Vec::new();
+    for line in proto.lines() {
+        if let Some(message) = line.strip_prefix("message ") {
+            messages.push((message.trim_end_matches('{').trim().to_string(), false));
+        } else if line.trim_start().starts_with("oneof ") {
+            if let Some((_, has_oneof)) = messages.last_mut() {
+                *has_oneof = true;
+            }
+        }
+    }
+    for (name, _) in messages.iter().filter(|(_, has_oneof)| !has_oneof) {
+        config.type_attribute(format!(".skynet.types.{}", name), "#[serde(default)]");
+    }
+
+    config
+        .compile_protos(&["../common/protobuf/system_types.proto"], &["../common/protobuf"])
         .expect("Failed to compile protos");
 
     println!("Should compile types to: {:?}", std::env::var("OUT_DIR"));
@@ -0,0 +1,508 @@
+use crate::generated_types::{Execution, Node, TeamRoles, Visibility};
+use crate::graph::collect_nested_nodes;
+use crate::sqlite_helper_functions::{
+    fetch_node, fetch_node_version, fetch_team_roles, latest_recorded_version, StoredExecution,
+};
+
+use colored::*;
+use r2d2::Pool;
+use r2d2_sqlite::SqliteConnectionManager;
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+// The user asking for something, along with their role in each team they are a member of
+pub struct Viewer {
+    pub email: String,
+    pub team_roles: HashMap<String, TeamRoles>,
+}
+
+impl Viewer {
+    pub fn load(pool: Arc<Pool<SqliteConnectionManager>>, email: Option<&String>) -> Viewer {
+        let email = email.cloned().unwrap_or_default();
+
+        // Without their teams the user can still get to their own nodes
+        let team_roles = match fetch_team_roles(pool, &email) {
+            Ok(team_roles) => team_roles,
+            Err(err) => {
+                println!(
+                    "{} {:?}",
+                    "Unable to fetch the teams of the user:".red(),
+                    err
+                );
+                HashMap::new()
+            }
+        };
+
+        Viewer { email, team_roles }
+    }
+
+    pub fn has_role(&self, team_id: &str, role: TeamRoles) -> bool {
+        self.team_roles
+            .get(team_id)
+            .is_some_and(|team_role| *team_role >= role)
+    }
+}
+
+pub fn same_email(email: &str, other_email: &str) -> bool {
+    email.trim().eq_ignore_ascii_case(other_email.trim())
+}
+
+pub fn can_view(node: &Node, viewer: &Viewer) -> bool {
+    if can_edit(node, viewer) || viewer.has_role(&node.team_id, TeamRoles::TeamViewer) {
+        return true;
+    }
+
+    match Visibility::try_from(node.visibility).unwrap_or(Visibility::Private) {
+        Visibility::Public => true,
+        Visibility::Shared => node
+            .shared_with
+            .iter()
+            .any(|shared_email| same_email(shared_email, &viewer.email)),
+        Visibility::Private => false,
+    }
+}
+
+// Nodes in the library of a team are up to the editors of the team. Nodes without an owner are from before owners existed, so anyone can change them.
+pub fn can_edit(node: &Node, viewer: &Viewer) -> bool {
+    if !node.team_id.is_empty() {
+        return viewer.has_role(&node.team_id, TeamRoles::TeamEditor);
+    }
+
+    node.owner_email.is_empty() || same_email(&node.owner_email, &viewer.email)
+}
+
+// Members of the team need to be runners to run the nodes of its library. Anyone else can run what they can see.
+pub fn can_run(node: &Node, viewer: &Viewer) -> bool {
+    if viewer.team_roles.contains_key(&node.team_id) {
+        return viewer.has_role(&node.team_id, TeamRoles::TeamRunner);
+    }
+
+    can_view(node, viewer)
+}
+
+fn tidy_shared_with(node: &mut Node) {
+    let mut shared_with: Vec<String> = node
+        .shared_with
+        .iter()
+        .map(|shared_email| shared_email.trim().to_lowercase())
+        .filter(|shared_email| !shared_email.is_empty())
+        .collect();
+    shared_with.sort();
+    shared_with.dedup();
+
+    node.shared_with = shared_with;
+}
+
+// The owner always comes from the backend, never from what the client sent: it is whoever saved the node first. The node can only be put in the library of a team the user is an editor of, and whoever takes it out of a library becomes its owner.
+pub fn place_node(
+    node: &mut Node,
+    saved_node: Option<&Node>,
+    viewer: &Viewer,
+) -> Result<(), String> {
+    let saved_team_id = saved_node
+        .map(|saved_node| saved_node.team_id.clone())
+        .unwrap_or_default();
+
+    node.owner_email = saved_node
+        .map(|saved_node| saved_node.owner_email.clone())
+        .unwrap_or_else(|| viewer.email.clone());
+
+    if node.team_id != saved_team_id {
+        if node.team_id.is_empty() {
+            node.owner_email = viewer.email.clone();
+        } else if !viewer.has_role(&node.team_id, TeamRoles::TeamEditor) {
+            return Err(format!(
+                "Only editors of team {} can add nodes to its library",
+                node.team_id
+            ));
+        }
+    }
+
+    tidy_shared_with(node);
+    Ok(())
+}
+
+// For saves that replace the node without meaning to change who it belongs to
+pub fn keep_placement(node: &mut Node, saved_node: &Node) {
+    node.owner_email = saved_node.owner_email.clone();
+    node.team_id = saved_node.team_id.clone();
+    node.visibility = saved_node.visibility;
+    node.shared_with = saved_node.shared_with.clone();
+}
+
+fn node_name(node: &Node) -> String {
+    node.node_info.clone().unwrap_or_default().name
+}
+
+fn not_allowed_to_edit(node: &Node) -> String {
+    if node.team_id.is_empty() {
+        format!(
+            "{} belongs to {}, only they can change it",
+            node_name(node),
+            node.owner_email
+        )
+    } else {
+        format!(
+            "{} is in the library of team {}, only its editors can change it",
+            node_name(node),
+            node.team_id
+        )
+    }
+}
+
+// The saved node, if there is one, as long as the user is allowed to change it
+pub fn editable_node(
+    pool: Arc<Pool<SqliteConnectionManager>>,
+    node_id: &str,
+    viewer: &Viewer,
+) -> Result<Option<Node>, String> {
+    match fetch_node(pool, node_id) {
+        Ok(Some(node)) if can_edit(&node, viewer) => Ok(Some(node)),
+        // Nodes the user can't see are treated as if they weren't there
+        Ok(Some(node)) if !can_view(&node, viewer) => Err(format!("There is no node {}", node_id)),
+        Ok(Some(node)) => Err(not_allowed_to_edit(&node)),
+        Ok(None) => Ok(None),
+        Err(err) => Err(format!("Unable to look up node {}: {:?}", node_id, err)),
+    }
+}
+
+// The history of a deleted node is still around, so it is checked against the last recorded version of the node
+pub fn check_history_access(
+    pool: Arc<Pool<SqliteConnectionManager>>,
+    node_id: &str,
+    viewer: &Viewer,
+    edit: bool,
+) -> Result<(), String> {
+    let node = match fetch_node(pool.clone(), node_id) {
+        Ok(Some(node)) => Some(node),
+        Ok(None) => latest_recorded_version(pool.clone(), node_id)
+            .and_then(|version| fetch_node_version(pool, node_id, version))
+            .map_err(|err| format!("Unable to look up node {}: {:?}", node_id, err))?
+            .and_then(|node_version| node_version.node),
+        Err(err) => return Err(format!("Unable to look up node {}: {:?}", node_id, err)),
+    };
+
+    match node {
+        Some(node) if !can_view(&node, viewer) => Err(format!("There is no node {}", node_id)),
+        Some(node) if edit && !can_edit(&node, viewer) => Err(not_allowed_to_edit(&node)),
+        // Without any saved state there is nothing to protect, the query itself reports the missing node
+        _ => Ok(()),
+    }
+}
+
+// Executions carry their nodes with them. The saved ones among them (nested nodes included) have to pass the check, the others were put together by the user themselves.
+pub fn check_execution_access(
+    pool: Arc<Pool<SqliteConnectionManager>>,
+    execution: &Execution,
+    viewer: &Viewer,
+    allowed: fn(&Node, &Viewer) -> bool,
+) -> Result<(), String> {
+    let mut nested_nodes = HashMap::new();
+    for node in execution
+        .process
+        .iter()
+        .flat_map(|process| process.nodes.iter())
+    {
+        collect_nested_nodes(node, &mut nested_nodes);
+    }
+
+    for node_id in nested_nodes.keys() {
+        match fetch_node(pool.clone(), node_id) {
+            Ok(Some(saved_node)) if !allowed(&saved_node, viewer) => {
+                return Err(format!(
+                    "You aren't allowed to run {}",
+                    node_name(&saved_node)
+                ))
+            }
+            Ok(_) => {}
+            Err(err) => return Err(format!("Unable to look up node {}: {:?}", node_id, err)),
+        }
+    }
+
+    Ok(())
+}
+
+// The execution is shared with the team whose library its nodes come from, as long as the user running it is a member of that team
+pub fn execution_team(
+    pool: Arc<Pool<SqliteConnectionManager>>,
+    execution: &Execution,
+    viewer: &Viewer,
+) -> String {
+    execution
+        .process
+        .iter()
+        .flat_map(|process| process.nodes.iter())
+        .filter_map(|node| node.node_info.as_ref())
+        .filter_map(|node_info| fetch_node(pool.clone(), &node_info.id).ok().flatten())
+        .map(|saved_node| saved_node.team_id)
+        .find(|team_id| !team_id.is_empty() && viewer.team_roles.contains_key(team_id))
+        .unwrap_or_default()
+}
+
+// Stored executions hold the variables and outputs of the run, so only whoever ran it and the members of its team (with at least the given role) get to them. Anyone else is told there is no such execution.
+pub fn check_execution_owner(
+    stored_execution: &StoredExecution,
+    viewer: &Viewer,
+    role: TeamRoles,
+) -> Result<(), String> {
+    let is_executor = !stored_execution.executor_email.is_empty()
+        && same_email(&stored_execution.executor_email, &viewer.email);
+
+    if is_executor || viewer.has_role(&stored_execution.team_id, role) {
+        Ok(())
+    } else {
+        Err(format!(
+            "No execution found with id: {}",
+            stored_execution.execution.execution_id
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OWNER: &str = "owner@example.com";
+    const OTHER: &str = "other@example.com";
+    const TEAM: &str = "team";
+
+    fn viewer(email: &str, team_role: Option<TeamRoles>) -> Viewer {
+        Viewer {
+            email: email.to_string(),
+            team_roles: team_role
+                .map(|role| HashMap::from([(TEAM.to_string(), role)]))
+                .unwrap_or_default(),
+        }
+    }
+
+    fn node(owner_email: &str, visibility: Visibility) -> Node {
+        Node {
+            owner_email: owner_email.to_string(),
+            visibility: visibility as i32,
+            ..Default::default()
+        }
+    }
+
+    fn team_node(visibility: Visibility) -> Node {
+        Node {
+            team_id: TEAM.to_string(),
+            ..node(OWNER, visibility)
+        }
+    }
+
+    #[test]
+    fn team_roles_include_the_roles_below_them() {
+        let admin = viewer(OTHER, Some(TeamRoles::TeamAdmin));
+        assert!(admin.has_role(TEAM, TeamRoles::TeamViewer));
+        assert!(admin.has_role(TEAM, TeamRoles::TeamEditor));
+
+        let runner = viewer(OTHER, Some(TeamRoles::TeamRunner));
+        assert!(runner.has_role(TEAM, TeamRoles::TeamViewer));
+        assert!(runner.has_role(TEAM, TeamRoles::TeamRunner));
+        assert!(!runner.has_role(TEAM, TeamRoles::TeamEditor));
+
+        assert!(!runner.has_role("another team", TeamRoles::TeamViewer));
+    }
+
+    #[test]
+    fn private_nodes_are_only_for_their_owner() {
+        let node = node(OWNER, Visibility::Private);
+
+        // Emails are compared the way users type them
+        let owner = viewer(" Owner@Example.com", None);
+        assert!(can_view(&node, &owner));
+        assert!(can_edit(&node, &owner));
+        assert!(can_run(&node, &owner));
+
+        let other = viewer(OTHER, None);
+        assert!(!can_view(&node, &other));
+        assert!(!can_edit(&node, &other));
+        assert!(!can_run(&node, &other));
+    }
+
+    #[test]
+    fn shared_nodes_can_be_seen_and_run_by_whoever_they_are_shared_with() {
+        let node = Node {
+            shared_with: vec!["Other@Example.com".to_string()],
+            ..node(OWNER, Visibility::Shared)
+        };
+
+        let other = viewer(OTHER, None);
+        assert!(can_view(&node, &other));
+        assert!(can_run(&node, &other));
+        assert!(!can_edit(&node, &other));
+
+        let stranger = viewer("stranger@example.com", None);
+        assert!(!can_view(&node, &stranger));
+        assert!(!can_run(&node, &stranger));
+    }
+
+    #[test]
+    fn public_nodes_can_be_seen_and_run_by_everyone() {
+        let node = node(OWNER, Visibility::Public);
+        let other = viewer(OTHER, None);
+
+        assert!(can_view(&node, &other));
+        assert!(can_run(&node, &other));
+        assert!(!can_edit(&node, &other));
+    }
+
+    #[test]
+    fn nodes_without_an_owner_can_be_changed_by_anyone() {
+        let node = node("", Visibility::Private);
+        let other = viewer(OTHER, None);
+
+        assert!(can_edit(&node, &other));
+        assert!(can_view(&node, &other));
+        assert!(can_run(&node, &other));
+    }
+
+    #[test]
+    fn team_nodes_follow_the_role_of_the_member() {
+        let node = team_node(Visibility::Private);
+
+        let team_viewer = viewer(OTHER, Some(TeamRoles::TeamViewer));
+        assert!(can_view(&node, &team_viewer));
+        assert!(!can_run(&node, &team_viewer));
+        assert!(!can_edit(&node, &team_viewer));
+
+        let runner = viewer(OTHER, Some(TeamRoles::TeamRunner));
+        assert!(can_run(&node, &runner));
+        assert!(!can_edit(&node, &runner));
+
+        let editor = viewer(OTHER, Some(TeamRoles::TeamEditor));
+        assert!(can_edit(&node, &editor));
+        assert!(can_run(&node, &editor));
+
+        let admin = viewer(OTHER, Some(TeamRoles::TeamAdmin));
+        assert!(can_edit(&node, &admin));
+    }
+
+    #[test]
+    fn team_nodes_belong_to_the_team_rather_than_their_owner() {
+        let owner = viewer(OWNER, None);
+
+        let private = team_node(Visibility::Private);
+        assert!(!can_edit(&private, &owner));
+        assert!(!can_view(&private, &owner));
+
+        // Outside the team only the visibility counts
+        let public = team_node(Visibility::Public);
+        assert!(can_view(&public, &owner));
+        assert!(can_run(&public, &owner));
+        assert!(!can_edit(&public, &owner));
+    }
+
+    #[test]
+    fn new_nodes_belong_to_whoever_saves_them() {
+        let mut node = Node {
+            owner_email: "someone@else.com".to_string(),
+            shared_with: vec![
+                " B@example.com".to_string(),
+                "a@example.com".to_string(),
+                "b@example.com".to_string(),
+                "".to_string(),
+            ],
+            ..Default::default()
+        };
+
+        place_node(&mut node, None, &viewer(OWNER, None)).unwrap();
+
+        assert_eq!(node.owner_email, OWNER);
+        assert_eq!(node.shared_with, vec!["a@example.com", "b@example.com"]);
+    }
+
+    #[test]
+    fn saved_nodes_keep_their_owner() {
+        let saved_node = node(OWNER, Visibility::Public);
+        let mut node = node(OTHER, Visibility::Public);
+
+        place_node(&mut node, Some(&saved_node), &viewer(OTHER, None)).unwrap();
+
+        assert_eq!(node.owner_email, OWNER);
+    }
+
+    #[test]
+    fn only_team_editors_can_put_nodes_in_a_library() {
+        let mut node = team_node(Visibility::Private);
+        assert!(place_node(&mut node, None, &viewer(OTHER, Some(TeamRoles::TeamRunner))).is_err());
+
+        let mut node = team_node(Visibility::Private);
+        assert!(place_node(&mut node, None, &viewer(OTHER, Some(TeamRoles::TeamEditor))).is_ok());
+    }
+
+    #[test]
+    fn taking_a_node_out_of_a_library_makes_it_yours() {
+        let saved_node = team_node(Visibility::Private);
+        let mut node = node(OWNER, Visibility::Private);
+
+        place_node(
+            &mut node,
+            Some(&saved_node),
+            &viewer(OTHER, Some(TeamRoles::TeamEditor)),
+        )
+        .unwrap();
+
+        assert_eq!(node.owner_email, OTHER);
+        assert!(node.team_id.is_empty());
+    }
+
+    #[test]
+    fn replacing_a_node_keeps_where_it_was_placed() {
+        let saved_node = Node {
+            shared_with: vec![OTHER.to_string()],
+            ..team_node(Visibility::Shared)
+        };
+        let mut node = node("", Visibility::Public);
+
+        keep_placement(&mut node, &saved_node);
+
+        assert_eq!(node.owner_email, OWNER);
+        assert_eq!(node.team_id, TEAM);
+        assert_eq!(node.visibility, Visibility::Shared as i32);
+        assert_eq!(node.shared_with, vec![OTHER]);
+    }
+
+    #[test]
+    fn executions_are_only_for_their_executor_and_team() {
+        let stored_execution = StoredExecution {
+            execution: Execution::default(),
+            executor_email: OWNER.to_string(),
+            team_id: TEAM.to_string(),
+        };
+
+        assert!(check_execution_owner(
+            &stored_execution,
+            &viewer(OWNER, None),
+            TeamRoles::TeamRunner
+        )
+        .is_ok());
+        assert!(check_execution_owner(
+            &stored_execution,
+            &viewer(OTHER, Some(TeamRoles::TeamRunner)),
+            TeamRoles::TeamRunner
+        )
+        .is_ok());
+        assert!(check_execution_owner(
+            &stored_execution,
+            &viewer(OTHER, Some(TeamRoles::TeamViewer)),
+            TeamRoles::TeamRunner
+        )
+        .is_err());
+        assert!(check_execution_owner(
+            &stored_execution,
+            &viewer(OTHER, None),
+            TeamRoles::TeamViewer
+        )
+        .is_err());
+
+        // Executions stored before owners were recorded belong to nobody
+        let unowned = StoredExecution {
+            execution: Execution::default(),
+            executor_email: String::new(),
+            team_id: String::new(),
+        };
+        assert!(check_execution_owner(&unowned, &viewer("", None), TeamRoles::TeamViewer).is_err());
+    }
+}
@@ -0,0 +1,139 @@
+use crate::access::same_email;
+use crate::generated_types::{AuditEntry, AuditEvents, AuditLog, AuditQuery, VerbTypes};
+use crate::sqlite_helper_functions::{fetch_audit_entries, insert_audit_entry};
+
+use colored::*;
+use r2d2::Pool;
+use r2d2_sqlite::SqliteConnectionManager;
+
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Who did something and where from, the same for every entry a letter causes
+#[derive(Clone, Default)]
+pub struct AuditActor {
+    pub user_email: String,
+    pub session_id: String,
+    pub source_ip: String,
+}
+
+// Failing to write the entry doesn't undo what happened, it is only reported
+pub fn record_audit(
+    pool: &Arc<Pool<SqliteConnectionManager>>,
+    actor: &AuditActor,
+    verb: VerbTypes,
+    event: AuditEvents,
+    object_id: &str,
+    detail: &str,
+) {
+    let entry = AuditEntry {
+        entry_id: 0,
+        timestamp: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs() as i64)
+            .unwrap_or(0),
+        user_email: actor.user_email.clone(),
+        session_id: actor.session_id.clone(),
+        verb: verb as i32,
+        event: event as i32,
+        object_id: object_id.to_string(),
+        source_ip: actor.source_ip.clone(),
+        detail: detail.to_string(),
+    };
+
+    if let Err(err) = insert_audit_entry(pool.clone(), &entry) {
+        println!(
+            "{} {:?} {:?}",
+            "Unable to write audit entry:".red(),
+            entry,
+            err
+        );
+    }
+}
+
+// Admins are listed in admin_emails.txt, one email per line, the same way allowed_emails.txt lists who can sign up
+pub fn is_admin(email: &str) -> bool {
+    if email.trim().is_empty() {
+        return false;
+    }
+
+    match std::fs::read_to_string("./admin_emails.txt") {
+        Ok(admin_emails) => admin_emails
+            .lines()
+            .any(|admin_email| !admin_email.trim().is_empty() && same_email(admin_email, email)),
+        Err(_) => false,
+    }
+}
+
+// The verb and event are written by name so the lines can be read without the proto file at hand
+fn entry_to_json(entry: &AuditEntry) -> serde_json::Value {
+    serde_json::json!({
+        "entry_id": entry.entry_id,
+        "timestamp": entry.timestamp,
+        "user_email": entry.user_email,
+        "session_id": entry.session_id,
+        "verb": VerbTypes::try_from(entry.verb)
+            .map(|verb| verb.as_str_name())
+            .unwrap_or("Unknown"),
+        "event": AuditEvents::try_from(entry.event)
+            .map(|event| event.as_str_name())
+            .unwrap_or("Unknown"),
+        "object_id": entry.object_id,
+        "source_ip": entry.source_ip,
+        "detail": entry.detail,
+    })
+}
+
+// One JSON object per line, oldest entry first. The entries come out of the database newest first.
+fn json_lines(entries: &[AuditEntry]) -> Result<Vec<u8>, String> {
+    let mut lines = Vec::new();
+
+    for entry in entries.iter().rev() {
+        serde_json::to_writer(&mut lines, &entry_to_json(entry))
+            .map_err(|err| format!("Unable to write the audit log as JSON: {}", err))?;
+        lines.push(b'\n');
+    }
+
+    Ok(lines)
+}
+
+pub fn query_audit_log(
+    pool: Arc<Pool<SqliteConnectionManager>>,
+    email: &str,
+    query: &AuditQuery,
+) -> Result<AuditLog, String> {
+    if !is_admin(email) {
+        return Err("Only admins can look through the audit log".to_string());
+    }
+
+    let (entries, total_count) = fetch_audit_entries(pool, query, query.json_lines)
+        .map_err(|err| format!("Unable to fetch the audit log: {:?}", err))?;
+
+    if query.json_lines {
+        Ok(AuditLog {
+            entries: Vec::new(),
+            total_count,
+            json_lines: json_lines(&entries)?,
+        })
+    } else {
+        Ok(AuditLog {
+            entries,
+            total_count,
+            json_lines: Vec::new(),
+        })
+    }
+}
+
+// Writes the whole audit log to the file as JSON lines. Returns how many entries were written.
+pub fn export_audit_log(
+    pool: Arc<Pool<SqliteConnectionManager>>,
+    path: &str,
+) -> Result<usize, String> {
+    let (entries, _) = fetch_audit_entries(pool, &AuditQuery::default(), true)
+        .map_err(|err| format!("Unable to fetch the audit log: {:?}", err))?;
+
+    std::fs::write(path, json_lines(&entries)?)
+        .map_err(|err| format!("Unable to write {}: {}", path, err))?;
+
+    Ok(entries.len())
+}
@@ -0,0 +1,341 @@
+use crate::access::{can_edit, can_view, keep_placement, place_node, Viewer};
+use crate::generated_types::{
+    node_content::NodeContent as NodeContentEnum, BundleFormats, BundleImport, IdPolicies,
+    ImportActions, ImportChange, ImportReport, Node, NodeBundle,
+};
+use crate::graph::{collect_nested_nodes, validate_nodes_in_loop, validate_nodes_in_process};
+use crate::node_history::{diff_nodes, record_version};
+use crate::node_schema::{upgrade_node, NODE_SCHEMA_VERSION};
+use crate::references::next_node_version;
+use crate::sqlite_helper_functions::{fetch_node, insert_node};
+
+use colored::*;
+use prost::Message;
+use r2d2::Pool;
+use r2d2_sqlite::SqliteConnectionManager;
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+fn node_id(node: &Node) -> String {
+    node.node_info.clone().unwrap_or_default().id
+}
+
+// The tools of the prompt (or conditional) node
+fn tool_node_ids(node: &Node) -> Vec<String> {
+    match node
+        .node_content
+        .clone()
+        .and_then(|node_content| node_content.node_content)
+    {
+        Some(NodeContentEnum::Prompt(prompt)) => prompt.tool_node_ids,
+        Some(NodeContentEnum::Conditional(conditional)) => conditional
+            .prompt
+            .map(|prompt| prompt.tool_node_ids)
+            .unwrap_or_default(),
+        _ => Vec::new(),
+    }
+}
+
+// The tools called by the node or any of the nodes nested in it
+fn tools_in_tree(node: &Node) -> Vec<String> {
+    let mut nested_nodes = HashMap::new();
+    collect_nested_nodes(node, &mut nested_nodes);
+
+    let mut ids: Vec<String> = nested_nodes.values().flat_map(tool_node_ids).collect();
+
+    ids.sort();
+    ids.dedup();
+    ids
+}
+
+// The ids of every node the node depends on: the nodes nested in it and the tools called by any of them
+fn dependency_ids(node: &Node) -> Vec<String> {
+    let mut nested_nodes = HashMap::new();
+    collect_nested_nodes(node, &mut nested_nodes);
+
+    let mut ids: Vec<String> = tools_in_tree(node)
+        .into_iter()
+        .chain(nested_nodes.keys().cloned())
+        .filter(|id| *id != node_id(node))
+        .collect();
+
+    ids.sort();
+    ids.dedup();
+    ids
+}
+
+// Only the nodes the user can see are exported
+pub fn export_bundle(
+    pool: Arc<Pool<SqliteConnectionManager>>,
+    root_node_id: &str,
+    format: BundleFormats,
+    viewer: &Viewer,
+) -> Result<Vec<u8>, String> {
+    let mut nodes = Vec::new();
+    let mut seen = HashSet::new();
+    let mut queue = VecDeque::from([root_node_id.to_string()]);
+
+    while let Some(id) = queue.pop_front() {
+        if !seen.insert(id.clone()) {
+            continue;
+        }
+
+        match fetch_node(pool.clone(), &id) {
+            Ok(Some(node)) if can_view(&node, viewer) => {
+                queue.extend(dependency_ids(&node));
+                nodes.push(node);
+            }
+            // Nested nodes that were never saved on their own only exist inside of the process that contains them
+            Ok(_) if id != root_node_id => {}
+            Ok(_) => return Err(format!("There is no node {} to export", id)),
+            Err(err) => return Err(format!("Unable to look up node {}: {:?}", id, err)),
+        }
+    }
+
+    let bundle = NodeBundle {
+        schema_version: NODE_SCHEMA_VERSION,
+        root_node_id: root_node_id.to_string(),
+        nodes,
+        exported_at: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs() as i64)
+            .unwrap_or(0),
+    };
+
+    println!(
+        "{} {} ({} nodes)",
+        "Exporting bundle:".green(),
+        root_node_id,
+        bundle.nodes.len()
+    );
+
+    match format {
+        BundleFormats::BundleProtobuf => Ok(bundle.encode_to_vec()),
+        BundleFormats::BundleJson => serde_json::to_vec_pretty(&bundle)
+            .map_err(|err| format!("Unable to write the bundle as JSON: {}", err)),
+        BundleFormats::BundleYaml => serde_yaml::to_string(&bundle)
+            .map(|yaml| yaml.into_bytes())
+            .map_err(|err| format!("Unable to write the bundle as YAML: {}", err)),
+    }
+}
+
+fn read_bundle(format: BundleFormats, bytes: &[u8]) -> Result<NodeBundle, String> {
+    match format {
+        BundleFormats::BundleProtobuf => NodeBundle::decode(bytes)
+            .map_err(|err| format!("The bundle is not valid protobuf: {}", err)),
+        BundleFormats::BundleJson => serde_json::from_slice(bytes)
+            .map_err(|err| format!("The bundle is not valid JSON: {}", err)),
+        BundleFormats::BundleYaml => serde_yaml::from_slice(bytes)
+            .map_err(|err| format!("The bundle is not valid YAML: {}", err)),
+    }
+}
+
+fn replace_ids(value: &mut serde_json::Value, new_ids: &HashMap<String, String>) {
+    match value {
+        serde_json::Value::String(text) => {
+            if let Some(new_id) = new_ids.get(text.as_str()) {
+                *text = new_id.clone();
+            }
+        }
+        serde_json::Value::Array(items) => {
+            items.iter_mut().for_each(|item| replace_ids(item, new_ids))
+        }
+        serde_json::Value::Object(fields) => fields
+            .values_mut()
+            .for_each(|field| replace_ids(field, new_ids)),
+        _ => {}
+    }
+}
+
+// Gives the node a new id, along with every other node of the bundle wherever the node refers to one (nested copies, graph, topological order, references and tools). Ids are uuids so they can't be mistaken for any other text.
+fn remap_ids(node: &Node, new_ids: &HashMap<String, String>) -> Result<Node, String> {
+    let mut value = serde_json::to_value(node).map_err(|err| err.to_string())?;
+    replace_ids(&mut value, new_ids);
+    serde_json::from_value(value).map_err(|err| err.to_string())
+}
+
+// Checks that every process and loop in the bundle still fits together
+fn validate_node(node: &Node) -> Result<(), String> {
+    let node_info = node.node_info.clone().unwrap_or_default();
+
+    match node
+        .node_content
+        .clone()
+        .and_then(|node_content| node_content.node_content)
+    {
+        Some(NodeContentEnum::Process(process)) => {
+            validate_nodes_in_process(process.nodes, node_info, process.conversation).map(|_| ())
+        }
+        Some(NodeContentEnum::Loop(contained_loop)) => {
+            validate_nodes_in_loop(contained_loop.process.unwrap_or_default().nodes, node_info)
+                .map(|_| ())
+        }
+        _ => Ok(()),
+    }
+}
+
+// Works out what importing the bundle would change. With commit set the changes are saved as well, unless the report has errors or conflicts. Added nodes belong to the viewer (team ids only mean something to the backend that exported them), and saved nodes the viewer isn't allowed to change are errors.
+pub fn import_bundle(
+    pool: Arc<Pool<SqliteConnectionManager>>,
+    import: &BundleImport,
+    viewer: &Viewer,
+    commit: bool,
+) -> Result<ImportReport, String> {
+    let format = BundleFormats::try_from(import.format).unwrap_or(BundleFormats::BundleProtobuf);
+    let bundle = read_bundle(format, &import.bundle)?;
+
+    let mut report = ImportReport::default();
+
+    let mut nodes = bundle.nodes.clone();
+    for node in nodes.iter_mut() {
+        upgrade_node(node, bundle.schema_version)?;
+    }
+
+    let bundle_ids: Vec<String> = nodes.iter().map(node_id).collect();
+    let unique_ids: HashSet<&String> = bundle_ids.iter().collect();
+
+    if bundle_ids.iter().any(|id| id.is_empty()) {
+        report
+            .errors
+            .push("Every node in the bundle needs an id".to_string());
+    }
+    if unique_ids.len() != bundle_ids.len() {
+        report
+            .errors
+            .push("The bundle contains the same node more than once".to_string());
+    }
+    if !bundle_ids.contains(&bundle.root_node_id) {
+        report.errors.push(format!(
+            "The root node {} is not in the bundle",
+            bundle.root_node_id
+        ));
+    }
+
+    let new_ids: HashMap<String, String> =
+        if IdPolicies::try_from(import.id_policy) == Ok(IdPolicies::RemapIds) {
+            bundle_ids
+                .iter()
+                .map(|id| (id.clone(), uuid::Uuid::new_v4().to_string()))
+                .collect()
+        } else {
+            HashMap::new()
+        };
+
+    let imported_ids: HashSet<String> = bundle_ids
+        .iter()
+        .map(|id| new_ids.get(id).unwrap_or(id).clone())
+        .collect();
+
+    let mut to_save = Vec::new();
+
+    for (bundle_node_id, bundle_node) in bundle_ids.iter().zip(nodes.iter()) {
+        let mut node = remap_ids(bundle_node, &new_ids)
+            .map_err(|err| format!("Unable to remap the ids of {}: {}", bundle_node_id, err))?;
+        let node_info = node.node_info.clone().unwrap_or_default();
+
+        let existing = fetch_node(pool.clone(), &node_info.id)
+            .map_err(|err| format!("Unable to look up node {}: {:?}", node_info.id, err))?;
+
+        // Who the node belongs to isn't part of what the bundle can change
+        match &existing {
+            Some(saved_node) => {
+                if !can_edit(saved_node, viewer) {
+                    report.errors.push(format!(
+                        "{} ({}) is already saved here and you aren't allowed to change it",
+                        node_info.name, bundle_node_id
+                    ));
+                }
+                keep_placement(&mut node, saved_node);
+            }
+            None => {
+                node.team_id.clear();
+                place_node(&mut node, None, viewer)?;
+            }
+        }
+
+        if let Err(err) = validate_node(&node) {
+            report.errors.push(format!(
+                "{} ({}) is not valid: {}",
+                node_info.name, bundle_node_id, err
+            ));
+        }
+
+        for tool_id in tools_in_tree(&node) {
+            let saved = matches!(fetch_node(pool.clone(), &tool_id), Ok(Some(_)));
+
+            if !imported_ids.contains(&tool_id) && !saved {
+                report.errors.push(format!(
+                    "{} calls the tool {} which is neither in the bundle nor saved here",
+                    node_info.name, tool_id
+                ));
+            }
+        }
+
+        let (action, changes) = match &existing {
+            None => (ImportActions::ImportAdd, Vec::new()),
+            Some(saved_node) => {
+                let changes = diff_nodes(saved_node, &node)?;
+
+                if changes.is_empty() {
+                    (ImportActions::ImportUnchanged, changes)
+                } else if import.overwrite_conflicts {
+                    (ImportActions::ImportUpdate, changes)
+                } else {
+                    (ImportActions::ImportConflict, changes)
+                }
+            }
+        };
+
+        report.changes.push(ImportChange {
+            node: Some(node_info),
+            bundle_node_id: bundle_node_id.clone(),
+            action: action as i32,
+            changes,
+        });
+
+        if action == ImportActions::ImportAdd || action == ImportActions::ImportUpdate {
+            to_save.push((node, action));
+        }
+    }
+
+    let conflicts = report
+        .changes
+        .iter()
+        .filter(|change| change.action == ImportActions::ImportConflict as i32)
+        .count();
+
+    if !commit || !report.errors.is_empty() || conflicts > 0 {
+        return Ok(report);
+    }
+
+    for (mut node, action) in to_save {
+        let id = node_id(&node);
+
+        // New nodes keep their version so the references to them in the imported processes stay accurate
+        node.version = if action == ImportActions::ImportAdd {
+            node.version.max(next_node_version(pool.clone(), &id))
+        } else {
+            next_node_version(pool.clone(), &id)
+        };
+
+        insert_node(pool.clone(), node.clone())
+            .map_err(|err| format!("Unable to save node {}: {:?}", id, err))?;
+
+        if let Err(err) =
+            record_version(pool.clone(), &node, &viewer.email, "Imported from a bundle")
+        {
+            println!("{} {}", "Unable to record node version:".red(), err);
+        }
+    }
+
+    println!(
+        "{} {} node(s)",
+        "Imported bundle:".green(),
+        report.changes.len()
+    );
+
+    report.committed = true;
+    Ok(report)
+}
@@ -0,0 +1,109 @@
+use crate::generated_types::{AtomicExecutionLog, NodeTypes, Process};
+use crate::graph::collect_nested_nodes;
+use crate::llm::{assistant_message, user_message, ModelError};
+use crate::templating::value_to_json;
+
+use async_openai::types::ChatCompletionRequestMessage;
+
+use std::collections::{HashMap, HashSet};
+
+const DEFAULT_MAX_CONTEXT_TOKENS: u32 = 4000;
+// A rough estimate that is good enough to keep the history inside the window without pulling in a tokenizer
+const CHARACTERS_PER_TOKEN: usize = 4;
+
+// The prompt nodes that take part in a conversation and how much of the earlier exchanges they get to see
+#[derive(Clone, Debug)]
+pub struct ConversationScope {
+    prompt_node_ids: HashSet<String>,
+    max_context_tokens: u32,
+}
+
+// Returns the scope for the process when it keeps a conversation going. Prompt nodes inside nested processes and loops are part of the same conversation.
+pub fn conversation_scope(process: &Process) -> Option<ConversationScope> {
+    let conversation = process.conversation.clone()?;
+
+    let mut nodes = HashMap::new();
+    for node in &process.nodes {
+        collect_nested_nodes(node, &mut nodes);
+    }
+
+    let prompt_node_ids = nodes
+        .into_iter()
+        .filter(|(_, node)| node.node_type == NodeTypes::Prompt as i32)
+        .map(|(id, _)| id)
+        .collect();
+
+    let max_context_tokens = if conversation.max_context_tokens == 0 {
+        DEFAULT_MAX_CONTEXT_TOKENS
+    } else {
+        conversation.max_context_tokens
+    };
+
+    Some(ConversationScope {
+        prompt_node_ids,
+        max_context_tokens,
+    })
+}
+
+fn estimate_tokens(text: &str) -> usize {
+    text.chars().count().div_ceil(CHARACTERS_PER_TOKEN)
+}
+
+fn response_text(log: &AtomicExecutionLog) -> String {
+    let response: serde_json::Map<String, serde_json::Value> = log
+        .response
+        .iter()
+        .map(|(name, value)| (name.clone(), value_to_json(value)))
+        .collect();
+
+    serde_json::Value::Object(response).to_string()
+}
+
+// The earlier exchanges of the conversation as (prompt, response) pairs, oldest first. Exchanges are taken from the most recent backwards until the next one would no longer fit in the window.
+pub fn conversation_exchanges(
+    scope: &ConversationScope,
+    prompt_histories: &[AtomicExecutionLog],
+) -> Vec<(String, String)> {
+    let budget = scope.max_context_tokens as usize;
+    let mut used = 0;
+    let mut exchanges = Vec::new();
+
+    for log in prompt_histories.iter().rev() {
+        let in_conversation = log
+            .node_info
+            .as_ref()
+            .map(|node_info| scope.prompt_node_ids.contains(&node_info.id))
+            .unwrap_or(false);
+
+        if !in_conversation {
+            continue;
+        }
+
+        let response = response_text(log);
+        let tokens = estimate_tokens(&log.prompt) + estimate_tokens(&response);
+
+        if used + tokens > budget {
+            break;
+        }
+
+        used += tokens;
+        exchanges.push((log.prompt.clone(), response));
+    }
+
+    exchanges.reverse();
+    exchanges
+}
+
+// The exchanges as the user and assistant messages that go between the system message and the new prompt
+pub fn conversation_messages(
+    exchanges: &[(String, String)],
+) -> Result<Vec<ChatCompletionRequestMessage>, ModelError> {
+    let mut messages = Vec::new();
+
+    for (prompt, response) in exchanges {
+        messages.push(user_message(prompt)?);
+        messages.push(assistant_message(response)?);
+    }
+
+    Ok(messages)
+}
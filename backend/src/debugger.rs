@@ -0,0 +1,277 @@
+use crate::access::{can_run, check_execution_access, same_email, Viewer};
+use crate::generated_types::{DebugActions, DebugCommand, DebugState, Execution};
+use crate::graph::{step_execution, ExecutionContext};
+use crate::receive_send::LocalServerIdentity;
+use crate::references::resolve_execution;
+
+use colored::*;
+use tokio::sync::Mutex;
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+// The debug sessions of every client keyed by the execution_id they debug. Commands run in tasks of their own, so every session has a lock of its own and a slow step only holds up the commands for the same session.
+pub type DebugSessions = Arc<Mutex<HashMap<String, Arc<Mutex<DebugSession>>>>>;
+
+// A debug session keeps an execution paused in between the messages sent by the client. The current_node of the execution is the next node that will be run.
+pub struct DebugSession {
+    // Only the user who started debugging can send commands to the session
+    pub owner_email: String,
+    // The session is thrown away once the connection that started it goes away
+    pub connection: LocalServerIdentity,
+    pub execution: Execution,
+    pub breakpoints: Vec<String>,
+    pub accumulator: Option<String>,
+}
+
+impl DebugSession {
+    pub fn new(
+        owner_email: String,
+        connection: LocalServerIdentity,
+        mut execution: Execution,
+        breakpoints: Vec<String>,
+    ) -> DebugSession {
+        if execution.current_node.is_none() {
+            execution.current_node = execution
+                .process
+                .clone()
+                .unwrap()
+                .topological_order
+                .first()
+                .cloned();
+        }
+
+        DebugSession {
+            owner_email,
+            connection,
+            execution,
+            breakpoints,
+            accumulator: None,
+        }
+    }
+
+    pub fn finished(&self) -> bool {
+        self.execution.current_node.is_none()
+    }
+
+    pub fn to_debug_state(&self) -> DebugState {
+        DebugState {
+            execution: Some(self.execution.clone()),
+            breakpoints: self.breakpoints.clone(),
+            finished: self.finished(),
+            accumulator: self.accumulator.clone().unwrap_or_default(),
+        }
+    }
+
+    async fn step(&mut self, context: &ExecutionContext) -> Result<(), String> {
+        match step_execution(self.execution.clone(), self.accumulator.clone(), context).await {
+            Ok((execution, accumulator)) => {
+                self.execution = execution;
+                self.accumulator = accumulator;
+                Ok(())
+            }
+            Err((_execution, err)) => Err(format!(
+                "Failed to run node {}: {}",
+                self.execution.current_node.clone().unwrap_or_default().name,
+                err
+            )),
+        }
+    }
+}
+
+// The sessions of other users are treated as if they weren't there
+async fn owned_session(
+    debug_sessions: &DebugSessions,
+    execution_id: &str,
+    email: &str,
+) -> Result<Arc<Mutex<DebugSession>>, String> {
+    let session = debug_sessions.lock().await.get(execution_id).cloned();
+
+    match session {
+        Some(session) if same_email(&session.lock().await.owner_email, email) => Ok(session),
+        _ => Err(format!(
+            "No debug session found for execution: {}",
+            execution_id
+        )),
+    }
+}
+
+pub async fn session_finished(debug_sessions: &DebugSessions, execution_id: &str) -> bool {
+    let session = debug_sessions.lock().await.get(execution_id).cloned();
+
+    match session {
+        Some(session) => session.lock().await.finished(),
+        None => false,
+    }
+}
+
+// Throws away the sessions started by a connection that has gone away. Returns the ids of the executions that hadn't finished yet.
+pub async fn end_connection_sessions(
+    debug_sessions: &DebugSessions,
+    connection: &LocalServerIdentity,
+) -> Vec<String> {
+    let sessions: Vec<(String, Arc<Mutex<DebugSession>>)> = debug_sessions
+        .lock()
+        .await
+        .iter()
+        .map(|(execution_id, session)| (execution_id.clone(), session.clone()))
+        .collect();
+
+    let mut unfinished = Vec::new();
+
+    for (execution_id, session) in sessions {
+        let session = session.lock().await;
+
+        if &session.connection != connection {
+            continue;
+        }
+
+        if !session.finished() {
+            unfinished.push(execution_id.clone());
+        }
+
+        debug_sessions.lock().await.remove(&execution_id);
+    }
+
+    unfinished
+}
+
+// Applies a debug command to the matching debug session (creating or removing the session where needed) and returns the state of the session afterwards.
+pub async fn handle_debug_command(
+    debug_sessions: &DebugSessions,
+    connection: &LocalServerIdentity,
+    command: DebugCommand,
+    context: &ExecutionContext,
+) -> Result<DebugState, String> {
+    let action = DebugActions::try_from(command.action)
+        .map_err(|_| format!("Unknown debug action: {}", command.action))?;
+
+    println!("{} {:?}", "Debug action:".yellow(), action);
+
+    let email = context.user_email.clone().unwrap_or_default();
+
+    if action == DebugActions::StartDebugging {
+        let mut execution = match command.execution {
+            Some(execution) => execution,
+            None => {
+                return Err("An execution is needed to start debugging".to_string());
+            }
+        };
+
+        if execution.process.is_none() {
+            return Err("The execution doesn't contain a process".to_string());
+        }
+
+        resolve_execution(&context.pool, &mut execution)?;
+
+        let viewer = Viewer::load(context.pool.clone(), context.user_email.as_ref());
+        check_execution_access(context.pool.clone(), &execution, &viewer, can_run)?;
+
+        let mut sessions = debug_sessions.lock().await;
+
+        // A session that is busy running a node can't be started over either
+        let taken = match sessions.get(&execution.execution_id) {
+            Some(session) => match session.try_lock() {
+                Ok(session) => !same_email(&session.owner_email, &email),
+                Err(_) => true,
+            },
+            None => false,
+        };
+
+        if taken {
+            return Err(format!(
+                "Execution {} is already being debugged",
+                execution.execution_id
+            ));
+        }
+
+        let session = DebugSession::new(
+            email,
+            connection.clone(),
+            execution.clone(),
+            command.breakpoints.clone(),
+        );
+        let state = session.to_debug_state();
+
+        sessions.insert(
+            execution.execution_id.clone(),
+            Arc::new(Mutex::new(session)),
+        );
+
+        return Ok(state);
+    }
+
+    let session = owned_session(debug_sessions, &command.execution_id, &email).await?;
+    let mut session = session.lock().await;
+
+    if action == DebugActions::StopDebugging {
+        let state = session.to_debug_state();
+        drop(session);
+        debug_sessions.lock().await.remove(&command.execution_id);
+        return Ok(state);
+    }
+
+    match action {
+        DebugActions::Step => {
+            if session.finished() {
+                return Err("The execution has already finished".to_string());
+            }
+
+            session.step(context).await?;
+        }
+        DebugActions::Continue => {
+            // Always run at least one node so that continuing from a breakpoint doesn't stop on the same breakpoint again
+            while !session.finished() {
+                session.step(context).await?;
+
+                if let Some(next_node) = &session.execution.current_node {
+                    if session.breakpoints.contains(&next_node.id) {
+                        println!("{} {}", "Paused at breakpoint:".yellow(), next_node.name);
+                        break;
+                    }
+                }
+            }
+        }
+        DebugActions::SetBreakpoints => {
+            session.breakpoints = command.breakpoints.clone();
+        }
+        DebugActions::SetVariables => {
+            session
+                .execution
+                .current_variable_definitions
+                .extend(command.variable_overrides.clone());
+        }
+        DebugActions::RerunNode => {
+            let node_info = session
+                .execution
+                .process
+                .clone()
+                .unwrap()
+                .topological_order
+                .into_iter()
+                .find(|node_info| node_info.id == command.node_id);
+
+            match node_info {
+                Some(node_info) => {
+                    // Jump back to the node and run it with the modified inputs. The execution carries on from the node after it.
+                    session
+                        .execution
+                        .current_variable_definitions
+                        .extend(command.variable_overrides.clone());
+                    session.execution.current_node = Some(node_info);
+
+                    session.step(context).await?;
+                }
+                None => {
+                    return Err(format!(
+                        "Node {} is not part of the process being debugged",
+                        command.node_id
+                    ));
+                }
+            }
+        }
+        DebugActions::StartDebugging | DebugActions::StopDebugging => {}
+    }
+
+    Ok(session.to_debug_state())
+}
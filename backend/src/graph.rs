@@ -1,7 +1,8 @@
-use crate::generated_types::{self, value, AtomicExecutionLog, UserSettings};
+use crate::generated_types::{self, value, AtomicExecutionLog, CachePolicies, UserSettings};
+use crate::sqlite_helper_functions::{fetch_cached_response, insert_cached_response};
 use crate::generated_types::{
-    node_content::NodeContent as NodeContentEnum, Command, Edge, Execution, Graph, GraphNodeInfo,
-    Loop, Node, NodeContent, NodeTypes, Process,
+    node_content::NodeContent as NodeContentEnum, Command, Conversation, Edge, Execution, Graph,
+    GraphNodeInfo, Loop, Node, NodeContent, NodeTypes, Process,
 };
 
 use async_openai::config::OpenAIConfig;
@@ -17,13 +18,25 @@ use petgraph::adj::NodeIndex;
 
 use async_recursion::async_recursion;
 
-use async_openai::types::{
-    ChatCompletionRequestMessage, ChatCompletionRequestUserMessage,
-    ChatCompletionRequestUserMessageContent, ChatCompletionResponseFormat,
-    ChatCompletionResponseFormatType, CreateChatCompletionRequest, Role,
+use crate::conversation::{
+    conversation_exchanges, conversation_messages, conversation_scope, ConversationScope,
+};
+use crate::http_request::handle_http_request;
+use crate::llm::{
+    language_model, prompt_messages, request_json_object, system_message, user_message,
+};
+use crate::memory::{handle_memory_read, handle_memory_write};
+use crate::retrieval::handle_retrieve;
+use crate::output_schema::OutputSchema;
+use crate::secrets::{contains_secret, redact, redact_log, secret_environment, SecretValues};
+use crate::templating::render_template;
+use crate::tools::{call_tools, load_tools};
+use crate::transform::handle_transform;
+use crate::variables::{
+    apply_output_types, check_connection, collect_input_declarations, collect_output_declarations,
+    prepare_inputs,
 };
 
-use handlebars::Handlebars;
 
 use async_openai::Client;
 use colored::*;
@@ -35,6 +48,9 @@ use petgraph::algo::toposort;
 use std::collections::HashMap;
 use std::sync::Arc;
 
+use r2d2::Pool;
+use r2d2_sqlite::SqliteConnectionManager;
+
 use petgraph::visit::EdgeRef;
 
 // use anyhow::Error;
@@ -42,6 +58,7 @@ use petgraph::visit::EdgeRef;
 pub fn validate_nodes_in_process(
     nodes: Vec<Node>,
     graph_node_info: GraphNodeInfo,
+    conversation: Option<Conversation>,
 ) -> Result<Node, String> {
     //generate maximal graph from nodes (based on input_variables and output_variables)
     println!("Validating nodes");
@@ -136,6 +153,8 @@ pub fn validate_nodes_in_process(
                     other_node.output_variables
                 );
                 if other_node.output_variables.contains(input_var) {
+                    check_connection(other_node, node, input_var)?;
+
                     let other_node_index = node_indices[&other_node.node_info.clone().unwrap().id];
                     println!(
                         "Found matching output_var in node: {:?}",
@@ -244,10 +263,15 @@ pub fn validate_nodes_in_process(
         topological_order.push(node.clone());
     }
 
+    let input_variable_declarations = collect_input_declarations(&nodes, &input_minus_output);
+    let output_variable_declarations = collect_output_declarations(&nodes, &output_minus_input);
+
     let process: Process = Process {
         nodes: nodes,
         graph: Some(new_graph),
         topological_order: topological_order,
+        conversation,
+        node_references: Vec::new(),
     };
 
     let node_content: NodeContent = NodeContent {
@@ -260,6 +284,16 @@ pub fn validate_nodes_in_process(
         input_variables: input_minus_output,
         output_variables: output_minus_input,
         node_content: Some(node_content),
+        cache_policy: CachePolicies::NoCache as i32,
+        output_variable_declarations,
+        input_variable_declarations,
+        version: 0,
+        change_note: "".to_string(),
+        tags: Vec::new(),
+        owner_email: "".to_string(),
+        visibility: 0,
+        shared_with: Vec::new(),
+        team_id: "".to_string(),
     };
 
     return Ok(node);
@@ -390,6 +424,8 @@ pub fn validate_nodes_in_loop(
                     other_node.output_variables
                 );
                 if other_node.output_variables.contains(input_var) {
+                    check_connection(other_node, node, input_var)?;
+
                     let other_node_index = node_indices[&other_node.node_info.clone().unwrap().id];
                     println!(
                         "Found matching output_var in node: {:?}",
@@ -547,10 +583,15 @@ pub fn validate_nodes_in_loop(
         topological_order.push(node.clone());
     }
 
+    let input_variable_declarations = collect_input_declarations(&nodes, &input_minus_output);
+    let output_variable_declarations = collect_output_declarations(&nodes, &output_minus_input);
+
     let process: Process = Process {
         nodes: nodes,
         graph: Some(new_graph),
         topological_order: topological_order,
+        conversation: None,
+        node_references: Vec::new(),
     };
 
     let node_content: NodeContent = NodeContent {
@@ -563,31 +604,71 @@ pub fn validate_nodes_in_loop(
         input_variables: input_minus_output,
         output_variables: output_minus_input,
         node_content: Some(node_content),
+        cache_policy: CachePolicies::NoCache as i32,
+        output_variable_declarations,
+        input_variable_declarations,
+        version: 0,
+        change_note: "".to_string(),
+        tags: Vec::new(),
+        owner_email: "".to_string(),
+        visibility: 0,
+        shared_with: Vec::new(),
+        team_id: "".to_string(),
     };
 
     return Ok(node);
 }
 
+// Everything that a node might need while it runs that isn't part of the execution itself. Docker and the pool are both cheap to clone.
+#[derive(Clone)]
+pub struct ExecutionContext {
+    pub docker_id: Option<String>,
+    pub docker: Docker,
+    pub user_settings: Arc<UserSettings>,
+    pub pool: Arc<Pool<SqliteConnectionManager>>,
+    // The decrypted secrets of the user running the execution
+    pub secrets: Arc<SecretValues>,
+    // Memory nodes read and write the memories of this user
+    pub user_email: Option<String>,
+    // Set while running the nodes of a process that keeps a conversation going
+    pub conversation: Option<Arc<ConversationScope>>,
+    // How many tool calls deep the execution is. Zero unless a prompt called the process as a tool.
+    pub tool_depth: u32,
+    // The process node that is running, memory nodes without a namespace keep their memories under its id. Empty when the client didn't say which process it sent.
+    pub process_id: String,
+}
+
+impl ExecutionContext {
+    // The context the nodes of the process run in. A process with a conversation starts a new one, any other process carries on with the conversation (if any) of the process it is in. Loops don't have a process id of their own, so they keep the one of the process they are in.
+    fn for_process(&self, execution: &Execution) -> ExecutionContext {
+        let mut context = match conversation_scope(execution.process.as_ref().unwrap()) {
+            Some(scope) => ExecutionContext {
+                conversation: Some(Arc::new(scope)),
+                ..self.clone()
+            },
+            None => self.clone(),
+        };
+
+        if !execution.process_id.is_empty() {
+            context.process_id = execution.process_id.clone();
+        }
+
+        context
+    }
+}
+
 #[async_recursion]
 pub async fn run_execution(
     execution: Execution,
     accumulator: Option<String>,
-    docker_id: Option<String>,
-    docker_instance: &Docker,
-    user_settings: Arc<UserSettings>,
-) -> Result<(Execution, Option<String>), Execution> {
+    context: &ExecutionContext,
+) -> Result<(Execution, Option<String>), (Execution, String)> {
     // Keep track of the variable definitions (accumulate their values as we loop through the topological order list)
 
     let mut variable_definitions: HashMap<String, generated_types::Value> =
         execution.clone().current_variable_definitions;
 
-    let local_nodes: Vec<Node> = execution.process.clone().unwrap().nodes.clone();
-
-    // Make a map out of the vec where the key is the id of the node:
-    let mut local_nodes_map: HashMap<String, Node> = HashMap::new();
-    local_nodes.iter().for_each(|node: &Node| {
-        local_nodes_map.insert(node.node_info.clone().unwrap().id, node.clone());
-    });
+    let local_nodes_map = local_nodes_map(&execution);
 
     let topological_order: Vec<GraphNodeInfo> =
         execution.process.clone().unwrap().topological_order.clone();
@@ -596,37 +677,329 @@ pub async fn run_execution(
 
     let mut prompt_histories: Vec<AtomicExecutionLog> = execution.clone().atomic_history.clone();
 
-    for node_info in topological_order {
+    let context = &context.for_process(&execution);
+
+    // Start from the current node so that an execution can be picked up part way through (for example when it is rerun from a node)
+    let start_index = match &execution.current_node {
+        Some(current) => topological_order
+            .iter()
+            .position(|node_info| node_info.id == current.id)
+            .unwrap_or(0),
+        None => 0,
+    };
+
+    for node_info in topological_order.into_iter().skip(start_index) {
         let current_node = local_nodes_map.get(&node_info.id).unwrap().clone();
 
-        match NodeTypes::try_from(current_node.node_type) {
-            Ok(NodeTypes::Process) => {
-                // Once we implement this functionality just for Prompts (and other node types), we can extract this function and call it recursively to handle this case (with a max depth?)
+        match run_node(
+            current_node,
+            &mut variable_definitions,
+            &mut prompt_histories,
+            &mut local_accumulator,
+            context,
+        )
+        .await
+        {
+            Ok(_) => {}
+            Err(err) => {
+                println!("{} {}", "Error running node:".red(), err);
+                return Err((execution, err));
+            }
+        }
+    }
+    let mut response = execution.clone();
 
-                let process: Process;
+    // Change this to use a prompt history
+    // response.node_execution_response = node_execution_response;
+    response.current_variable_definitions = variable_definitions.clone();
+    response.atomic_history = prompt_histories.clone();
 
-                match current_node.node_content.unwrap().node_content.unwrap() {
-                    NodeContentEnum::Process(p) => {
-                        process = p;
-                    }
-                    _ => {
-                        println!("Process not handled");
-                        continue;
-                    }
+    return Ok((response, local_accumulator.clone()));
+}
+
+// Runs the node that the execution's current_node points at and then moves current_node along to the next node in the topological order. Once the last node has run, current_node is cleared. This is what the debugger uses to walk through an execution one node at a time.
+pub async fn step_execution(
+    execution: Execution,
+    accumulator: Option<String>,
+    context: &ExecutionContext,
+) -> Result<(Execution, Option<String>), (Execution, String)> {
+    let topological_order: Vec<GraphNodeInfo> =
+        execution.process.clone().unwrap().topological_order.clone();
+
+    let current_index = match &execution.current_node {
+        Some(current) => match topological_order
+            .iter()
+            .position(|node_info| node_info.id == current.id)
+        {
+            Some(index) => index,
+            None => {
+                return Err((
+                    execution,
+                    "The current node is not part of the process".to_string(),
+                ));
+            }
+        },
+        None => {
+            return Err((execution, "The execution has already finished".to_string()));
+        }
+    };
+
+    let current_node = match local_nodes_map(&execution).get(&topological_order[current_index].id) {
+        Some(node) => node.clone(),
+        None => {
+            return Err((
+                execution,
+                "The current node is missing from the process".to_string(),
+            ));
+        }
+    };
+
+    let mut variable_definitions = execution.current_variable_definitions.clone();
+    let mut prompt_histories = execution.atomic_history.clone();
+    let mut local_accumulator = accumulator.clone();
+
+    let context = &context.for_process(&execution);
+
+    match run_node(
+        current_node,
+        &mut variable_definitions,
+        &mut prompt_histories,
+        &mut local_accumulator,
+        context,
+    )
+    .await
+    {
+        Ok(_) => {}
+        Err(err) => {
+            return Err((execution, err));
+        }
+    }
+
+    let mut response = execution.clone();
+
+    response.current_variable_definitions = variable_definitions;
+    response.atomic_history = prompt_histories;
+    response.current_node = topological_order.get(current_index + 1).cloned();
+
+    return Ok((response, local_accumulator));
+}
+
+// Builds a new execution that picks up a stored execution at the node with node_id. Rather than running the nodes before it again, the outputs they recorded in the atomic_history are put back into the variable definitions.
+pub fn prepare_rerun(execution: Execution, node_id: &str) -> Result<Execution, String> {
+    let process = match execution.process.clone() {
+        Some(process) => process,
+        None => {
+            return Err("The stored execution doesn't contain a process".to_string());
+        }
+    };
+
+    let rerun_index = match process
+        .topological_order
+        .iter()
+        .position(|node_info| node_info.id == node_id)
+    {
+        Some(index) => index,
+        None => {
+            return Err(format!("Node {} is not part of the execution", node_id));
+        }
+    };
+
+    let local_nodes_map = local_nodes_map(&execution);
+
+    // The upstream nodes include everything nested inside of the upstream processes and loops since those record their own entries in the atomic history
+    let mut upstream_nodes: HashMap<String, Node> = HashMap::new();
+    for node_info in &process.topological_order[..rerun_index] {
+        if let Some(node) = local_nodes_map.get(&node_info.id) {
+            collect_nested_nodes(node, &mut upstream_nodes);
+        }
+    }
+
+    let mut variable_definitions = execution.initial_variable_definitions.clone();
+
+    if variable_definitions.is_empty() {
+        // Executions stored before the initial variables were recorded: fall back to the final variables without anything the rerun nodes produce
+        variable_definitions = execution.current_variable_definitions.clone();
+        for node_info in &process.topological_order[rerun_index..] {
+            if let Some(node) = local_nodes_map.get(&node_info.id) {
+                for output_variable in &node.output_variables {
+                    variable_definitions.remove(output_variable);
                 }
+            }
+        }
+    }
+
+    let atomic_history: Vec<AtomicExecutionLog> = execution
+        .atomic_history
+        .iter()
+        .filter(|log| match &log.node_info {
+            Some(node_info) => upstream_nodes.contains_key(&node_info.id),
+            None => false,
+        })
+        .cloned()
+        .collect();
+
+    for log in &atomic_history {
+        let node = &upstream_nodes[&log.node_info.clone().unwrap().id];
+
+        // The response of a command node is the command that was run, it never made it into the variable definitions
+        if node.node_type == (NodeTypes::Command as i32) {
+            continue;
+        }
+
+        variable_definitions.extend(log.response.clone());
+    }
+
+    let rerun_execution = Execution {
+        current_node: Some(process.topological_order[rerun_index].clone()),
+        process: Some(process),
+        current_variable_definitions: variable_definitions,
+        execution_id: uuid::Uuid::new_v4().to_string(),
+        atomic_history,
+        initial_variable_definitions: execution.initial_variable_definitions.clone(),
+        process_id: execution.process_id.clone(),
+    };
+
+    Ok(rerun_execution)
+}
+
+// Adds the node (and all of the nodes inside of it if it is a process or loop) to the map, keyed by id
+pub fn collect_nested_nodes(node: &Node, nodes: &mut HashMap<String, Node>) {
+    nodes.insert(node.node_info.clone().unwrap_or_default().id, node.clone());
+
+    let nested_process = match node.node_content.clone().and_then(|content| content.node_content) {
+        Some(NodeContentEnum::Process(process)) => Some(process),
+        Some(NodeContentEnum::Loop(contained_loop)) => contained_loop.process,
+        _ => None,
+    };
+
+    if let Some(process) = nested_process {
+        for nested_node in &process.nodes {
+            collect_nested_nodes(nested_node, nodes);
+        }
+    }
+}
+
+// Make a map out of the nodes of the execution's process where the key is the id of the node
+fn local_nodes_map(execution: &Execution) -> HashMap<String, Node> {
+    let local_nodes: Vec<Node> = execution.process.clone().unwrap().nodes.clone();
+
+    let mut local_nodes_map: HashMap<String, Node> = HashMap::new();
+    local_nodes.iter().for_each(|node: &Node| {
+        local_nodes_map.insert(node.node_info.clone().unwrap().id, node.clone());
+    });
+
+    local_nodes_map
+}
+
+// Runs a single node of a process. The variable definitions, prompt histories and accumulator are updated in place so that the next node in the topological order can pick up where this one left off.
+pub async fn run_node(
+    current_node: Node,
+    variable_definitions: &mut HashMap<String, generated_types::Value>,
+    prompt_histories: &mut Vec<AtomicExecutionLog>,
+    local_accumulator: &mut Option<String>,
+    context: &ExecutionContext,
+) -> Result<(), String> {
+    prepare_inputs(&current_node, variable_definitions)?;
+
+    match NodeTypes::try_from(current_node.node_type) {
+        Ok(NodeTypes::Process) => {
+            // Once we implement this functionality just for Prompts (and other node types), we can extract this function and call it recursively to handle this case (with a max depth?)
+
+            let process: Process;
+
+            match current_node.node_content.unwrap().node_content.unwrap() {
+                NodeContentEnum::Process(p) => {
+                    process = p;
+                }
+                _ => {
+                    println!("Process not handled");
+                    return Ok(());
+                }
+            }
+
+            let local_execution = process_to_execution(
+                variable_definitions.clone(),
+                process.clone(),
+                prompt_histories.clone(),
+                current_node.node_info.clone().unwrap_or_default().id,
+            );
+
+            match run_execution(
+                local_execution,
+                local_accumulator.clone(),
+                context,
+            )
+            .await
+            {
+                Ok((progressed_execution, returned_accumulator)) => {
+                    println!("{}", "Process executed successfully".green());
+                    // update the variable definitions and prompt histories
+                    variable_definitions
+                        .extend(progressed_execution.current_variable_definitions.clone());
+                    *prompt_histories = progressed_execution.atomic_history.clone();
+
+                    *local_accumulator = returned_accumulator.clone();
+                }
+                Err((_execution, err)) => {
+                    println!("Error at process execution: {}", err);
+                    return Err(err);
+                }
+            }
+        }
+        Ok(NodeTypes::Prompt) => {
+            // we need to replace the prompt text input_variables with their definitions
+            match handle_prompt(
+                current_node.clone(),
+                variable_definitions.clone(),
+                local_accumulator.clone(),
+                prompt_histories,
+                language_model(&context.user_settings),
+                context,
+            )
+            .await
+            {
+                Ok((prompt_history, local_variable_definitions)) => {
+                    prompt_histories.push(redact_log(&prompt_history, &context.secrets));
+                    // update the variable definitions
+                    variable_definitions.extend(local_variable_definitions.clone());
+                }
+                Err(err) => {
+                    return Err(err);
+                }
+            }
+        }
+        Ok(NodeTypes::Loop) => {
+            let contained_loop: Loop;
+
+            match current_node.node_content.unwrap().node_content.unwrap() {
+                NodeContentEnum::Loop(looop) => {
+                    contained_loop = looop;
+                }
+                _ => {
+                    println!("Somehow the stored contents is not actually a loop");
+                    return Ok(());
+                }
+            }
+
+            // Run the process (marked as a loop so that the aggregator is injected into the promp)
+
+            let max_iterations = contained_loop.max_iterations;
+
+            // run the following loop up to and including max iterations. This
+            for _i in 1..max_iterations {
+                // an execution may be returned that contains an external branch (with an empty accumulator) OR the accumulator containing text to feed into the next iteration of the loop
 
                 let local_execution = process_to_execution(
                     variable_definitions.clone(),
-                    process.clone(),
+                    contained_loop.clone().process.unwrap().clone(),
                     prompt_histories.clone(),
+                    "".to_string(),
                 );
 
                 match run_execution(
                     local_execution,
                     local_accumulator.clone(),
-                    docker_id.clone(),
-                    docker_instance,
-                    user_settings.clone(),
+                    context,
                 )
                 .await
                 {
@@ -635,185 +1008,126 @@ pub async fn run_execution(
                         // update the variable definitions and prompt histories
                         variable_definitions
                             .extend(progressed_execution.current_variable_definitions.clone());
-                        prompt_histories = progressed_execution.atomic_history.clone();
+                        *prompt_histories = progressed_execution.atomic_history.clone();
+
+                        *local_accumulator = returned_accumulator.clone();
 
-                        local_accumulator = returned_accumulator.clone();
+                        match local_accumulator {
+                            None => {
+                                break;
+                            }
+                            Some(_) => {
+                                continue;
+                            }
+                        }
                     }
-                    // Err(execution) => {
-                    //     return Err(execution);
-                    // }
-                    _ => {
-                        println!("Error at process execution");
+                    Err((_execution, err)) => {
+                        return Err(err);
                     }
                 }
             }
-            Ok(NodeTypes::Prompt) => {
-                // we need to replace the prompt text input_variables with their definitions
-                match handle_prompt(
-                    current_node.clone(),
-                    variable_definitions.clone(),
-                    local_accumulator.clone(),
-                    "gpt-4-1106-preview".to_string(),
-                    user_settings.clone(),
-                )
-                .await
-                {
-                    Ok((prompt_history, local_variable_definitions)) => {
-                        prompt_histories.push(prompt_history);
-                        // update the variable definitions
-                        variable_definitions.extend(local_variable_definitions.clone());
-                    }
-                    Err(_) => {
-                        return Err(execution);
-                    }
+        }
+        Ok(NodeTypes::Conditional) => {
+            // In this case, the main thing we need to do is determine if the loop should continue (by returning the accumulator and the process) OR if it should exit to one of the external branches
+
+            // Check if any of the output_variables of the process containing this conditional are currently defined
+
+            match handle_conditional(
+                current_node.clone(),
+                variable_definitions.clone(),
+                language_model(&context.user_settings),
+                context,
+            )
+            .await
+            {
+                Ok((prompt_history, local_variable_definitions, accumulator)) => {
+                    prompt_histories.push(redact_log(&prompt_history, &context.secrets));
+                    // update the variable definitions
+                    variable_definitions.extend(local_variable_definitions.clone());
+                    *local_accumulator = accumulator.clone();
+                }
+                Err(err) => {
+                    return Err(err);
                 }
             }
-            Ok(NodeTypes::Loop) => {
-                let contained_loop: Loop;
 
-                match current_node.node_content.unwrap().node_content.unwrap() {
-                    NodeContentEnum::Loop(looop) => {
-                        contained_loop = looop;
-                    }
-                    _ => {
-                        println!("Somehow the stored contents is not actually a loop");
-                        continue;
-                    }
+            // For inspiration, a conditional should be handled VERY similarly to a prompt
+        }
+        Ok(NodeTypes::Command) => {
+            let command: Command;
+
+            match current_node
+                .clone()
+                .node_content
+                .unwrap()
+                .node_content
+                .unwrap()
+            {
+                NodeContentEnum::Command(c) => {
+                    command = c;
                 }
-
-                // Run the process (marked as a loop so that the aggregator is injected into the promp)
-
-                let max_iterations = contained_loop.max_iterations;
-
-                // run the following loop up to and including max iterations. This
-                for _i in 1..max_iterations {
-                    // an execution may be returned that contains an external branch (with an empty accumulator) OR the accumulator containing text to feed into the next iteration of the loop
-
-                    let local_execution = process_to_execution(
-                        variable_definitions.clone(),
-                        contained_loop.clone().process.unwrap().clone(),
-                        prompt_histories.clone(),
-                    );
-
-                    match run_execution(
-                        local_execution,
-                        local_accumulator.clone(),
-                        docker_id.clone(),
-                        docker_instance,
-                        user_settings.clone(),
-                    )
-                    .await
-                    {
-                        Ok((progressed_execution, returned_accumulator)) => {
-                            println!("{}", "Process executed successfully".green());
-                            // update the variable definitions and prompt histories
-                            variable_definitions
-                                .extend(progressed_execution.current_variable_definitions.clone());
-                            prompt_histories = progressed_execution.atomic_history.clone();
-
-                            local_accumulator = returned_accumulator.clone();
-
-                            match local_accumulator {
-                                None => {
-                                    break;
-                                }
-                                Some(_) => {
-                                    continue;
-                                }
-                            }
-                        }
-                        Err(execution) => {
-                            return Err(execution);
-                        }
-                    }
+                _ => {
+                    println!("Command not handled");
+                    return Ok(());
                 }
             }
-            Ok(NodeTypes::Conditional) => {
-                // In this case, the main thing we need to do is determine if the loop should continue (by returning the accumulator and the process) OR if it should exit to one of the external branches
-
-                // Check if any of the output_variables of the process containing this conditional are currently defined
 
-                match handle_conditional(
-                    current_node.clone(),
-                    variable_definitions.clone(),
-                    "gpt-4-1106-preview".to_string(),
-                )
-                .await
-                {
-                    Ok((prompt_history, local_variable_definitions, accumulator)) => {
-                        prompt_histories.push(prompt_history);
-                        // update the variable definitions
-                        variable_definitions.extend(local_variable_definitions.clone());
-                        local_accumulator = accumulator.clone();
-                    }
-                    Err(_) => {
-                        return Err(execution);
-                    }
+            match handle_command(
+                current_node.clone(),
+                variable_definitions.clone(),
+                local_accumulator.clone(),
+                language_model(&context.user_settings),
+                command.clone(),
+                context,
+            )
+            .await
+            {
+                Ok(atomic_log) => {
+                    prompt_histories.push(redact_log(&atomic_log, &context.secrets));
                 }
-
-                // For inspiration, a conditional should be handled VERY similarly to a prompt
-            }
-            Ok(NodeTypes::Command) => {
-                let command: Command;
-
-                match current_node
-                    .clone()
-                    .node_content
-                    .unwrap()
-                    .node_content
-                    .unwrap()
-                {
-                    NodeContentEnum::Command(c) => {
-                        command = c;
-                    }
-                    _ => {
-                        println!("Command not handled");
-                        continue;
-                    }
+                Err(err) => {
+                    println!("Error with running command: {}", redact(&err, &context.secrets));
+                    return Err(err);
                 }
-
-                match handle_command(
-                    current_node.clone(),
-                    variable_definitions.clone(),
-                    local_accumulator.clone(),
-                    "gpt-4-1106-preview".to_string(),
-                    command.clone(),
-                    docker_instance,
-                    docker_id.clone().unwrap(),
-                )
-                .await
-                {
-                    Ok(atomic_log) => {
-                        prompt_histories.push(atomic_log);
-                    }
-                    Err(_err) => {
-                        // return Err(execution);
-                        println!("Error with running command");
-                    }
-                }
-
-                // There will be a loop here that looks at the goal, the current output and determines if either: 1) a new command must be run OR 2) the goal has been reached.
-            }
-            _ => {
-                println!("Other types not implemented yet");
-                continue;
             }
+
+            // There will be a loop here that looks at the goal, the current output and determines if either: 1) a new command must be run OR 2) the goal has been reached.
+        }
+        Ok(NodeTypes::MemoryRead) => {
+            let atomic_log = handle_memory_read(&current_node, variable_definitions, context)?;
+            prompt_histories.push(redact_log(&atomic_log, &context.secrets));
+        }
+        Ok(NodeTypes::MemoryWrite) => {
+            let atomic_log = handle_memory_write(&current_node, variable_definitions, context)?;
+            prompt_histories.push(redact_log(&atomic_log, &context.secrets));
+        }
+        Ok(NodeTypes::Retrieve) => {
+            let atomic_log = handle_retrieve(&current_node, variable_definitions, context)?;
+            prompt_histories.push(redact_log(&atomic_log, &context.secrets));
+        }
+        Ok(NodeTypes::Transform) => {
+            let atomic_log = handle_transform(&current_node, variable_definitions, context)?;
+            prompt_histories.push(redact_log(&atomic_log, &context.secrets));
+        }
+        Ok(NodeTypes::HttpRequest) => {
+            let atomic_log =
+                handle_http_request(&current_node, variable_definitions, context).await?;
+            prompt_histories.push(redact_log(&atomic_log, &context.secrets));
+        }
+        _ => {
+            println!("Other types not implemented yet");
+            return Ok(());
         }
     }
-    let mut response = execution.clone();
-
-    // Change this to use a prompt history
-    // response.node_execution_response = node_execution_response;
-    response.current_variable_definitions = variable_definitions.clone();
-    response.atomic_history = prompt_histories.clone();
 
-    return Ok((response, local_accumulator.clone()));
+    Ok(())
 }
 
 pub fn process_to_execution(
     current_variables: HashMap<String, generated_types::Value>,
     process: Process,
     prompt_histories: Vec<AtomicExecutionLog>,
+    process_id: String,
 ) -> Execution {
     let execution: Execution = Execution {
         current_variable_definitions: current_variables,
@@ -821,6 +1135,8 @@ pub fn process_to_execution(
         current_node: Some(process.clone().topological_order.first().unwrap().clone()),
         atomic_history: prompt_histories,
         execution_id: uuid::Uuid::new_v4().to_string(),
+        initial_variable_definitions: HashMap::new(),
+        process_id,
     };
 
     return execution;
@@ -830,13 +1146,19 @@ pub async fn handle_prompt(
     current_node: Node,
     mut variable_definitions: HashMap<String, generated_types::Value>,
     accumulator: Option<String>,
+    prompt_histories: &mut Vec<AtomicExecutionLog>,
     language_model_version: String,
-    user_settings: Arc<UserSettings>,
-) -> Result<(AtomicExecutionLog, HashMap<String, generated_types::Value>), ()> {
+    context: &ExecutionContext,
+) -> Result<(AtomicExecutionLog, HashMap<String, generated_types::Value>), String> {
     let mut prompt_text: String = "".to_string();
     let mut hydrated_prompt_text: String = "".to_string();
+    let mut system_prompt: String = "".to_string();
+    let mut tool_node_ids: Vec<String> = Vec::new();
+
+    let api_key = context.user_settings.openai_api_key.clone();
 
-    let api_key = user_settings.openai_api_key.clone();
+    let cache_policy =
+        CachePolicies::try_from(current_node.cache_policy).unwrap_or(CachePolicies::NoCache);
 
     let additional_instruction =
         "When coming up with a response, please make the fields of the json response be the following: ".to_string();
@@ -851,150 +1173,146 @@ pub async fn handle_prompt(
 
     let variable_string: String = current_node.output_variables.join(", ");
 
-    match current_node.node_content.unwrap().node_content.unwrap() {
-        NodeContentEnum::Prompt(prompt) => {
-            // hydrate the prompt text with the variable definitions
+    let schema = OutputSchema::for_node(&current_node);
 
-            let mut handlebars = Handlebars::new();
+    let schema_string = schema.to_json_schema().to_string();
 
-            let string_map = convert_to_string_map(variable_definitions.clone());
+    let schema_instruction = format!(
+        "The json response must match the following JSON schema: {}",
+        schema_string
+    );
 
-            let json_variable_definitions: serde_json::Value = serde_json::json!(string_map);
+    match current_node.node_content.clone().unwrap().node_content.unwrap() {
+        NodeContentEnum::Prompt(prompt) => {
+            // hydrate the prompt text with the variable definitions
 
-            handlebars
-                .register_template_string("prompt", prompt.clone().prompt)
-                .unwrap();
+            hydrated_prompt_text =
+                render_template(
+                    "prompt",
+                    &prompt.prompt,
+                    &variable_definitions,
+                    &context.secrets,
+                )?;
 
-            hydrated_prompt_text = handlebars
-                .render("prompt", &json_variable_definitions)
-                .unwrap();
+            system_prompt = prompt.system.clone();
+            tool_node_ids = prompt.tool_node_ids.clone();
 
             match accumulator {
                 Some(accumulator_text) => {
                     prompt_text = format!(
-                        "{}\n{}\n {} {} {}",
+                        "{}\n{}\n {} {}\n{} {}",
                         hydrated_prompt_text.clone(),
                         accumulator_text.clone(),
                         additional_instruction,
                         variable_string,
+                        schema_instruction,
                         more_additional_instruction
                     );
                 }
                 None => {
                     prompt_text = format!(
-                        "{} {} {} {}",
+                        "{} {} {}\n{} {}",
                         hydrated_prompt_text.clone(),
                         additional_instruction,
                         variable_string,
+                        schema_instruction,
                         more_additional_instruction
                     );
                 }
             }
 
-            print!("Prompt text: {}", prompt_text.green());
+            print!(
+                "Prompt text: {}",
+                redact(&prompt_text, &context.secrets).green()
+            );
         }
         _ => {
             println!("prompt not handled");
         }
     }
 
-    let config = OpenAIConfig::new().with_api_key(api_key);
-
-    let client = Client::with_config(config);
+    // Inside a conversation the earlier exchanges are sent along with the prompt
+    let exchanges = match &context.conversation {
+        Some(scope) => conversation_exchanges(scope, prompt_histories),
+        None => Vec::new(),
+    };
 
-    let user_message = ChatCompletionRequestUserMessage {
-        content: Some(ChatCompletionRequestUserMessageContent::Text(prompt_text)),
-        role: Role::User,
+    // The same prompt can get a different response depending on what was said before it, so the exchanges are part of the cache key
+    let conversation_text: String = exchanges
+        .iter()
+        .map(|(prompt, response)| format!("{}\n{}\n", prompt, response))
+        .collect();
+
+    let cache_prompt = format!("{}{}", conversation_text, prompt_text);
+
+    let tools = load_tools(&tool_node_ids, context)?;
+
+    // A cached response would skip the tool calls (and whatever they do) so prompts with tools always go to the model. Secret values are never written to the cache, so prompts that contain one aren't cached either.
+    let cacheable = tools.is_empty() && !contains_secret(&cache_prompt, &context.secrets);
+
+    let cached_response = match cache_policy {
+        CachePolicies::UseCache if cacheable => lookup_cached_response(
+            context,
+            &language_model_version,
+            &system_prompt,
+            &cache_prompt,
+            &schema_string,
+        )
+        // Responses cached before the declarations of the node changed may not fit the schema anymore
+        .filter(|(_, object)| schema.validate(object).is_ok()),
+        _ => None,
     };
 
-    let message: ChatCompletionRequestMessage = ChatCompletionRequestMessage::User(user_message);
+    let cache_hit = cached_response.is_some();
 
-    // Create request using builder pattern
-    // Every request struct has companion builder struct with same name + Args suffix
-    let mut request = CreateChatCompletionRequest::default();
+    let (json_string, mut response_object) = match cached_response {
+        Some(cached_response) => cached_response,
+        None => {
+            let config = OpenAIConfig::new().with_api_key(api_key);
 
-    request.messages = vec![message];
-    request.response_format = Some(ChatCompletionResponseFormat {
-        r#type: ChatCompletionResponseFormatType::JsonObject,
-    });
+            let client = Client::with_config(config);
+
+            let mut messages = Vec::new();
 
-    // Any model can be used so long as it supports response_format
-    // request.model = "gpt-4-1106-preview".to_string();
-    request.model = language_model_version.clone();
+            if !system_prompt.trim().is_empty() {
+                messages.push(system_message(&system_prompt).map_err(|err| err.to_string())?);
+            }
+
+            messages
+                .extend(conversation_messages(&exchanges).map_err(|err| err.to_string())?);
+            messages.push(user_message(&prompt_text).map_err(|err| err.to_string())?);
+
+            if !tools.is_empty() {
+                call_tools(
+                    &client,
+                    &language_model_version,
+                    &mut messages,
+                    &tools,
+                    prompt_histories,
+                    context,
+                )
+                .await?;
+            }
 
-    // Call API
-    let response = match client.chat().create(request).await {
-        Ok(response) => response,
-        Err(_) => {
-            println!("{}", "Error with OpenAI API".red());
-            return Err(());
+            request_json_object(&client, &language_model_version, messages, Some(&schema))
+                .await
+                .map_err(|err| err.to_string())?
         }
     };
 
-    let json_string = response
-        .choices
-        .first()
-        .unwrap()
-        .message
-        .content
-        .clone()
-        .unwrap()
-        .as_str()
-        .to_string();
-
     let node_info = current_node.node_info.clone().unwrap();
 
     let hydrated_and_cleaned_prompt_text = clean_response(&hydrated_prompt_text);
 
-    let mut execution_response_hashmap = HashMap::new();
-
-    let value: serde_json::Value = serde_json::from_str(json_string.as_str()).unwrap();
-
     println!("{}", "VARIABLES RETURNED FROM PROMPT:".green());
 
-    if let Some(obj) = value.as_object() {
-        for (key, value) in obj {
-            let mut return_val: crate::generated_types::Value = crate::generated_types::Value {
-                value_type: Some(value::ValueType::StringValue(
-                    "err: uninitialized value".to_string(),
-                )),
-            };
-            match value {
-                serde_json::Value::String(s) => {
-                    return_val = crate::generated_types::Value {
-                        value_type: Some(value::ValueType::StringValue(s.clone())),
-                    };
-                }
-                serde_json::Value::Number(n) => {
-                    let return_num = n.as_f64().unwrap();
+    remove_empty_error(&mut response_object);
 
-                    return_val = crate::generated_types::Value {
-                        value_type: Some(value::ValueType::NumberValue(return_num)),
-                    };
-                }
-                serde_json::Value::Array(arr) => {
-                    let string_list = crate::generated_types::StringList {
-                        values: arr.clone().into_iter().map(|v| v.to_string()).collect(),
-                    };
-
-                    return_val = crate::generated_types::Value {
-                        value_type: Some(value::ValueType::StringList(string_list)),
-                    };
-                }
-                // Handle other types as needed
-                _ => {
-                    value.to_string();
-                }
-            };
+    let mut execution_response_hashmap = json_object_to_values(&response_object);
 
-            execution_response_hashmap.insert(key.clone(), return_val.clone());
+    apply_output_types(&current_node, &mut execution_response_hashmap);
 
-            variable_definitions.insert(key.clone(), return_val.clone());
-        }
-    } else {
-        println!("{}", "The JSON is not an object.".red());
-        return Err(());
-    }
+    variable_definitions.extend(execution_response_hashmap.clone());
 
     // see if all of the output_variables of the node are in the variable definition hashmap:
 
@@ -1005,23 +1323,177 @@ pub async fn handle_prompt(
     for output_var in check_output_vars.iter() {
         if !variable_definitions.contains_key(output_var) {
             println!("Missing variable: {}", output_var.red());
-            // Handle the missing variable case here (e.g., error handling or logging)
 
             // Send back the Execution to the frontend and let the user decide what to do.
 
-            return Err(());
+            return Err(format!(
+                "The model did not provide the output variable: {}",
+                output_var
+            ));
         }
     }
 
+    // Only responses that made it through the checks above are worth caching
+    if cacheable && !cache_hit && cache_policy != CachePolicies::NoCache {
+        store_cached_response(
+            context,
+            &language_model_version,
+            &system_prompt,
+            &cache_prompt,
+            &schema_string,
+            &redact(&json_string, &context.secrets),
+        );
+    }
+
     let prompt_history = AtomicExecutionLog {
         prompt: hydrated_and_cleaned_prompt_text.clone(),
         response: execution_response_hashmap.clone(),
         node_info: Some(node_info.clone()),
+        cache_hit,
     };
 
     return Ok((prompt_history, variable_definitions));
 }
 
+// Responses are only cached for a signed in user, and the cache of one user is never used for another
+fn cache_owner(context: &ExecutionContext) -> Option<String> {
+    context
+        .user_email
+        .as_ref()
+        .map(|email| email.trim().to_lowercase())
+        .filter(|email| !email.is_empty())
+}
+
+// The schema always asks for the error field, there is no point in keeping it around when nothing went wrong
+fn remove_empty_error(object: &mut serde_json::Map<String, serde_json::Value>) {
+    if object.get("error").and_then(|error| error.as_str()) == Some("") {
+        object.remove("error");
+    }
+}
+
+// Returns the cached response for the request (if there is one). Any problem reading the cache is treated like a cache miss.
+fn lookup_cached_response(
+    context: &ExecutionContext,
+    model: &str,
+    system_prompt: &str,
+    prompt: &str,
+    output_variables: &str,
+) -> Option<(String, serde_json::Map<String, serde_json::Value>)> {
+    let owner_email = cache_owner(context)?;
+
+    match fetch_cached_response(
+        context.pool.clone(),
+        &owner_email,
+        model,
+        system_prompt,
+        prompt,
+        output_variables,
+    ) {
+        Ok(Some(response)) => match serde_json::from_str::<serde_json::Value>(&response) {
+            Ok(serde_json::Value::Object(object)) => {
+                println!("{}", "Using the cached response for this prompt".green());
+                Some((response, object))
+            }
+            _ => {
+                println!("{}", "Ignoring a cached response that isn't a JSON object".red());
+                None
+            }
+        },
+        Ok(None) => None,
+        Err(err) => {
+            println!("{}: {:?}", "Unable to read the response cache".red(), err);
+            None
+        }
+    }
+}
+
+fn store_cached_response(
+    context: &ExecutionContext,
+    model: &str,
+    system_prompt: &str,
+    prompt: &str,
+    output_variables: &str,
+    response: &str,
+) {
+    let owner_email = match cache_owner(context) {
+        Some(owner_email) => owner_email,
+        None => return,
+    };
+
+    if let Err(err) = insert_cached_response(
+        context.pool.clone(),
+        &owner_email,
+        model,
+        system_prompt,
+        prompt,
+        output_variables,
+        response,
+    ) {
+        println!("{}: {:?}", "Unable to write to the response cache".red(), err);
+    }
+}
+
+// Converts the fields of a JSON object returned by the model into variable definitions
+pub fn json_object_to_values(
+    object: &serde_json::Map<String, serde_json::Value>,
+) -> HashMap<String, generated_types::Value> {
+    let mut values = HashMap::new();
+
+    for (key, value) in object {
+        let mut return_val: crate::generated_types::Value = crate::generated_types::Value {
+            value_type: Some(value::ValueType::StringValue(
+                "err: uninitialized value".to_string(),
+            )),
+        };
+        match value {
+            serde_json::Value::String(s) => {
+                return_val = crate::generated_types::Value {
+                    value_type: Some(value::ValueType::StringValue(s.clone())),
+                };
+            }
+            serde_json::Value::Number(n) => {
+                let return_num = n.as_f64().unwrap_or_default();
+
+                return_val = crate::generated_types::Value {
+                    value_type: Some(value::ValueType::NumberValue(return_num)),
+                };
+            }
+            serde_json::Value::Array(arr) => {
+                // Strings are kept as they are, anything else in the list is stored as JSON
+                let string_list = crate::generated_types::StringList {
+                    values: arr
+                        .iter()
+                        .map(|v| match v {
+                            serde_json::Value::String(s) => s.clone(),
+                            other => other.to_string(),
+                        })
+                        .collect(),
+                };
+
+                return_val = crate::generated_types::Value {
+                    value_type: Some(value::ValueType::StringList(string_list)),
+                };
+            }
+            serde_json::Value::Bool(b) => {
+                return_val = crate::generated_types::Value {
+                    value_type: Some(value::ValueType::BoolValue(*b)),
+                };
+            }
+            serde_json::Value::Object(_) => {
+                return_val = crate::generated_types::Value {
+                    value_type: Some(value::ValueType::JsonValue(value.to_string())),
+                };
+            }
+            // Handle other types as needed
+            _ => {}
+        };
+
+        values.insert(key.clone(), return_val);
+    }
+
+    values
+}
+
 fn convert_to_string_map(
     variable_definitions: HashMap<String, generated_types::Value>,
 ) -> HashMap<String, String> {
@@ -1040,6 +1512,15 @@ fn convert_to_string_map(
                 let string_list_string = string_list_vec.join(", ");
                 string_map.insert(key, string_list_string);
             }
+            value::ValueType::BoolValue(b) => {
+                string_map.insert(key, b.to_string());
+            }
+            value::ValueType::IntegerValue(i) => {
+                string_map.insert(key, i.to_string());
+            }
+            value::ValueType::JsonValue(json) => {
+                string_map.insert(key, json);
+            }
         }
     }
 
@@ -1052,26 +1533,20 @@ pub async fn handle_command(
     accumulator: Option<String>,
     language_model_version: String,
     command: Command,
-    docker_instance: &Docker,
-    docker_id: String,
-) -> Result<AtomicExecutionLog, ()> {
+    context: &ExecutionContext,
+) -> Result<AtomicExecutionLog, String> {
+    let docker_instance = &context.docker;
+    let docker_id = match &context.docker_id {
+        Some(docker_id) => docker_id.clone(),
+        None => return Err("There is no container to run the command in".to_string()),
+    };
+    let secrets = &context.secrets;
+
     let mut prompt_text: String = "".to_string();
 
     let mut goal = command.goal.clone();
 
-    let mut handlebars = Handlebars::new();
-
-    let string_map = convert_to_string_map(variable_definitions.clone());
-
-    let json_variable_definitions: serde_json::Value = serde_json::json!(string_map);
-
-    handlebars
-        .register_template_string("goal", goal.clone())
-        .unwrap();
-
-    goal = handlebars
-        .render("goal", &json_variable_definitions)
-        .unwrap();
+    goal = render_template("goal", &goal, &variable_definitions, secrets)?;
 
     // let the command_line_history string be empty OR the contents of the accumulator:
     let command_line_history: String = match accumulator {
@@ -1085,110 +1560,45 @@ pub async fn handle_command(
         command_line_history
     );
 
+    // Only the names of the secrets are given to the model, the values are set in the environment of the command
+    if !secrets.is_empty() {
+        let mut secret_names: Vec<&String> = secrets.keys().collect();
+        secret_names.sort();
+
+        prompt_text = format!(
+            "{}\nThe following environment variables are set and can be used in the commands (never print their values): {}",
+            prompt_text,
+            secret_names
+                .iter()
+                .map(|name| format!("${}", name))
+                .collect::<Vec<String>>()
+                .join(", ")
+        );
+    }
+
     // I believe this already pulls the key from the environmental variable.
     let client = Client::new();
 
-    let user_message = ChatCompletionRequestUserMessage {
-        content: Some(ChatCompletionRequestUserMessageContent::Text(
-            prompt_text.clone(),
-        )),
-        role: Role::User,
-    };
-
-    let message: ChatCompletionRequestMessage = ChatCompletionRequestMessage::User(user_message);
-
-    // Create request using builder pattern
-    // Every request struct has companion builder struct with same name + Args suffix
-    let mut request = CreateChatCompletionRequest::default();
+    let messages = prompt_messages("", &prompt_text).map_err(|err| err.to_string())?;
 
-    request.messages = vec![message];
-    request.response_format = Some(ChatCompletionResponseFormat {
-        r#type: ChatCompletionResponseFormatType::JsonObject,
-    });
-
-    // Any model can be used so long as it supports response_format
-    // request.model = "gpt-4-1106-preview".to_string();
-    request.model = language_model_version.clone();
+    let schema = OutputSchema::for_strings(&["command", "verification_command"]);
 
-    // Call API
-    let response = client.chat().create(request).await.unwrap();
+    let (json_string, mut response_object) =
+        request_json_object(&client, &language_model_version, messages, Some(&schema))
+            .await
+            .map_err(|err| err.to_string())?;
 
-    println!(
-        "{}",
-        response
-            .choices
-            .first()
-            .unwrap()
-            .message
-            .content
-            .clone()
-            .unwrap()
-            .as_str()
-            .to_string()
-    );
+    remove_empty_error(&mut response_object);
 
-    let json_string = response
-        .choices
-        .first()
-        .unwrap()
-        .message
-        .content
-        .clone()
-        .unwrap()
-        .as_str()
-        .to_string();
+    println!("{}", redact(&json_string, secrets));
 
     let node_info = current_node.node_info.clone().unwrap();
 
     // let hydrated_and_cleaned_prompt_text = clean_response(&hydrated_prompt_text);
 
-    let mut execution_response_hashmap = HashMap::new();
-
-    let value: serde_json::Value = serde_json::from_str(json_string.as_str()).unwrap();
-
-    if let Some(obj) = value.as_object() {
-        for (key, value) in obj {
-            let mut return_val: crate::generated_types::Value = crate::generated_types::Value {
-                value_type: Some(value::ValueType::StringValue(
-                    "err: uninitialized value".to_string(),
-                )),
-            };
-            match value {
-                serde_json::Value::String(s) => {
-                    return_val = crate::generated_types::Value {
-                        value_type: Some(value::ValueType::StringValue(s.clone())),
-                    };
-                }
-                serde_json::Value::Number(n) => {
-                    let return_num = n.as_f64().unwrap();
-
-                    return_val = crate::generated_types::Value {
-                        value_type: Some(value::ValueType::NumberValue(return_num)),
-                    };
-                }
-                serde_json::Value::Array(arr) => {
-                    let string_list = crate::generated_types::StringList {
-                        values: arr.clone().into_iter().map(|v| v.to_string()).collect(),
-                    };
-
-                    return_val = crate::generated_types::Value {
-                        value_type: Some(value::ValueType::StringList(string_list)),
-                    };
-                }
-                // Handle other types as needed
-                _ => {
-                    value.to_string();
-                }
-            };
-
-            execution_response_hashmap.insert(key.clone(), return_val.clone());
+    let mut execution_response_hashmap = json_object_to_values(&response_object);
 
-            variable_definitions.insert(key.clone(), return_val.clone());
-        }
-    } else {
-        println!("{}", "The JSON is not an object.".red());
-        return Err(());
-    }
+    variable_definitions.extend(execution_response_hashmap.clone());
 
     // if let Some(obj) = value.as_object() {
     //     for (key, value) in obj {
@@ -1253,7 +1663,7 @@ pub async fn handle_command(
                 "{}",
                 "The command field was not found in the response.".red()
             );
-            return Err(());
+            return Err("The command field was not found in the response.".to_string());
         }
     }
 
@@ -1264,23 +1674,31 @@ pub async fn handle_command(
         None => {
             println!(
                 "{}",
-                "The command field was not found in the response.".red()
+                "The verification_command field was not found in the response.".red()
+            );
+            return Err(
+                "The verification_command field was not found in the response.".to_string(),
             );
-            return Err(());
         }
     }
 
     println!(
         "Running the following command: '{}'",
-        run_this_command.green()
+        redact(&run_this_command, secrets).green()
     );
 
-    match run_command(run_this_command.clone(), docker_id.clone(), docker_instance).await {
+    match run_command(
+        run_this_command.clone(),
+        docker_id.clone(),
+        docker_instance,
+        secrets,
+    )
+    .await {
         Ok(res) => {
             println!(
                 "{}\nResult: {:?}",
                 "The command was run successfully".green(),
-                res
+                redact(&res, secrets)
             );
 
             let return_value = crate::generated_types::Value {
@@ -1297,12 +1715,18 @@ pub async fn handle_command(
     }
 
     // run the verfication command here:
-    match run_command(verification_command.clone(), docker_id, docker_instance).await {
+    match run_command(
+        verification_command.clone(),
+        docker_id,
+        docker_instance,
+        secrets,
+    )
+    .await {
         Ok(verification_res) => {
             println!(
                 "{}\nResult: {:?}",
                 "The verification command was run successfully".green(),
-                verification_res
+                redact(&verification_res, secrets)
             );
 
             let return_value = crate::generated_types::Value {
@@ -1315,7 +1739,7 @@ pub async fn handle_command(
             );
         }
         Err(err) => {
-            println!("{}: ", "Error verifying command".red());
+            println!("{}: {}", "Error verifying command".red(), redact(&err, secrets));
         }
     }
 
@@ -1325,6 +1749,7 @@ pub async fn handle_command(
         prompt: prompt_text.clone(),
         response: execution_response_hashmap.clone(),
         node_info: Some(node_info.clone()),
+        cache_hit: false,
     };
 
     return Ok(prompt_history);
@@ -1333,17 +1758,24 @@ pub async fn handle_command(
 pub async fn handle_conditional(
     current_node: Node,
     mut variable_definitions: HashMap<String, generated_types::Value>,
-    _language_model_version: String,
+    language_model_version: String,
+    context: &ExecutionContext,
 ) -> Result<
     (
         AtomicExecutionLog,
         HashMap<String, generated_types::Value>,
         Option<String>,
     ),
-    (),
+    String,
 > {
     let mut prompt_text: String = "".to_string();
     let mut hydrated_prompt_text: String = "".to_string();
+    let mut system_prompt: String = "".to_string();
+
+    let model = language_model_version;
+
+    let cache_policy =
+        CachePolicies::try_from(current_node.cache_policy).unwrap_or(CachePolicies::NoCache);
 
     let additional_instruction = "
 
@@ -1362,23 +1794,24 @@ pub async fn handle_conditional(
 
     let variable_string: String = current_node.output_variables.join(", ");
 
+    let schema = OutputSchema::for_conditional(&current_node);
+
+    // The cache is keyed on the schema so that changing the declarations of the node doesn't hit responses made for the old ones
+    let schema_string = schema.to_json_schema().to_string();
+
     match current_node.node_content.unwrap().node_content.unwrap() {
         NodeContentEnum::Prompt(prompt) => {
             // hydrate the prompt text with the variable definitions
 
-            let mut handlebars = Handlebars::new();
+            hydrated_prompt_text =
+                render_template(
+                    "prompt",
+                    &prompt.prompt,
+                    &variable_definitions,
+                    &context.secrets,
+                )?;
 
-            let string_map = convert_to_string_map(variable_definitions.clone());
-
-            let json_variable_definitions: serde_json::Value = serde_json::json!(string_map);
-
-            handlebars
-                .register_template_string("prompt", prompt.clone().prompt)
-                .unwrap();
-
-            hydrated_prompt_text = handlebars
-                .render("prompt", &json_variable_definitions)
-                .unwrap();
+            system_prompt = prompt.system.clone();
 
             prompt_text = format!(
                 "{} {} {} {}",
@@ -1388,179 +1821,118 @@ pub async fn handle_conditional(
                 more_additional_instruction
             );
 
-            print!("Prompt text: {}", prompt_text.green());
+            print!(
+                "Prompt text: {}",
+                redact(&prompt_text, &context.secrets).green()
+            );
         }
         _ => {
             println!("prompt not handled");
         }
     }
 
-    // I believe this already pulls the key from the environmental variable.
-    let client = Client::new();
-
-    let user_message = ChatCompletionRequestUserMessage {
-        content: Some(ChatCompletionRequestUserMessageContent::Text(prompt_text)),
-        role: Role::User,
+    // Secret values are never written to the cache, so prompts that contain one aren't cached
+    let cacheable = !contains_secret(&prompt_text, &context.secrets);
+
+    let cached_response = match cache_policy {
+        CachePolicies::UseCache if cacheable => lookup_cached_response(
+            context,
+            &model,
+            &system_prompt,
+            &prompt_text,
+            &schema_string,
+        )
+        .filter(|(_, object)| schema.validate(object).is_ok()),
+        _ => None,
     };
 
-    let message: ChatCompletionRequestMessage = ChatCompletionRequestMessage::User(user_message);
+    let cache_hit = cached_response.is_some();
 
-    // Create request using builder pattern
-    // Every request struct has companion builder struct with same name + Args suffix
-    let mut request = CreateChatCompletionRequest::default();
+    let (json_string, mut response_object) = match cached_response {
+        Some(cached_response) => cached_response,
+        None => {
+            // I believe this already pulls the key from the environmental variable.
+            let client = Client::new();
 
-    request.messages = vec![message];
-    request.response_format = Some(ChatCompletionResponseFormat {
-        r#type: ChatCompletionResponseFormatType::JsonObject,
-    });
+            let messages =
+                prompt_messages(&system_prompt, &prompt_text).map_err(|err| err.to_string())?;
 
-    // Any model can be used so long as it supports response_format
-    request.model = "gpt-4-1106-preview".to_string();
+            let reply = request_json_object(&client, &model, messages, Some(&schema))
+                .await
+                .map_err(|err| err.to_string())?;
 
-    // Call API
-    let response = client.chat().create(request).await.unwrap();
+            println!("{}", redact(&reply.0, &context.secrets));
 
-    println!(
-        "{}",
-        response
-            .choices
-            .first()
-            .unwrap()
-            .message
-            .content
-            .clone()
-            .unwrap()
-            .as_str()
-            .to_string()
-    );
-
-    let json_string = response
-        .choices
-        .first()
-        .unwrap()
-        .message
-        .content
-        .clone()
-        .unwrap()
-        .as_str()
-        .to_string();
+            reply
+        }
+    };
 
     let node_info = current_node.node_info.clone().unwrap();
 
     let hydrated_and_cleaned_prompt_text = clean_response(&hydrated_prompt_text);
 
-    let mut execution_response_hashmap = HashMap::new();
-
-    let value: serde_json::Value = serde_json::from_str(json_string.as_str()).unwrap();
-    // if let Some(obj) = value.as_object() {
-    //     for (key, value) in obj {
-    //         println!("{}: {}", key, value);
-
-    //         let return_value = crate::generated_types::Value {
-    //             value_type: Some(value::ValueType::StringValue(value.clone().to_string())),
-    //         };
-
-    //         variable_definitions.insert(key.clone(), value.clone().to_string());
-    //         execution_response_hashmap.insert(key.clone(), return_value);
-    //     }
-    // } else {
-    //     println!("{}", "The JSON is not an object.".red());
-    // }
+    remove_empty_error(&mut response_object);
 
-    if let Some(obj) = value.as_object() {
-        for (key, value) in obj {
-            let mut return_val: crate::generated_types::Value = crate::generated_types::Value {
-                value_type: Some(value::ValueType::StringValue(
-                    "err: uninitialized value".to_string(),
-                )),
-            };
-            match value {
-                serde_json::Value::String(s) => {
-                    return_val = crate::generated_types::Value {
-                        value_type: Some(value::ValueType::StringValue(s.clone())),
-                    };
-                }
-                serde_json::Value::Number(n) => {
-                    let return_num = n.as_f64().unwrap();
+    let execution_response_hashmap = json_object_to_values(&response_object);
 
-                    return_val = crate::generated_types::Value {
-                        value_type: Some(value::ValueType::NumberValue(return_num)),
-                    };
-                }
-                serde_json::Value::Array(arr) => {
-                    let string_list = crate::generated_types::StringList {
-                        values: arr.clone().into_iter().map(|v| v.to_string()).collect(),
-                    };
-
-                    return_val = crate::generated_types::Value {
-                        value_type: Some(value::ValueType::StringList(string_list)),
-                    };
-                }
-                // Handle other types as needed
-                _ => {
-                    value.to_string();
-                }
-            };
+    variable_definitions.extend(execution_response_hashmap.clone());
 
-            execution_response_hashmap.insert(key.clone(), return_val.clone());
+    // check to see if the execution_response contains "accumulator". If it does, the loop needs another iteration and the conditional won't have decided on any of its output variables yet.
 
-            variable_definitions.insert(key.clone(), return_val.clone());
+    let accumulator: Option<String> = match execution_response_hashmap
+        .get("accumulator")
+        .and_then(|value| value.value_type.clone())
+    {
+        Some(value::ValueType::StringValue(value)) if !value.is_empty() => Some(value),
+        _ => {
+            println!(
+                "{}",
+                "The accumulator field was not found in the response.".yellow()
+            );
+            None
         }
-    } else {
-        println!("{}", "The JSON is not an object.".red());
-        return Err(());
-    }
+    };
 
-    // see if all of the output_variables of the node are in the variable definition hashmap:
+    if accumulator.is_none() {
+        // see if all of the output_variables of the node are in the variable definition hashmap:
 
-    let check_output_vars: Vec<String> = current_node.output_variables;
+        let check_output_vars: Vec<String> = current_node.output_variables;
 
-    //loop through check_output_vars and see if the key exists in the variable_definitions hashmap:
+        //loop through check_output_vars and see if the key exists in the variable_definitions hashmap:
 
-    for output_var in check_output_vars.iter() {
-        if !variable_definitions.contains_key(output_var) {
-            println!("Missing variable: {}", output_var.red());
-            // Handle the missing variable case here (e.g., error handling or logging)
+        for output_var in check_output_vars.iter() {
+            if !variable_definitions.contains_key(output_var) {
+                println!("Missing variable: {}", output_var.red());
 
-            // Send back the Execution to the frontend and let the user decide what to do.
+                // Send back the Execution to the frontend and let the user decide what to do.
 
-            return Err(());
+                return Err(format!(
+                    "The model did not provide the output variable: {}",
+                    output_var
+                ));
+            }
         }
     }
 
-    // check to see if the execution_response contains "accumulator"
-
-    let mut accumulator = String::new();
-
-    match execution_response_hashmap
-        .get("accumulator")
-        .unwrap()
-        .clone()
-        .value_type
-        .unwrap()
-    {
-        value::ValueType::StringValue(value) => {
-            accumulator = value.clone();
-        }
-        _ => {
-            println!(
-                "{}",
-                "The accumulator field was not found in the response.".red()
-            );
-        }
+    if cacheable && !cache_hit && cache_policy != CachePolicies::NoCache {
+        store_cached_response(
+            context,
+            &model,
+            &system_prompt,
+            &prompt_text,
+            &schema_string,
+            &redact(&json_string, &context.secrets),
+        );
     }
 
     let prompt_history = AtomicExecutionLog {
         prompt: hydrated_and_cleaned_prompt_text.clone(),
         response: execution_response_hashmap.clone(),
         node_info: Some(node_info.clone()),
+        cache_hit,
     };
 
-    return Ok((
-        prompt_history,
-        variable_definitions,
-        Some(accumulator.clone()),
-    ));
+    return Ok((prompt_history, variable_definitions, accumulator));
 }
 
 fn clean_response(input: &str) -> String {
@@ -1573,16 +1945,20 @@ async fn run_command(
     command: String,
     docker_id: String,
     docker_instance: &Docker,
+    secrets: &SecretValues,
 ) -> Result<String, String> {
-    println!("Preparing to run command: {}", command);
+    println!("Preparing to run command: {}", redact(&command, secrets));
 
     // strip any '"' characters from the command string
     let command = command.replace("\"", "");
 
+    let environment = secret_environment(secrets);
+
     let exec_options = CreateExecOptions {
         attach_stdout: Some(true),
         attach_stderr: Some(true),
         cmd: Some(vec!["sh", "-c", &command]),
+        env: Some(environment.iter().map(|entry| entry.as_str()).collect()),
         ..Default::default()
     };
 
@@ -1622,7 +1998,10 @@ async fn run_command(
                         match log {
                             LogOutput::StdOut { message } => {
                                 if let Ok(str_message) = String::from_utf8(message.to_vec()) {
-                                    println!("Received StdOut: {}", str_message);
+                                    println!(
+                                        "Received StdOut: {}",
+                                        redact(&str_message, secrets)
+                                    );
                                     full_output.push_str(&str_message);
                                 } else {
                                     println!("Received non-UTF8 StdOut data");
@@ -1630,7 +2009,10 @@ async fn run_command(
                             }
                             LogOutput::StdErr { message } => {
                                 if let Ok(str_message) = String::from_utf8(message.to_vec()) {
-                                    println!("Received StdErr: {}", str_message);
+                                    println!(
+                                        "Received StdErr: {}",
+                                        redact(&str_message, secrets)
+                                    );
                                     full_output.push_str(&str_message);
                                 } else {
                                     println!("Received non-UTF8 StdErr data");
@@ -0,0 +1,514 @@
+use crate::generated_types::{
+    node_content::NodeContent as NodeContentEnum, AtomicExecutionLog, HttpRequest, Node, Value,
+};
+use crate::graph::{json_object_to_values, ExecutionContext};
+use crate::json_path::select_value;
+use crate::secrets::redact;
+use crate::templating::render_template;
+use crate::variables::apply_output_types;
+
+use colored::*;
+use reqwest::{Method, StatusCode};
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+const DEFAULT_TIMEOUT_SECONDS: u64 = 30;
+const INITIAL_BACKOFF_MILLIS: u64 = 500;
+const MAX_BACKOFF_MILLIS: u64 = 10_000;
+
+// The request once every template in it has been filled in
+struct RenderedRequest {
+    method: Method,
+    url: String,
+    headers: Vec<(String, String)>,
+    body: String,
+}
+
+fn render_request(
+    http_request: &HttpRequest,
+    variable_definitions: &HashMap<String, Value>,
+    context: &ExecutionContext,
+) -> Result<RenderedRequest, String> {
+    let method_text = render_template(
+        "method",
+        &http_request.method,
+        variable_definitions,
+        &context.secrets,
+    )?;
+
+    let method = if method_text.trim().is_empty() {
+        Method::GET
+    } else {
+        Method::from_bytes(method_text.trim().to_uppercase().as_bytes())
+            .map_err(|_| format!("{} is not an HTTP method", method_text))?
+    };
+
+    let url = render_template(
+        "url",
+        &http_request.url,
+        variable_definitions,
+        &context.secrets,
+    )?;
+
+    let mut headers = Vec::new();
+    for (name, value) in &http_request.headers {
+        headers.push((
+            name.clone(),
+            render_template(
+                &format!("{} header", name),
+                value,
+                variable_definitions,
+                &context.secrets,
+            )?,
+        ));
+    }
+
+    let body = render_template(
+        "body",
+        &http_request.body,
+        variable_definitions,
+        &context.secrets,
+    )?;
+
+    Ok(RenderedRequest {
+        method,
+        url: url.trim().to_string(),
+        headers,
+        body,
+    })
+}
+
+// Rate limiting and server errors are usually over after a little while, anything else will fail the same way again
+fn should_retry(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+}
+
+fn backoff_delay(attempt: u32) -> Duration {
+    let millis = INITIAL_BACKOFF_MILLIS.saturating_mul(1 << (attempt - 1).min(16));
+    Duration::from_millis(millis.min(MAX_BACKOFF_MILLIS))
+}
+
+// Sends the request, retrying it up to max_retries times. Returns the status and body of the last response.
+async fn send_request(
+    request: &RenderedRequest,
+    timeout: Duration,
+    max_retries: u32,
+    context: &ExecutionContext,
+) -> Result<(StatusCode, String), String> {
+    let client = reqwest::Client::builder()
+        .timeout(timeout)
+        .build()
+        .map_err(|err| format!("Unable to create the HTTP client: {}", err))?;
+
+    let mut attempt: u32 = 0;
+
+    loop {
+        attempt += 1;
+
+        let mut builder = client.request(request.method.clone(), &request.url);
+        for (name, value) in &request.headers {
+            builder = builder.header(name, value);
+        }
+        if !request.body.is_empty() {
+            builder = builder.body(request.body.clone());
+        }
+
+        let problem = match builder.send().await {
+            Ok(response) => {
+                let status = response.status();
+                let body = response
+                    .text()
+                    .await
+                    .map_err(|err| format!("Unable to read the response: {}", err))?;
+
+                if !should_retry(status) || attempt > max_retries {
+                    return Ok((status, body));
+                }
+
+                format!("the server responded with {}", status)
+            }
+            Err(err) if (err.is_timeout() || err.is_connect()) && attempt <= max_retries => {
+                err.to_string()
+            }
+            Err(err) => {
+                return Err(redact(
+                    &format!("The request to {} failed: {}", request.url, err),
+                    &context.secrets,
+                ));
+            }
+        };
+
+        let delay = backoff_delay(attempt);
+        println!(
+            "{} {} (attempt {} of {}, retrying in {:?})",
+            "HTTP request failed:".yellow(),
+            redact(&problem, &context.secrets),
+            attempt,
+            max_retries + 1,
+            delay
+        );
+        tokio::time::sleep(delay).await;
+    }
+}
+
+// Picks the output variables out of the response. Every output variable without an extractor gets the whole body.
+fn extract_outputs(
+    node: &Node,
+    http_request: &HttpRequest,
+    body: &str,
+) -> Result<HashMap<String, Value>, String> {
+    let document = if http_request.extractors.is_empty() {
+        None
+    } else {
+        match serde_json::from_str::<serde_json::Value>(body) {
+            Ok(document) => Some(document),
+            Err(err) => return Err(format!("The response is not valid JSON: {}", err)),
+        }
+    };
+
+    let mut outputs = serde_json::Map::new();
+
+    for output_variable in &node.output_variables {
+        let value = match (http_request.extractors.get(output_variable), &document) {
+            (Some(path), Some(document)) => select_value(document, path)
+                .map_err(|err| format!("Unable to fill in {}: {}", output_variable, err))?,
+            _ => serde_json::Value::String(body.to_string()),
+        };
+
+        outputs.insert(output_variable.clone(), value);
+    }
+
+    let mut values = json_object_to_values(&outputs);
+    apply_output_types(node, &mut values);
+
+    Ok(values)
+}
+
+pub async fn handle_http_request(
+    node: &Node,
+    variable_definitions: &mut HashMap<String, Value>,
+    context: &ExecutionContext,
+) -> Result<AtomicExecutionLog, String> {
+    let http_request = match node
+        .node_content
+        .clone()
+        .and_then(|node_content| node_content.node_content)
+    {
+        Some(NodeContentEnum::HttpRequest(http_request)) => http_request,
+        _ => return Err("The HTTP node doesn't contain a request".to_string()),
+    };
+
+    let request = render_request(&http_request, variable_definitions, context)?;
+
+    let timeout = Duration::from_secs(if http_request.timeout_seconds == 0 {
+        DEFAULT_TIMEOUT_SECONDS
+    } else {
+        http_request.timeout_seconds as u64
+    });
+
+    let description = redact(
+        &format!("{} {}", request.method, request.url),
+        &context.secrets,
+    );
+
+    println!("{} {}", "Sending HTTP request:".green(), description);
+
+    let (status, body) = send_request(&request, timeout, http_request.max_retries, context).await?;
+
+    if !status.is_success() {
+        return Err(redact(
+            &format!(
+                "{} responded with {}: {}",
+                description,
+                status,
+                body.chars().take(500).collect::<String>()
+            ),
+            &context.secrets,
+        ));
+    }
+
+    let response = extract_outputs(node, &http_request, &body)?;
+
+    variable_definitions.extend(response.clone());
+
+    Ok(AtomicExecutionLog {
+        prompt: description,
+        response,
+        node_info: node.node_info.clone(),
+        cache_hit: false,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generated_types::{
+        value::ValueType, GraphNodeInfo, NodeContent, StringList, UserSettings,
+    };
+
+    use bollard::{Docker, API_DEFAULT_VERSION};
+    use r2d2::Pool;
+    use r2d2_sqlite::SqliteConnectionManager;
+    use serde_json::json;
+    use warp::http::StatusCode as StubStatus;
+    use warp::Filter;
+
+    use std::net::SocketAddr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    // A local server standing in for the API, counting how often each failing route was hit
+    struct StubServer {
+        address: SocketAddr,
+        flaky_hits: Arc<AtomicUsize>,
+        broken_hits: Arc<AtomicUsize>,
+        missing_hits: Arc<AtomicUsize>,
+    }
+
+    fn start_stub_server() -> StubServer {
+        let flaky_hits = Arc::new(AtomicUsize::new(0));
+        let broken_hits = Arc::new(AtomicUsize::new(0));
+        let missing_hits = Arc::new(AtomicUsize::new(0));
+
+        // Sends back what it received along with a list to pick fields out of
+        let echo = warp::path!("echo" / String)
+            .and(warp::method())
+            .and(warp::header::optional::<String>("x-token"))
+            .and(warp::body::bytes())
+            .map(
+                |id: String,
+                 method: warp::http::Method,
+                 token: Option<String>,
+                 body: bytes::Bytes| {
+                    warp::reply::with_status(
+                        json!({
+                            "id": id,
+                            "method": method.as_str(),
+                            "token": token.unwrap_or_default(),
+                            "body": String::from_utf8_lossy(&body),
+                            "items": [{ "name": "first" }, { "name": "second" }]
+                        })
+                        .to_string(),
+                        StubStatus::OK,
+                    )
+                },
+            );
+
+        // Rate limits the first request only
+        let flaky = {
+            let hits = flaky_hits.clone();
+            warp::path!("flaky").map(move || {
+                if hits.fetch_add(1, Ordering::SeqCst) == 0 {
+                    warp::reply::with_status("slow down".to_string(), StubStatus::TOO_MANY_REQUESTS)
+                } else {
+                    warp::reply::with_status("{\"ok\":true}".to_string(), StubStatus::OK)
+                }
+            })
+        };
+
+        let broken = {
+            let hits = broken_hits.clone();
+            warp::path!("broken").map(move || {
+                hits.fetch_add(1, Ordering::SeqCst);
+                warp::reply::with_status("down".to_string(), StubStatus::SERVICE_UNAVAILABLE)
+            })
+        };
+
+        let missing = {
+            let hits = missing_hits.clone();
+            warp::path!("missing").map(move || {
+                hits.fetch_add(1, Ordering::SeqCst);
+                warp::reply::with_status("not here".to_string(), StubStatus::NOT_FOUND)
+            })
+        };
+
+        let (address, server) =
+            warp::serve(echo.or(flaky).or(broken).or(missing)).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        StubServer {
+            address,
+            flaky_hits,
+            broken_hits,
+            missing_hits,
+        }
+    }
+
+    fn test_context() -> ExecutionContext {
+        ExecutionContext {
+            docker_id: None,
+            docker: Docker::connect_with_http("localhost:2375", 4, API_DEFAULT_VERSION).unwrap(),
+            user_settings: Arc::new(UserSettings::default()),
+            pool: Arc::new(Pool::new(SqliteConnectionManager::memory()).unwrap()),
+            secrets: Arc::new(HashMap::from([(
+                "TOKEN".to_string(),
+                "hunter2".to_string(),
+            )])),
+            user_email: None,
+            conversation: None,
+            tool_depth: 0,
+            process_id: "".to_string(),
+        }
+    }
+
+    fn http_node(http_request: HttpRequest, output_variables: &[&str]) -> Node {
+        Node {
+            node_info: Some(GraphNodeInfo {
+                id: "http-node".to_string(),
+                name: "http node".to_string(),
+                description: "".to_string(),
+            }),
+            output_variables: output_variables
+                .iter()
+                .map(|output_variable| output_variable.to_string())
+                .collect(),
+            node_content: Some(NodeContent {
+                node_content: Some(NodeContentEnum::HttpRequest(http_request)),
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn string_value(value: &str) -> Value {
+        Value {
+            value_type: Some(ValueType::StringValue(value.to_string())),
+        }
+    }
+
+    #[tokio::test]
+    async fn fills_in_the_request_and_extracts_the_outputs() {
+        let stub = start_stub_server();
+
+        let node = http_node(
+            HttpRequest {
+                method: "{{verb}}".to_string(),
+                url: format!("http://{}/echo/{{{{id}}}}", stub.address),
+                headers: HashMap::from([(
+                    "x-token".to_string(),
+                    "Bearer {{secrets.TOKEN}}".to_string(),
+                )]),
+                body: "{\"query\": \"{{query}}\"}".to_string(),
+                extractors: HashMap::from([
+                    ("id".to_string(), "$.id".to_string()),
+                    ("method".to_string(), "$.method".to_string()),
+                    ("token".to_string(), "$.token".to_string()),
+                    ("sent_body".to_string(), "$.body".to_string()),
+                    ("names".to_string(), "$.items[*].name".to_string()),
+                ]),
+                ..Default::default()
+            },
+            &["id", "method", "token", "sent_body", "names"],
+        );
+
+        let mut variable_definitions = HashMap::from([
+            ("verb".to_string(), string_value("put")),
+            ("id".to_string(), string_value("42")),
+            ("query".to_string(), string_value("weather")),
+        ]);
+
+        let log = handle_http_request(&node, &mut variable_definitions, &test_context())
+            .await
+            .unwrap();
+
+        assert_eq!(log.response["id"], string_value("42"));
+        assert_eq!(log.response["method"], string_value("PUT"));
+        assert_eq!(log.response["token"], string_value("Bearer hunter2"));
+        assert_eq!(
+            log.response["sent_body"],
+            string_value("{\"query\": \"weather\"}")
+        );
+        assert_eq!(
+            log.response["names"],
+            Value {
+                value_type: Some(ValueType::StringList(StringList {
+                    values: vec!["first".to_string(), "second".to_string()],
+                })),
+            }
+        );
+        assert_eq!(variable_definitions["names"], log.response["names"]);
+        assert!(!log.prompt.contains("hunter2"));
+    }
+
+    #[tokio::test]
+    async fn retries_after_being_rate_limited() {
+        let stub = start_stub_server();
+
+        let node = http_node(
+            HttpRequest {
+                url: format!("http://{}/flaky", stub.address),
+                max_retries: 2,
+                ..Default::default()
+            },
+            &["result"],
+        );
+
+        let log = handle_http_request(&node, &mut HashMap::new(), &test_context())
+            .await
+            .unwrap();
+
+        assert_eq!(stub.flaky_hits.load(Ordering::SeqCst), 2);
+        // Without an extractor the output gets the whole body
+        assert_eq!(log.response["result"], string_value("{\"ok\":true}"));
+    }
+
+    #[tokio::test]
+    async fn fails_once_server_errors_run_out_of_retries() {
+        let stub = start_stub_server();
+
+        let node = http_node(
+            HttpRequest {
+                url: format!("http://{}/broken", stub.address),
+                max_retries: 1,
+                ..Default::default()
+            },
+            &["result"],
+        );
+
+        let err = handle_http_request(&node, &mut HashMap::new(), &test_context())
+            .await
+            .unwrap_err();
+
+        assert_eq!(stub.broken_hits.load(Ordering::SeqCst), 2);
+        assert!(err.contains("503"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn fails_on_other_errors_without_retrying() {
+        let stub = start_stub_server();
+
+        let node = http_node(
+            HttpRequest {
+                url: format!("http://{}/missing?key={{{{secrets.TOKEN}}}}", stub.address),
+                max_retries: 3,
+                ..Default::default()
+            },
+            &["result"],
+        );
+
+        let err = handle_http_request(&node, &mut HashMap::new(), &test_context())
+            .await
+            .unwrap_err();
+
+        assert_eq!(stub.missing_hits.load(Ordering::SeqCst), 1);
+        assert!(err.contains("404"), "{}", err);
+        assert!(err.contains("not here"), "{}", err);
+        assert!(!err.contains("hunter2"), "{}", err);
+    }
+
+    #[test]
+    fn only_rate_limits_and_server_errors_are_retried() {
+        assert!(should_retry(StatusCode::TOO_MANY_REQUESTS));
+        assert!(should_retry(StatusCode::BAD_GATEWAY));
+        assert!(!should_retry(StatusCode::NOT_FOUND));
+        assert!(!should_retry(StatusCode::OK));
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_maximum() {
+        assert_eq!(backoff_delay(1), Duration::from_millis(500));
+        assert_eq!(backoff_delay(2), Duration::from_millis(1000));
+        assert_eq!(backoff_delay(3), Duration::from_millis(2000));
+        assert_eq!(backoff_delay(30), Duration::from_millis(MAX_BACKOFF_MILLIS));
+    }
+}
@@ -0,0 +1,254 @@
+use serde_json::Value;
+
+// One step of a path: a field of an object, an item of an array (negative indexes count from the end) or every child
+#[derive(Debug, PartialEq)]
+enum Segment {
+    Field(String),
+    Index(i64),
+    Wildcard,
+}
+
+// Parses the subset of JSONPath that is useful for picking fields out of an API response: $.field, $['field'], $.list[0], $.list[-1], $.list[*].field and $.object.*
+fn parse(path: &str) -> Result<Vec<Segment>, String> {
+    let path = path.trim();
+
+    let rest = match path.strip_prefix('$') {
+        Some(rest) => rest,
+        None => return Err(format!("\"{}\" has to start with $", path)),
+    };
+
+    let characters: Vec<char> = rest.chars().collect();
+    let mut segments = Vec::new();
+    let mut position = 0;
+
+    while position < characters.len() {
+        match characters[position] {
+            '.' => {
+                position += 1;
+                let start = position;
+                while position < characters.len()
+                    && characters[position] != '.'
+                    && characters[position] != '['
+                {
+                    position += 1;
+                }
+
+                let field: String = characters[start..position].iter().collect();
+                match field.as_str() {
+                    "" => return Err(format!("\"{}\" has an empty field name", path)),
+                    "*" => segments.push(Segment::Wildcard),
+                    _ => segments.push(Segment::Field(field)),
+                }
+            }
+            '[' => {
+                let end = match characters[position..].iter().position(|c| *c == ']') {
+                    Some(offset) => position + offset,
+                    None => return Err(format!("\"{}\" is missing a closing ]", path)),
+                };
+
+                let inside: String = characters[position + 1..end].iter().collect();
+                let inside = inside.trim();
+
+                if inside == "*" {
+                    segments.push(Segment::Wildcard);
+                } else if let Some(quoted) = inside
+                    .strip_prefix('\'')
+                    .and_then(|inside| inside.strip_suffix('\''))
+                    .or_else(|| {
+                        inside
+                            .strip_prefix('"')
+                            .and_then(|inside| inside.strip_suffix('"'))
+                    })
+                {
+                    segments.push(Segment::Field(quoted.to_string()));
+                } else {
+                    match inside.parse::<i64>() {
+                        Ok(index) => segments.push(Segment::Index(index)),
+                        Err(_) => {
+                            return Err(format!("\"{}\" has an invalid index: {}", path, inside))
+                        }
+                    }
+                }
+
+                position = end + 1;
+            }
+            other => {
+                return Err(format!(
+                    "\"{}\" has an unexpected character: {}",
+                    path, other
+                ))
+            }
+        }
+    }
+
+    Ok(segments)
+}
+
+// Returns every value in the document that the path points at
+pub fn select<'a>(document: &'a Value, path: &str) -> Result<Vec<&'a Value>, String> {
+    let mut current: Vec<&Value> = vec![document];
+
+    for segment in parse(path)? {
+        let mut next = Vec::new();
+
+        for value in current {
+            match (&segment, value) {
+                (Segment::Field(field), Value::Object(fields)) => {
+                    if let Some(child) = fields.get(field) {
+                        next.push(child);
+                    }
+                }
+                (Segment::Index(index), Value::Array(items)) => {
+                    let position = if *index < 0 {
+                        items.len() as i64 + index
+                    } else {
+                        *index
+                    };
+
+                    if position >= 0 {
+                        if let Some(child) = items.get(position as usize) {
+                            next.push(child);
+                        }
+                    }
+                }
+                (Segment::Wildcard, Value::Array(items)) => next.extend(items.iter()),
+                (Segment::Wildcard, Value::Object(fields)) => next.extend(fields.values()),
+                _ => {}
+            }
+        }
+
+        current = next;
+    }
+
+    Ok(current)
+}
+
+// Like select, but gives back a single value: the match itself when there is exactly one and a list of the matches otherwise. Paths that match nothing are an error.
+pub fn select_value(document: &Value, path: &str) -> Result<Value, String> {
+    let matches = select(document, path)?;
+
+    match matches.len() {
+        0 => Err(format!("Nothing in the response matches {}", path)),
+        1 => Ok(matches[0].clone()),
+        _ => Ok(Value::Array(matches.into_iter().cloned().collect())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn document() -> Value {
+        json!({
+            "name": "skynet",
+            "dotted.field": 1,
+            "items": [
+                { "name": "first", "tags": ["a", "b"] },
+                { "name": "second", "tags": [] },
+                { "name": "third" }
+            ],
+            "counts": { "open": 2, "closed": 5 }
+        })
+    }
+
+    #[test]
+    fn parses_every_kind_of_segment() {
+        assert_eq!(
+            parse("$.items[0]['name']").unwrap(),
+            vec![
+                Segment::Field("items".to_string()),
+                Segment::Index(0),
+                Segment::Field("name".to_string()),
+            ]
+        );
+        assert_eq!(
+            parse("$.items[-1].*[*]").unwrap(),
+            vec![
+                Segment::Field("items".to_string()),
+                Segment::Index(-1),
+                Segment::Wildcard,
+                Segment::Wildcard,
+            ]
+        );
+        assert_eq!(
+            parse("$[\"dotted.field\"]").unwrap(),
+            vec![Segment::Field("dotted.field".to_string())]
+        );
+        assert_eq!(parse(" $ ").unwrap(), vec![]);
+    }
+
+    #[test]
+    fn rejects_malformed_paths() {
+        assert!(parse("items[0]").is_err());
+        assert!(parse("$.").is_err());
+        assert!(parse("$..name").is_err());
+        assert!(parse("$.items[0").is_err());
+        assert!(parse("$.items[first]").is_err());
+        assert!(parse("$items").is_err());
+    }
+
+    #[test]
+    fn selects_fields_and_indexes() {
+        let document = document();
+
+        assert_eq!(select(&document, "$.name").unwrap(), vec![&json!("skynet")]);
+        assert_eq!(
+            select(&document, "$.items[1].name").unwrap(),
+            vec![&json!("second")]
+        );
+        assert_eq!(
+            select(&document, "$.items[-1]['name']").unwrap(),
+            vec![&json!("third")]
+        );
+        assert_eq!(
+            select(&document, "$['dotted.field']").unwrap(),
+            vec![&json!(1)]
+        );
+        assert_eq!(select(&document, "$").unwrap(), vec![&document]);
+    }
+
+    #[test]
+    fn selects_every_child_with_wildcards() {
+        let document = document();
+
+        assert_eq!(
+            select(&document, "$.items[*].name").unwrap(),
+            vec![&json!("first"), &json!("second"), &json!("third")]
+        );
+        assert_eq!(
+            select(&document, "$.items[*].tags[*]").unwrap(),
+            vec![&json!("a"), &json!("b")]
+        );
+
+        let mut counts = select(&document, "$.counts.*").unwrap();
+        counts.sort_by_key(|count| count.as_i64());
+        assert_eq!(counts, vec![&json!(2), &json!(5)]);
+    }
+
+    #[test]
+    fn skips_what_isnt_there() {
+        let document = document();
+
+        assert!(select(&document, "$.missing").unwrap().is_empty());
+        assert!(select(&document, "$.items[3]").unwrap().is_empty());
+        assert!(select(&document, "$.items[-4]").unwrap().is_empty());
+        assert!(select(&document, "$.name[0]").unwrap().is_empty());
+        assert!(select(&document, "$.counts[0]").unwrap().is_empty());
+    }
+
+    #[test]
+    fn select_value_unwraps_single_matches() {
+        let document = document();
+
+        assert_eq!(
+            select_value(&document, "$.items[0].name").unwrap(),
+            json!("first")
+        );
+        assert_eq!(
+            select_value(&document, "$.items[*].name").unwrap(),
+            json!(["first", "second", "third"])
+        );
+        assert!(select_value(&document, "$.missing").is_err());
+    }
+}
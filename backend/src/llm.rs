@@ -0,0 +1,417 @@
+use async_openai::config::OpenAIConfig;
+use async_openai::error::OpenAIError;
+use async_openai::types::{
+    ChatCompletionMessageToolCall, ChatCompletionRequestAssistantMessageArgs,
+    ChatCompletionRequestMessage, ChatCompletionRequestSystemMessageArgs,
+    ChatCompletionRequestToolMessageArgs, ChatCompletionRequestUserMessageArgs, ChatCompletionTool,
+    ChatCompletionToolType, CreateChatCompletionRequestArgs, FunctionObject, ResponseFormat,
+    ResponseFormatJsonSchema,
+};
+use async_openai::Client;
+
+use crate::generated_types::UserSettings;
+use crate::output_schema::OutputSchema;
+
+use colored::*;
+use thiserror::Error;
+
+use std::time::Duration;
+
+// How many times a request is sent before a transient error is given back to the caller
+const MAX_ATTEMPTS: u32 = 5;
+// How many times the model is asked to fix a reply that isn't a JSON object (or doesn't match the schema)
+const MAX_REPAIR_ATTEMPTS: u32 = 2;
+const INITIAL_BACKOFF_MILLIS: u64 = 500;
+const MAX_BACKOFF_MILLIS: u64 = 20_000;
+// Used when the user hasn't picked a model
+const DEFAULT_LANGUAGE_MODEL: &str = "gpt-4-1106-preview";
+
+#[derive(Error, Debug)]
+pub enum ModelError {
+    #[error("The model provider is rate limiting requests: {0}")]
+    RateLimited(String),
+    #[error("The model provider is unavailable: {0}")]
+    Unavailable(String),
+    #[error("Unable to reach the model provider: {0}")]
+    Network(String),
+    #[error("The model provider rejected the request: {0}")]
+    Api(String),
+    #[error("The model replied without any content")]
+    EmptyResponse,
+    #[error("The model did not reply with a JSON object: {0}")]
+    MalformedJson(String),
+    #[error("The reply of the model does not match the output variables: {0}")]
+    SchemaMismatch(String),
+}
+
+impl ModelError {
+    // Transient errors are worth retrying after waiting a little while
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            ModelError::RateLimited(_) | ModelError::Unavailable(_) | ModelError::Network(_)
+        )
+    }
+}
+
+impl From<OpenAIError> for ModelError {
+    fn from(err: OpenAIError) -> Self {
+        match err {
+            OpenAIError::Reqwest(err) => ModelError::Network(err.to_string()),
+            OpenAIError::ApiError(api_error) => {
+                let error_type = api_error.r#type.clone().unwrap_or_default();
+
+                if error_type == "requests"
+                    || error_type == "tokens"
+                    || api_error.message.to_lowercase().contains("rate limit")
+                {
+                    ModelError::RateLimited(api_error.message)
+                } else if error_type == "server_error" || error_type == "engine_overloaded" {
+                    ModelError::Unavailable(api_error.message)
+                } else {
+                    ModelError::Api(api_error.message)
+                }
+            }
+            OpenAIError::JSONDeserialize(err) => ModelError::Unavailable(err.to_string()),
+            other => ModelError::Api(other.to_string()),
+        }
+    }
+}
+
+pub fn system_message(text: &str) -> Result<ChatCompletionRequestMessage, ModelError> {
+    Ok(ChatCompletionRequestSystemMessageArgs::default()
+        .content(text)
+        .build()?
+        .into())
+}
+
+pub fn user_message(text: &str) -> Result<ChatCompletionRequestMessage, ModelError> {
+    Ok(ChatCompletionRequestUserMessageArgs::default()
+        .content(text)
+        .build()?
+        .into())
+}
+
+pub fn assistant_message(text: &str) -> Result<ChatCompletionRequestMessage, ModelError> {
+    Ok(ChatCompletionRequestAssistantMessageArgs::default()
+        .content(text)
+        .build()?
+        .into())
+}
+
+// The messages for a single prompt. The system message is left out when the node doesn't have a system prompt.
+pub fn prompt_messages(
+    system_prompt: &str,
+    prompt_text: &str,
+) -> Result<Vec<ChatCompletionRequestMessage>, ModelError> {
+    let mut messages = Vec::new();
+
+    if !system_prompt.trim().is_empty() {
+        messages.push(system_message(system_prompt)?);
+    }
+
+    messages.push(user_message(prompt_text)?);
+
+    Ok(messages)
+}
+
+// Sends the messages to the model in JSON mode and returns the reply as both the raw text and the parsed object. Transient errors are retried with exponential backoff and a reply that isn't a JSON object (or doesn't match the schema, when one is given) is sent back to the model with a request to fix it.
+pub async fn request_json_object(
+    client: &Client<OpenAIConfig>,
+    model: &str,
+    messages: Vec<ChatCompletionRequestMessage>,
+    schema: Option<&OutputSchema>,
+) -> Result<(String, serde_json::Map<String, serde_json::Value>), ModelError> {
+    let mut messages = messages;
+    let mut attempt: u32 = 0;
+    let mut repairs: u32 = 0;
+
+    loop {
+        attempt += 1;
+
+        let reply = match send_request(client, model, messages.clone(), schema).await {
+            Ok(reply) => reply,
+            Err(err) if wait_before_retry(&err, attempt).await => continue,
+            Err(err) => return Err(err),
+        };
+
+        let parsed = parse_json_object(&reply).and_then(|object| match schema {
+            Some(schema) => schema
+                .validate(&object)
+                .map(|_| object)
+                .map_err(ModelError::SchemaMismatch),
+            None => Ok(object),
+        });
+
+        match parsed {
+            Ok(object) => {
+                return Ok((reply, object));
+            }
+            Err(err) if repairs < MAX_REPAIR_ATTEMPTS => {
+                repairs += 1;
+                println!(
+                    "{} {}",
+                    "Asking the model to repair its reply:".yellow(),
+                    err
+                );
+
+                messages.push(assistant_message(&reply)?);
+                messages.push(user_message(&repair_prompt(&err))?);
+            }
+            Err(err) => {
+                println!("{} {}", "Giving up on the model reply:".red(), err);
+                return Err(err);
+            }
+        }
+    }
+}
+
+// Sends the messages along with the tools the model may call. Returns the tool calls the model wants to make, which is empty once it is ready to answer. The answer itself is asked for afterwards with request_json_object so that it goes through the same checks as any other reply.
+pub async fn request_tool_calls(
+    client: &Client<OpenAIConfig>,
+    model: &str,
+    messages: Vec<ChatCompletionRequestMessage>,
+    tools: Vec<ChatCompletionTool>,
+) -> Result<Vec<ChatCompletionMessageToolCall>, ModelError> {
+    let mut attempt: u32 = 0;
+
+    loop {
+        attempt += 1;
+
+        let request = CreateChatCompletionRequestArgs::default()
+            .model(model)
+            .messages(messages.clone())
+            .tools(tools.clone())
+            .build()?;
+
+        match client.chat().create(request).await {
+            Ok(response) => {
+                return Ok(response
+                    .choices
+                    .first()
+                    .and_then(|choice| choice.message.tool_calls.clone())
+                    .unwrap_or_default());
+            }
+            Err(err) => {
+                let err = ModelError::from(err);
+                if !wait_before_retry(&err, attempt).await {
+                    return Err(err);
+                }
+            }
+        }
+    }
+}
+
+pub fn tool(name: &str, description: &str, parameters: serde_json::Value) -> ChatCompletionTool {
+    ChatCompletionTool {
+        r#type: ChatCompletionToolType::Function,
+        function: FunctionObject {
+            name: name.to_string(),
+            description: Some(description.to_string()),
+            parameters: Some(parameters),
+            strict: None,
+        },
+    }
+}
+
+// The assistant message that has to come before the results of the tool calls it asked for
+pub fn tool_calls_message(
+    tool_calls: &[ChatCompletionMessageToolCall],
+) -> Result<ChatCompletionRequestMessage, ModelError> {
+    Ok(ChatCompletionRequestAssistantMessageArgs::default()
+        .tool_calls(tool_calls.to_vec())
+        .build()?
+        .into())
+}
+
+pub fn tool_result_message(
+    tool_call_id: &str,
+    result: &str,
+) -> Result<ChatCompletionRequestMessage, ModelError> {
+    Ok(ChatCompletionRequestToolMessageArgs::default()
+        .tool_call_id(tool_call_id)
+        .content(result)
+        .build()?
+        .into())
+}
+
+// Waits before the next attempt when the error is transient. Returns false once the error should be given back to the caller.
+async fn wait_before_retry(err: &ModelError, attempt: u32) -> bool {
+    if !err.is_transient() || attempt >= MAX_ATTEMPTS {
+        println!("{} {}", "Model request failed:".red(), err);
+        return false;
+    }
+
+    let delay = backoff_delay(attempt);
+    println!(
+        "{} {} (attempt {} of {}, retrying in {:?})",
+        "Model request failed:".yellow(),
+        err,
+        attempt,
+        MAX_ATTEMPTS,
+        delay
+    );
+    tokio::time::sleep(delay).await;
+
+    true
+}
+
+pub fn language_model(settings: &UserSettings) -> String {
+    let model = settings.language_model.trim();
+
+    if model.is_empty() {
+        DEFAULT_LANGUAGE_MODEL.to_string()
+    } else {
+        model.to_string()
+    }
+}
+
+// Models that can be given a JSON schema as their response format. Every other model is only asked for a JSON object; the schema is still checked once the reply comes back.
+pub fn supports_structured_outputs(model: &str) -> bool {
+    let structured_output_models = [
+        "gpt-4o-mini",
+        "gpt-4o-2024-08-06",
+        "gpt-4o-2024-11-20",
+        "gpt-4.1",
+        "gpt-5",
+        "o3",
+        "o4-mini",
+    ];
+
+    model == "gpt-4o"
+        || model == "o1"
+        || structured_output_models
+            .iter()
+            .any(|prefix| model.starts_with(prefix))
+}
+
+// Any model can be used so long as it supports response_format
+fn response_format(model: &str, schema: Option<&OutputSchema>) -> ResponseFormat {
+    match schema {
+        Some(schema) if supports_structured_outputs(model) => ResponseFormat::JsonSchema {
+            json_schema: ResponseFormatJsonSchema {
+                description: None,
+                name: "node_output".to_string(),
+                schema: Some(schema.to_json_schema()),
+                strict: Some(schema.is_strict()),
+            },
+        },
+        _ => ResponseFormat::JsonObject,
+    }
+}
+
+async fn send_request(
+    client: &Client<OpenAIConfig>,
+    model: &str,
+    messages: Vec<ChatCompletionRequestMessage>,
+    schema: Option<&OutputSchema>,
+) -> Result<String, ModelError> {
+    let request = CreateChatCompletionRequestArgs::default()
+        .model(model)
+        .messages(messages)
+        .response_format(response_format(model, schema))
+        .build()?;
+
+    let response = client.chat().create(request).await?;
+
+    match response
+        .choices
+        .first()
+        .and_then(|choice| choice.message.content.clone())
+    {
+        Some(content) => Ok(content),
+        None => Err(ModelError::EmptyResponse),
+    }
+}
+
+fn parse_json_object(
+    reply: &str,
+) -> Result<serde_json::Map<String, serde_json::Value>, ModelError> {
+    match serde_json::from_str::<serde_json::Value>(reply) {
+        Ok(serde_json::Value::Object(object)) => Ok(object),
+        Ok(_) => Err(ModelError::MalformedJson(
+            "the reply is valid JSON but it is not an object".to_string(),
+        )),
+        Err(err) => Err(ModelError::MalformedJson(err.to_string())),
+    }
+}
+
+fn repair_prompt(err: &ModelError) -> String {
+    format!(
+        "Your last reply could not be used ({}). Please reply again with only a single valid JSON object containing the requested fields.",
+        err
+    )
+}
+
+fn backoff_delay(attempt: u32) -> Duration {
+    let millis = INITIAL_BACKOFF_MILLIS.saturating_mul(1 << (attempt - 1).min(16));
+    Duration::from_millis(millis.min(MAX_BACKOFF_MILLIS))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generated_types::{Node, VariableDeclaration, VariableTypes};
+
+    fn declared_node() -> Node {
+        Node {
+            output_variables: vec!["summary".to_string()],
+            output_variable_declarations: vec![VariableDeclaration {
+                name: "summary".to_string(),
+                variable_type: VariableTypes::StringVariable as i32,
+                ..Default::default()
+            }],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn the_default_model_is_used_until_one_is_picked() {
+        assert_eq!(
+            language_model(&UserSettings::default()),
+            DEFAULT_LANGUAGE_MODEL
+        );
+
+        let settings = UserSettings {
+            language_model: " gpt-4o-mini ".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(language_model(&settings), "gpt-4o-mini");
+    }
+
+    #[test]
+    fn structured_output_models_are_recognised() {
+        assert!(supports_structured_outputs("gpt-4o"));
+        assert!(supports_structured_outputs("gpt-4o-mini-2024-07-18"));
+        assert!(supports_structured_outputs("gpt-4.1-nano"));
+        assert!(!supports_structured_outputs("gpt-4o-2024-05-13"));
+        assert!(!supports_structured_outputs(DEFAULT_LANGUAGE_MODEL));
+    }
+
+    #[test]
+    fn structured_output_models_get_the_schema() {
+        let schema = OutputSchema::for_node(&declared_node());
+
+        match response_format("gpt-4o-mini", Some(&schema)) {
+            ResponseFormat::JsonSchema { json_schema } => {
+                assert_eq!(json_schema.schema, Some(schema.to_json_schema()));
+                assert_eq!(json_schema.strict, Some(true));
+            }
+            other => panic!("expected a JSON schema, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn other_models_are_only_asked_for_a_json_object() {
+        let schema = OutputSchema::for_node(&declared_node());
+
+        assert!(matches!(
+            response_format(DEFAULT_LANGUAGE_MODEL, Some(&schema)),
+            ResponseFormat::JsonObject
+        ));
+        assert!(matches!(
+            response_format("gpt-4o-mini", None),
+            ResponseFormat::JsonObject
+        ));
+    }
+}
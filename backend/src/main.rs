@@ -4,13 +4,36 @@ use r2d2_sqlite::SqliteConnectionManager;
 use std::env;
 use std::sync::Arc;
 use tokio::sync::{ mpsc, Mutex };
+mod access;
+mod audit;
+mod bundles;
+mod conversation;
+mod debugger;
 mod env_vars_checker;
 mod graph;
+mod http_request;
+mod json_path;
+mod llm;
+mod memory;
+mod migrations;
 mod mongo;
+mod mongo_import;
+mod node_deletion;
+mod node_history;
+mod node_schema;
 mod openai;
+mod output_schema;
 mod receive_send;
+mod references;
+mod retrieval;
+mod secrets;
 mod settings;
 mod sqlite_helper_functions;
+mod teams;
+mod templating;
+mod tools;
+mod transform;
+mod variables;
 mod websocket;
 
 #[allow(non_snake_case)]
@@ -20,6 +43,7 @@ pub mod generated_types {
 
 use crate::receive_send::start_message_sending_loop;
 use crate::websocket::start_websocket_server;
+use clap::{Parser, Subcommand};
 use colored::*;
 use reqwest;
 
@@ -29,10 +53,90 @@ use crate::generated_types::Identity;
 
 static SERVER_IDENTITY: OnceCell<Identity> = OnceCell::new();
 
+// Without a subcommand the server is started
+#[derive(Parser)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Commands>,
+}
+
+#[derive(Subcommand)]
+enum Commands {
+    #[command(
+        about = "Imports the nodes of a legacy MongoDB dump (like ../mongodump/admin/nodes.bson) into SQLite"
+    )]
+    ImportMongoDump {
+        #[arg(help = "The nodes.bson file of the dump")]
+        path: String,
+        #[arg(long, help = "Only report what would be imported")]
+        dry_run: bool,
+    },
+    #[command(about = "Writes every entry of the audit log to a file as JSON lines")]
+    ExportAuditLog {
+        #[arg(help = "The file to write")]
+        path: String,
+    },
+}
+
+// Commands work on the same database as the server, brought up to date the same way
+fn command_pool() -> Arc<Pool<SqliteConnectionManager>> {
+    if let Err(err) = sqlite_helper_functions::setup_sqlite_db() {
+        panic!("Oh goodness... {:?}", err);
+    }
+
+    let sqlite_location = env::var("SQLITE_FILE_LOCATION").unwrap();
+    match Pool::new(SqliteConnectionManager::file(sqlite_location)) {
+        Ok(p) => Arc::new(p),
+        Err(err) => {
+            panic!("Failed to create SQLite connection pool: {:?}", err);
+        }
+    }
+}
+
+fn run_audit_export(path: &str) {
+    match audit::export_audit_log(command_pool(), path) {
+        Ok(count) => println!("{} {} entries to {}", "Exported".green(), count, path),
+        Err(err) => eprintln!("{} {}", "Export failed:".red(), err),
+    }
+}
+
+fn run_mongo_import(path: &str, dry_run: bool) {
+    match mongo_import::import_mongo_dump(command_pool(), path, dry_run) {
+        Ok(report) => {
+            let verb = if dry_run { "Would import" } else { "Imported" };
+            println!("{} {} node(s): {:?}", verb.green(), report.imported.len(), report.imported);
+            println!(
+                "{} {} node(s): {:?}",
+                "Already imported".yellow(),
+                report.already_imported.len(),
+                report.already_imported
+            );
+            for failure in &report.failed {
+                println!("{} {}", "Unable to import".red(), failure);
+            }
+        }
+        Err(err) => {
+            eprintln!("{} {}", "Import failed:".red(), err);
+        }
+    }
+}
+
 #[tokio::main]
 async fn main() {
     env_logger::init();
 
+    match Cli::parse().command {
+        Some(Commands::ImportMongoDump { path, dry_run }) => {
+            run_mongo_import(&path, dry_run);
+            return;
+        }
+        Some(Commands::ExportAuditLog { path }) => {
+            run_audit_export(&path);
+            return;
+        }
+        None => {}
+    }
+
     let res = reqwest::get("http://api.ipify.org").await.unwrap().text().await.unwrap();
     println!("My external IP address is: {}", res);
 
@@ -0,0 +1,134 @@
+use crate::generated_types::{
+    node_content::NodeContent as NodeContentEnum, AtomicExecutionLog, Node, Value,
+};
+use crate::graph::ExecutionContext;
+use crate::secrets::redact_value;
+use crate::sqlite_helper_functions::{read_memory, write_memory};
+
+use colored::*;
+
+use std::collections::HashMap;
+
+const GLOBAL_NAMESPACE: &str = "global";
+// Keeps the namespaces of processes apart from the ones named by users
+const PROCESS_NAMESPACE_PREFIX: &str = "process:";
+
+// Memories are kept per process unless the node names a namespace ("global" being the one every process can share)
+fn memory_namespace(node: &Node, context: &ExecutionContext) -> Result<String, String> {
+    let namespace = match node
+        .node_content
+        .clone()
+        .and_then(|node_content| node_content.node_content)
+    {
+        Some(NodeContentEnum::Memory(memory)) => memory.namespace,
+        _ => "".to_string(),
+    };
+
+    if !namespace.trim().is_empty() {
+        Ok(namespace.trim().to_string())
+    } else if !context.process_id.is_empty() {
+        Ok(format!(
+            "{}{}",
+            PROCESS_NAMESPACE_PREFIX, context.process_id
+        ))
+    } else {
+        Err(format!(
+            "Memory node {} has no namespace and isn't running in a known process. Give it a namespace (like \"{}\") or send the process_id with the execution.",
+            node.node_info.clone().unwrap_or_default().name,
+            GLOBAL_NAMESPACE
+        ))
+    }
+}
+
+fn memory_owner(context: &ExecutionContext) -> Result<&str, String> {
+    match &context.user_email {
+        Some(email) => Ok(email),
+        None => Err("Memory can only be used once the user is known".to_string()),
+    }
+}
+
+// Looks up every output variable of the node in memory. Keys that were never written are left undefined.
+pub fn handle_memory_read(
+    node: &Node,
+    variable_definitions: &mut HashMap<String, Value>,
+    context: &ExecutionContext,
+) -> Result<AtomicExecutionLog, String> {
+    let email = memory_owner(context)?;
+    let namespace = memory_namespace(node, context)?;
+
+    let mut response = HashMap::new();
+
+    for key in &node.output_variables {
+        match read_memory(context.pool.clone(), email, &namespace, key) {
+            Ok(Some(value)) => {
+                response.insert(key.clone(), value);
+            }
+            Ok(None) => {
+                println!(
+                    "{} {}/{}",
+                    "Nothing in memory for:".yellow(),
+                    namespace,
+                    key
+                );
+            }
+            Err(err) => {
+                return Err(format!("Unable to read {} from memory: {:?}", key, err));
+            }
+        }
+    }
+
+    variable_definitions.extend(response.clone());
+
+    Ok(AtomicExecutionLog {
+        prompt: format!(
+            "Read from memory ({}): {}",
+            namespace,
+            node.output_variables.join(", ")
+        ),
+        response,
+        node_info: node.node_info.clone(),
+        cache_hit: false,
+    })
+}
+
+// Stores every input variable of the node in memory. Secret values are never written, they are stored redacted.
+pub fn handle_memory_write(
+    node: &Node,
+    variable_definitions: &HashMap<String, Value>,
+    context: &ExecutionContext,
+) -> Result<AtomicExecutionLog, String> {
+    let email = memory_owner(context)?;
+    let namespace = memory_namespace(node, context)?;
+
+    let mut written = HashMap::new();
+
+    for key in &node.input_variables {
+        let value = match variable_definitions.get(key) {
+            Some(value) => redact_value(value, &context.secrets),
+            None => {
+                println!(
+                    "{} {}",
+                    "Not writing undefined variable to memory:".yellow(),
+                    key
+                );
+                continue;
+            }
+        };
+
+        write_memory(context.pool.clone(), email, &namespace, key, &value)
+            .map_err(|err| format!("Unable to write {} to memory: {:?}", key, err))?;
+
+        written.insert(key.clone(), value);
+    }
+
+    Ok(AtomicExecutionLog {
+        prompt: format!(
+            "Wrote to memory ({}): {}",
+            namespace,
+            written.keys().cloned().collect::<Vec<String>>().join(", ")
+        ),
+        response: written,
+        node_info: node.node_info.clone(),
+        cache_hit: false,
+    })
+}
@@ -0,0 +1,428 @@
+use crate::node_schema::decode_node;
+use crate::sqlite_helper_functions::{
+    create_audit_log_table, create_documents_tables, create_executions_table,
+    create_memories_table, create_node_search_tables, create_node_shares_table,
+    create_node_versions_table, create_nodes_table, create_pass_table, create_response_cache_table,
+    create_secrets_table, create_teams_tables, index_node, key_response_cache_by_owner,
+};
+
+use colored::*;
+use rusqlite::{params, Connection};
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub struct Migration {
+    pub version: u32,
+    pub description: &'static str,
+    pub apply: fn(&Connection) -> rusqlite::Result<()>,
+}
+
+// Migrations are only ever appended. Once a migration has been released it must not change (fix mistakes with a new migration) since databases that already ran it won't run it again.
+pub const MAIN_MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "Create the original tables",
+        apply: create_original_tables,
+    },
+    Migration {
+        version: 2,
+        description: "Keep a history of node versions",
+        apply: create_node_versions_table,
+    },
+    Migration {
+        version: 3,
+        description: "Add tombstones for soft deleted nodes",
+        apply: add_node_tombstones,
+    },
+    Migration {
+        version: 4,
+        description: "Store node types as integers in a node_type column",
+        apply: store_node_types_as_integers,
+    },
+    Migration {
+        version: 5,
+        description: "Stamp stored nodes with their schema version and quarantine unreadable ones",
+        apply: add_node_schema_versions,
+    },
+    Migration {
+        version: 6,
+        description: "Index the tags, variables, names and descriptions of nodes for searching",
+        apply: add_node_search,
+    },
+    Migration {
+        version: 7,
+        description: "Record who owns each node and who else can see it",
+        apply: add_node_ownership,
+    },
+    Migration {
+        version: 8,
+        description: "Add teams and the node libraries they share",
+        apply: add_teams,
+    },
+    Migration {
+        version: 9,
+        description: "Keep an append-only audit log",
+        apply: create_audit_log_table,
+    },
+    Migration {
+        version: 10,
+        description: "Record who ran each execution and which team it belongs to",
+        apply: add_execution_owners,
+    },
+    Migration {
+        version: 11,
+        description: "Keep a response cache per user",
+        apply: drop_shared_response_cache,
+    },
+];
+
+pub const AUTH_MIGRATIONS: &[Migration] = &[Migration {
+    version: 1,
+    description: "Create the pass and secrets tables",
+    apply: create_auth_tables,
+}];
+
+// Databases from before migrations existed already have some of these tables, which is why they are all created if they don't exist
+fn create_original_tables(conn: &Connection) -> rusqlite::Result<()> {
+    create_nodes_table(conn)?;
+    create_executions_table(conn)?;
+    create_response_cache_table(conn)?;
+    create_memories_table(conn)?;
+    create_documents_tables(conn)
+}
+
+fn create_auth_tables(conn: &Connection) -> rusqlite::Result<()> {
+    create_pass_table(conn)?;
+    create_secrets_table(conn)
+}
+
+fn column_exists(conn: &Connection, table: &str, column: &str) -> rusqlite::Result<bool> {
+    conn.query_row(
+        "SELECT COUNT(*) FROM pragma_table_info(?1) WHERE name = ?2",
+        params![table, column],
+        |row| row.get::<_, i64>(0).map(|count| count > 0),
+    )
+}
+
+// Databases that were set up right before migrations existed got the column when the nodes table was created
+fn add_node_tombstones(conn: &Connection) -> rusqlite::Result<()> {
+    if !column_exists(conn, "nodes", "deleted_at")? {
+        conn.execute("ALTER TABLE nodes ADD COLUMN deleted_at INTEGER", [])?;
+    }
+    Ok(())
+}
+
+// The type_name column has always held the NodeTypes number, stored as text because of the column type. SQLite can't change the type of a column so the table is rebuilt.
+fn store_node_types_as_integers(conn: &Connection) -> rusqlite::Result<()> {
+    conn.execute_batch(
+        "CREATE TABLE nodes_migrated (
+            id TEXT PRIMARY KEY,
+            name TEXT,
+            node_type INTEGER NOT NULL DEFAULT 0,
+            serialized_node BLOB,
+            deleted_at INTEGER
+        );
+        INSERT INTO nodes_migrated (id, name, node_type, serialized_node, deleted_at)
+            SELECT id, name, COALESCE(CAST(type_name AS INTEGER), 0), serialized_node, deleted_at
+            FROM nodes;
+        DROP TABLE nodes;
+        ALTER TABLE nodes_migrated RENAME TO nodes;",
+    )
+}
+
+// Blobs stored before the stamp existed are schema version 0
+fn add_node_schema_versions(conn: &Connection) -> rusqlite::Result<()> {
+    conn.execute_batch(
+        "ALTER TABLE nodes ADD COLUMN schema_version INTEGER NOT NULL DEFAULT 0;
+        ALTER TABLE node_versions ADD COLUMN schema_version INTEGER NOT NULL DEFAULT 0;
+        CREATE TABLE quarantined_nodes (
+            node_id TEXT NOT NULL,
+            name TEXT,
+            schema_version INTEGER NOT NULL,
+            serialized_node BLOB,
+            error TEXT NOT NULL,
+            quarantined_at INTEGER NOT NULL
+        );",
+    )
+}
+
+// The nodes that are already stored are indexed right away. Nodes that can't be read are left for the quarantine. Indexing also keeps node_shares up to date, so that table has to be there already even though ownership only comes with migration 7.
+fn add_node_search(conn: &Connection) -> rusqlite::Result<()> {
+    create_node_search_tables(conn)?;
+    create_node_shares_table(conn)?;
+
+    let rows: Vec<(Vec<u8>, u32)> = {
+        let mut stmt = conn.prepare("SELECT serialized_node, schema_version FROM nodes")?;
+        let row_iter = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        row_iter.collect::<rusqlite::Result<_>>()?
+    };
+
+    for (blob_data, schema_version) in rows {
+        if let Ok(node) = decode_node(blob_data.as_slice(), schema_version) {
+            index_node(conn, &node)?;
+        }
+    }
+
+    Ok(())
+}
+
+// Nodes saved before this have no owner, which leaves them open to everyone like they were
+fn add_node_ownership(conn: &Connection) -> rusqlite::Result<()> {
+    conn.execute_batch(
+        "ALTER TABLE nodes ADD COLUMN owner_email TEXT NOT NULL DEFAULT '';
+        ALTER TABLE nodes ADD COLUMN visibility INTEGER NOT NULL DEFAULT 0;
+        CREATE INDEX IF NOT EXISTS nodes_by_owner ON nodes (owner_email);",
+    )?;
+
+    create_node_shares_table(conn)
+}
+
+fn add_teams(conn: &Connection) -> rusqlite::Result<()> {
+    conn.execute_batch(
+        "ALTER TABLE nodes ADD COLUMN team_id TEXT NOT NULL DEFAULT '';
+        CREATE INDEX IF NOT EXISTS nodes_by_team ON nodes (team_id);",
+    )?;
+
+    create_teams_tables(conn)
+}
+
+// Executions stored before this have no owner. Nobody can tell who ran them anymore, so they aren't shown to anyone.
+fn add_execution_owners(conn: &Connection) -> rusqlite::Result<()> {
+    conn.execute_batch(
+        "ALTER TABLE executions ADD COLUMN executor_email TEXT NOT NULL DEFAULT '';
+        ALTER TABLE executions ADD COLUMN team_id TEXT NOT NULL DEFAULT '';
+        CREATE INDEX IF NOT EXISTS executions_by_executor ON executions (executor_email);
+        CREATE INDEX IF NOT EXISTS executions_by_team ON executions (team_id);",
+    )
+}
+
+// Nobody can tell whose cached responses these were, so the shared cache is thrown away rather than handed to everyone
+fn drop_shared_response_cache(conn: &Connection) -> rusqlite::Result<()> {
+    key_response_cache_by_owner(conn)
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+fn schema_version(conn: &Connection) -> rusqlite::Result<u32> {
+    conn.query_row(
+        "SELECT COALESCE(MAX(version), 0) FROM schema_migrations",
+        [],
+        |row| row.get(0),
+    )
+}
+
+// A database without any tables of its own is new, there is nothing worth backing up
+fn has_data(conn: &Connection) -> rusqlite::Result<bool> {
+    conn.query_row(
+        "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name != 'schema_migrations'",
+        [],
+        |row| row.get::<_, i64>(0).map(|count| count > 0),
+    )
+}
+
+// Copies the database next to itself. VACUUM INTO writes a consistent copy even while other connections are open.
+fn backup(conn: &Connection, location: &str, version: u32) -> Result<String, String> {
+    let backup_location = format!("{}.v{}-{}.backup", location, version, now());
+
+    conn.execute("VACUUM INTO ?1", params![backup_location])
+        .map_err(|err| format!("Unable to back up {}: {:?}", location, err))?;
+
+    Ok(backup_location)
+}
+
+// Brings the database up to the latest migration. The database is backed up first whenever there is anything to migrate, and every migration runs in its own transaction so that a failing one leaves the database at the previous version.
+pub fn run_migrations(
+    conn: &mut Connection,
+    location: &str,
+    migrations: &[Migration],
+) -> Result<(), String> {
+    if migrations
+        .windows(2)
+        .any(|pair| pair[0].version >= pair[1].version)
+    {
+        return Err("Migrations have to be listed in order of their version".to_string());
+    }
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS schema_migrations (
+            version INTEGER PRIMARY KEY,
+            description TEXT NOT NULL,
+            applied_at INTEGER NOT NULL
+        )",
+        [],
+    )
+    .map_err(|err| format!("Unable to create the schema_migrations table: {:?}", err))?;
+
+    let current_version = schema_version(conn)
+        .map_err(|err| format!("Unable to read the schema version: {:?}", err))?;
+    let latest_version = migrations
+        .last()
+        .map(|migration| migration.version)
+        .unwrap_or(0);
+
+    if current_version > latest_version {
+        return Err(format!(
+            "{} is at schema version {} but this build only knows up to version {}",
+            location, current_version, latest_version
+        ));
+    }
+
+    let pending: Vec<&Migration> = migrations
+        .iter()
+        .filter(|migration| migration.version > current_version)
+        .collect();
+
+    if pending.is_empty() {
+        println!("{} is at schema version {}", location, current_version);
+        return Ok(());
+    }
+
+    if has_data(conn).map_err(|err| format!("Unable to inspect {}: {:?}", location, err))? {
+        let backup_location = backup(conn, location, current_version)?;
+        println!(
+            "{} {}",
+            "Backed up the database to".green(),
+            backup_location
+        );
+    }
+
+    for migration in pending {
+        println!(
+            "{} {}: {}",
+            "Applying migration".yellow(),
+            migration.version,
+            migration.description
+        );
+
+        let transaction = conn
+            .transaction()
+            .map_err(|err| format!("Unable to start a transaction: {:?}", err))?;
+
+        (migration.apply)(&transaction)
+            .and_then(|_| {
+                transaction.execute(
+                    "INSERT INTO schema_migrations (version, description, applied_at) VALUES (?1, ?2, ?3)",
+                    params![migration.version, migration.description, now()],
+                )
+            })
+            .map_err(|err| format!("Migration {} failed: {:?}", migration.version, err))?;
+
+        transaction.commit().map_err(|err| {
+            format!(
+                "Unable to commit migration {}: {:?}",
+                migration.version, err
+            )
+        })?;
+    }
+
+    println!("{} is now at schema version {}", location, latest_version);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generated_types::{GraphNodeInfo, Node, NodeTypes};
+
+    use prost::Message;
+
+    #[test]
+    fn baseline_databases_are_migrated_to_the_latest_version() {
+        let mut conn = Connection::open_in_memory().unwrap();
+
+        // The nodes table as it was before migrations existed, with the type in a text column and no tombstones
+        conn.execute_batch(
+            "CREATE TABLE nodes (
+                id TEXT PRIMARY KEY,
+                name TEXT,
+                type_name TEXT,
+                serialized_node BLOB
+            );",
+        )
+        .unwrap();
+
+        let node = Node {
+            node_info: Some(GraphNodeInfo {
+                id: "loop-node".to_string(),
+                name: "Loop".to_string(),
+                description: String::new(),
+            }),
+            node_type: NodeTypes::Loop as i32,
+            ..Default::default()
+        };
+        conn.execute(
+            "INSERT INTO nodes (id, name, type_name, serialized_node) VALUES (?1, ?2, ?3, ?4)",
+            params![
+                "loop-node",
+                "Loop",
+                (NodeTypes::Loop as i32).to_string(),
+                node.encode_to_vec()
+            ],
+        )
+        .unwrap();
+
+        // The database is backed up next to this location before migrating
+        let location = std::env::temp_dir()
+            .join(format!("migrations-test-{}.db", std::process::id()))
+            .to_string_lossy()
+            .to_string();
+        let result = run_migrations(&mut conn, &location, MAIN_MIGRATIONS);
+
+        if let Ok(entries) = std::fs::read_dir(std::env::temp_dir()) {
+            for entry in entries.flatten() {
+                if entry.path().to_string_lossy().starts_with(&location) {
+                    let _ = std::fs::remove_file(entry.path());
+                }
+            }
+        }
+
+        result.unwrap();
+
+        assert_eq!(
+            schema_version(&conn).unwrap(),
+            MAIN_MIGRATIONS.last().unwrap().version
+        );
+
+        assert!(!column_exists(&conn, "nodes", "type_name").unwrap());
+        assert!(column_exists(&conn, "nodes", "deleted_at").unwrap());
+        assert!(column_exists(&conn, "nodes", "schema_version").unwrap());
+
+        let (node_type, stored_type, deleted_at, blob_data): (
+            i64,
+            String,
+            Option<i64>,
+            Vec<u8>,
+        ) = conn
+            .query_row(
+                "SELECT node_type, typeof(node_type), deleted_at, serialized_node FROM nodes WHERE id = ?1",
+                params!["loop-node"],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+            )
+            .unwrap();
+
+        assert_eq!(node_type, NodeTypes::Loop as i64);
+        assert_eq!(stored_type, "integer");
+        assert_eq!(deleted_at, None);
+        assert_eq!(Node::decode(blob_data.as_slice()).unwrap(), node);
+    }
+
+    #[test]
+    fn migrated_databases_are_left_alone() {
+        let mut conn = Connection::open_in_memory().unwrap();
+
+        run_migrations(&mut conn, ":memory:", MAIN_MIGRATIONS).unwrap();
+        run_migrations(&mut conn, ":memory:", MAIN_MIGRATIONS).unwrap();
+
+        let applied: i64 = conn
+            .query_row("SELECT COUNT(*) FROM schema_migrations", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(applied, MAIN_MIGRATIONS.len() as i64);
+    }
+}
@@ -0,0 +1,293 @@
+use crate::audit::{record_audit, AuditActor};
+use crate::generated_types::{
+    node_content::NodeContent as NodeContentEnum, AuditEvents, Command, GraphNodeInfo, Node,
+    NodeContent, NodeTypes, Prompt, ReferencePolicies, VerbTypes,
+};
+use crate::graph::{validate_nodes_in_loop, validate_nodes_in_process};
+use crate::node_history::record_version;
+use crate::references::{attach_references, references_for};
+use crate::sqlite_helper_functions::{fetch_node, insert_node};
+
+use bson::oid::ObjectId;
+use colored::*;
+use r2d2::Pool;
+use r2d2_sqlite::SqliteConnectionManager;
+use serde::Deserialize;
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, ErrorKind, Read};
+use std::sync::Arc;
+
+const IMPORT_AUTHOR: &str = "mongodump import";
+
+// The document shapes of the Mongo-backed design (see the commented out types in domain.rs)
+#[derive(Deserialize)]
+struct LegacyPrompt {
+    prompt: String,
+    system: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct LegacyProcess {
+    #[serde(default)]
+    topological_order: Vec<String>,
+    #[serde(default)]
+    is_loop: bool,
+}
+
+#[derive(Deserialize)]
+struct LegacyCommand {
+    command: String,
+}
+
+#[derive(Deserialize)]
+enum LegacyNodeContent {
+    Prompt(LegacyPrompt),
+    Process(LegacyProcess),
+    Command(LegacyCommand),
+    // Its options point at nodes by ObjectId, which current conditionals have no equivalent for. The contents are never read, they only have to deserialize so the node can be reported as skipped.
+    #[allow(dead_code)]
+    Conditional(bson::Document),
+}
+
+#[derive(Deserialize)]
+struct LegacyNode {
+    _id: Option<ObjectId>,
+    name: String,
+    node_content: LegacyNodeContent,
+    #[serde(default)]
+    description: String,
+    #[serde(default)]
+    input_variables: Vec<String>,
+    #[serde(default)]
+    output_variables: Vec<String>,
+}
+
+#[derive(Default)]
+pub struct MongoImportReport {
+    pub imported: Vec<String>,
+    pub already_imported: Vec<String>,
+    pub failed: Vec<String>,
+}
+
+fn read_documents(path: &str) -> Result<Vec<bson::Document>, String> {
+    let file = File::open(path).map_err(|err| format!("Unable to open {}: {}", path, err))?;
+    let mut reader = BufReader::new(file);
+    let mut documents = Vec::new();
+
+    loop {
+        // A dump is just one document after the other, so the end of the file is where the next document would start
+        let mut length_bytes = [0u8; 4];
+        match reader.read_exact(&mut length_bytes) {
+            Ok(_) => {}
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => break,
+            Err(err) => return Err(format!("Unable to read {}: {}", path, err)),
+        }
+
+        let document = bson::Document::from_reader(length_bytes.chain(&mut reader))
+            .map_err(|err| format!("{} is not a valid BSON dump: {}", path, err))?;
+
+        documents.push(document);
+    }
+
+    Ok(documents)
+}
+
+// The ObjectId is kept as the id so that importing the same dump again finds the nodes it imported before. Documents without one are given one when they are read.
+fn node_info(legacy_node: &LegacyNode) -> GraphNodeInfo {
+    GraphNodeInfo {
+        id: legacy_node
+            ._id
+            .map(|object_id| object_id.to_hex())
+            .unwrap_or_default(),
+        name: legacy_node.name.clone(),
+        description: legacy_node.description.clone(),
+    }
+}
+
+fn convert_node(legacy_node: &LegacyNode, node_type: NodeTypes, content: NodeContentEnum) -> Node {
+    Node {
+        node_info: Some(node_info(legacy_node)),
+        input_variables: legacy_node.input_variables.clone(),
+        output_variables: legacy_node.output_variables.clone(),
+        node_type: node_type as i32,
+        node_content: Some(NodeContent {
+            node_content: Some(content),
+        }),
+        version: 1,
+        ..Default::default()
+    }
+}
+
+// Old processes list their nodes by ObjectId (or by name in the oldest documents)
+fn find_member<'a>(converted: &'a HashMap<String, Node>, entry: &str) -> Option<&'a Node> {
+    converted.get(entry).or_else(|| {
+        converted
+            .values()
+            .find(|node| node.node_info.clone().unwrap_or_default().name == entry)
+    })
+}
+
+fn convert_process(
+    legacy_node: &LegacyNode,
+    legacy_process: &LegacyProcess,
+    converted: &HashMap<String, Node>,
+) -> Result<Option<Node>, String> {
+    let mut members = Vec::new();
+
+    for entry in &legacy_process.topological_order {
+        match find_member(converted, entry) {
+            Some(member) => members.push(member.clone()),
+            // The member may be a process that hasn't been converted yet
+            None => return Ok(None),
+        }
+    }
+
+    let mut node = if legacy_process.is_loop {
+        validate_nodes_in_loop(members.clone(), node_info(legacy_node))
+    } else {
+        validate_nodes_in_process(members.clone(), node_info(legacy_node), None)
+    }?;
+
+    node.version = 1;
+    attach_references(
+        &mut node,
+        references_for(&members, ReferencePolicies::Pinned),
+    );
+
+    Ok(Some(node))
+}
+
+// Reads the nodes of a mongodump nodes.bson file and saves them to SQLite. Nodes that were imported before are left alone, and with dry_run nothing is saved at all.
+pub fn import_mongo_dump(
+    pool: Arc<Pool<SqliteConnectionManager>>,
+    path: &str,
+    dry_run: bool,
+) -> Result<MongoImportReport, String> {
+    let mut report = MongoImportReport::default();
+    let mut legacy_nodes = Vec::new();
+
+    for document in read_documents(path)? {
+        let name = document.get_str("name").unwrap_or("unnamed").to_string();
+
+        match bson::from_document::<LegacyNode>(document) {
+            Ok(mut legacy_node) => {
+                legacy_node._id.get_or_insert_with(ObjectId::new);
+                legacy_nodes.push(legacy_node)
+            }
+            Err(err) => report
+                .failed
+                .push(format!("{}: unknown document shape ({})", name, err)),
+        }
+    }
+
+    let mut converted: HashMap<String, Node> = HashMap::new();
+    let mut processes = Vec::new();
+
+    for legacy_node in &legacy_nodes {
+        let node = match &legacy_node.node_content {
+            LegacyNodeContent::Prompt(prompt) => convert_node(
+                legacy_node,
+                NodeTypes::Prompt,
+                NodeContentEnum::Prompt(Prompt {
+                    prompt: prompt.prompt.clone(),
+                    system: prompt.system.clone().unwrap_or_default(),
+                    tool_node_ids: Vec::new(),
+                }),
+            ),
+            LegacyNodeContent::Command(command) => convert_node(
+                legacy_node,
+                NodeTypes::Command,
+                NodeContentEnum::Command(Command {
+                    command: command.command.clone(),
+                    ..Default::default()
+                }),
+            ),
+            LegacyNodeContent::Process(process) => {
+                processes.push((legacy_node, process));
+                continue;
+            }
+            LegacyNodeContent::Conditional(_) => {
+                report.failed.push(format!(
+                    "{}: conditionals can't be converted",
+                    legacy_node.name
+                ));
+                continue;
+            }
+        };
+
+        converted.insert(node_info(legacy_node).id, node);
+    }
+
+    // Processes can contain other processes, so they are converted over several passes until none of them can be
+    loop {
+        let mut remaining = Vec::new();
+
+        for (legacy_node, process) in processes.iter() {
+            match convert_process(legacy_node, process, &converted) {
+                Ok(Some(node)) => {
+                    converted.insert(node_info(legacy_node).id, node);
+                }
+                Ok(None) => remaining.push((*legacy_node, *process)),
+                Err(err) => report.failed.push(format!("{}: {}", legacy_node.name, err)),
+            }
+        }
+
+        if remaining.len() == processes.len() {
+            for (legacy_node, _) in remaining {
+                report.failed.push(format!(
+                    "{}: not every node of the process is in the dump",
+                    legacy_node.name
+                ));
+            }
+            break;
+        }
+
+        processes = remaining;
+    }
+
+    for (id, node) in converted {
+        let name = node.node_info.clone().unwrap_or_default().name;
+
+        match fetch_node(pool.clone(), &id) {
+            Ok(Some(_)) => {
+                report.already_imported.push(name);
+                continue;
+            }
+            Ok(None) => {}
+            Err(err) => return Err(format!("Unable to look up node {}: {:?}", id, err)),
+        }
+
+        if !dry_run {
+            insert_node(pool.clone(), node.clone())
+                .map_err(|err| format!("Unable to save {}: {:?}", name, err))?;
+
+            if let Err(err) = record_version(
+                pool.clone(),
+                &node,
+                IMPORT_AUTHOR,
+                "Imported from the MongoDB dump",
+            ) {
+                println!("{} {}", "Unable to record node version:".red(), err);
+            }
+
+            // There is no session or client behind an import from the command line
+            record_audit(
+                &pool,
+                &AuditActor {
+                    user_email: IMPORT_AUTHOR.to_string(),
+                    ..Default::default()
+                },
+                VerbTypes::Create,
+                AuditEvents::AuditNodeImported,
+                &id,
+                "Imported from the MongoDB dump",
+            );
+        }
+
+        report.imported.push(name);
+    }
+
+    Ok(report)
+}
@@ -0,0 +1,103 @@
+use crate::access::{can_edit, can_view, editable_node, Viewer};
+use crate::generated_types::{Node, NodeDeletion};
+use crate::graph::collect_nested_nodes;
+use crate::sqlite_helper_functions::{delete_nodes, fetch_all_nodes};
+
+use r2d2::Pool;
+use r2d2_sqlite::SqliteConnectionManager;
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+fn node_id(node: &Node) -> String {
+    node.node_info.clone().unwrap_or_default().id
+}
+
+// The saved nodes (other than the given ones) whose process or loop contains one of the given nodes, however deeply nested
+fn dependents<'a>(saved_nodes: &'a [Node], node_ids: &HashSet<String>) -> Vec<&'a Node> {
+    saved_nodes
+        .iter()
+        .filter(|saved_node| !node_ids.contains(&node_id(saved_node)))
+        .filter(|saved_node| {
+            let mut nested_nodes = HashMap::new();
+            collect_nested_nodes(saved_node, &mut nested_nodes);
+            nested_nodes.remove(&node_id(saved_node));
+
+            nested_nodes.keys().any(|id| node_ids.contains(id))
+        })
+        .collect()
+}
+
+// Names the nodes. Like editable_node, nodes the user can't see are treated as if they weren't there, so they are only counted.
+fn describe(nodes: &[&Node], viewer: &Viewer) -> String {
+    let mut descriptions: Vec<String> = nodes
+        .iter()
+        .filter(|node| can_view(node, viewer))
+        .map(|node| node.node_info.clone().unwrap_or_default().name)
+        .collect();
+
+    let hidden = nodes.len() - descriptions.len();
+    if hidden > 0 {
+        descriptions.push(format!("{} node(s) you can't see", hidden));
+    }
+
+    descriptions.join(", ")
+}
+
+// Deletes the node, and with cascade every saved node that (indirectly) contains it. Returns the ids of the deleted nodes. The user has to be allowed to change every one of them.
+pub fn delete_node(
+    pool: Arc<Pool<SqliteConnectionManager>>,
+    deletion: &NodeDeletion,
+    viewer: &Viewer,
+) -> Result<Vec<String>, String> {
+    if editable_node(pool.clone(), &deletion.node_id, viewer)?.is_none() {
+        return Err(format!("There is no node {} to delete", deletion.node_id));
+    }
+
+    let saved_nodes =
+        fetch_all_nodes(pool.clone()).map_err(|err| format!("Unable to fetch nodes: {:?}", err))?;
+
+    let mut deleted_node_ids = vec![deletion.node_id.clone()];
+    let mut to_delete: HashSet<String> = deleted_node_ids.iter().cloned().collect();
+
+    loop {
+        let found = dependents(&saved_nodes, &to_delete);
+
+        if found.is_empty() {
+            break;
+        }
+
+        if !deletion.cascade {
+            return Err(format!(
+                "Node {} is still used by {}",
+                deletion.node_id,
+                describe(&found, viewer)
+            ));
+        }
+
+        let not_owned: Vec<&Node> = found
+            .iter()
+            .copied()
+            .filter(|dependent| !can_edit(dependent, viewer))
+            .collect();
+
+        if !not_owned.is_empty() {
+            return Err(format!(
+                "Deleting node {} would also delete {}, which you aren't allowed to change",
+                deletion.node_id,
+                describe(&not_owned, viewer)
+            ));
+        }
+
+        for dependent in found {
+            let dependent_id = node_id(dependent);
+            to_delete.insert(dependent_id.clone());
+            deleted_node_ids.push(dependent_id);
+        }
+    }
+
+    delete_nodes(pool, &deleted_node_ids, deletion.soft_delete)
+        .map_err(|err| format!("Unable to delete node {}: {:?}", deletion.node_id, err))?;
+
+    Ok(deleted_node_ids)
+}
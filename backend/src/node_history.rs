@@ -0,0 +1,170 @@
+use crate::access::keep_placement;
+use crate::generated_types::{FieldChange, Node, NodeDiff, NodeVersion};
+use crate::references::next_node_version;
+use crate::sqlite_helper_functions::{
+    fetch_node, fetch_node_version, insert_node, insert_node_version,
+};
+
+use r2d2::Pool;
+use r2d2_sqlite::SqliteConnectionManager;
+
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Appends the node, as it was just saved, to its history
+pub fn record_version(
+    pool: Arc<Pool<SqliteConnectionManager>>,
+    node: &Node,
+    author: &str,
+    change_note: &str,
+) -> Result<(), String> {
+    let node_id = node.node_info.clone().unwrap_or_default().id;
+
+    let created_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs() as i64)
+        .unwrap_or(0);
+
+    let node_version = NodeVersion {
+        node_id: node_id.clone(),
+        version: node.version,
+        author: author.to_string(),
+        created_at,
+        change_note: change_note.to_string(),
+        node: Some(node.clone()),
+    };
+
+    insert_node_version(pool, &node_version).map_err(|err| {
+        format!(
+            "Unable to store version {} of node {}: {:?}",
+            node.version, node_id, err
+        )
+    })
+}
+
+fn stored_version(
+    pool: Arc<Pool<SqliteConnectionManager>>,
+    node_id: &str,
+    version: u32,
+) -> Result<Node, String> {
+    match fetch_node_version(pool, node_id, version) {
+        Ok(Some(NodeVersion {
+            node: Some(node), ..
+        })) => Ok(node),
+        Ok(_) => Err(format!("Node {} has no version {}", node_id, version)),
+        Err(err) => Err(format!(
+            "Unable to fetch version {} of node {}: {:?}",
+            version, node_id, err
+        )),
+    }
+}
+
+fn compare(
+    path: &str,
+    before: &serde_json::Value,
+    after: &serde_json::Value,
+    changes: &mut Vec<FieldChange>,
+) {
+    match (before, after) {
+        (serde_json::Value::Object(before_fields), serde_json::Value::Object(after_fields)) => {
+            let mut keys: Vec<&String> = before_fields.keys().chain(after_fields.keys()).collect();
+            keys.sort();
+            keys.dedup();
+
+            for key in keys {
+                let child_path = if path.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{}.{}", path, key)
+                };
+
+                compare(
+                    &child_path,
+                    before_fields.get(key).unwrap_or(&serde_json::Value::Null),
+                    after_fields.get(key).unwrap_or(&serde_json::Value::Null),
+                    changes,
+                );
+            }
+        }
+        (serde_json::Value::Array(before_items), serde_json::Value::Array(after_items)) => {
+            for index in 0..before_items.len().max(after_items.len()) {
+                compare(
+                    &format!("{}[{}]", path, index),
+                    before_items.get(index).unwrap_or(&serde_json::Value::Null),
+                    after_items.get(index).unwrap_or(&serde_json::Value::Null),
+                    changes,
+                );
+            }
+        }
+        _ if before != after => changes.push(FieldChange {
+            path: path.to_string(),
+            before: before.to_string(),
+            after: after.to_string(),
+        }),
+        _ => {}
+    }
+}
+
+// Lists every field that differs between the two nodes. The version itself is left out since it always differs.
+pub fn diff_nodes(before: &Node, after: &Node) -> Result<Vec<FieldChange>, String> {
+    let mut before = before.clone();
+    let mut after = after.clone();
+    before.version = 0;
+    after.version = 0;
+
+    let before_json = serde_json::to_value(&before).map_err(|err| err.to_string())?;
+    let after_json = serde_json::to_value(&after).map_err(|err| err.to_string())?;
+
+    let mut changes = Vec::new();
+    compare("", &before_json, &after_json, &mut changes);
+
+    Ok(changes)
+}
+
+pub fn diff_versions(
+    pool: Arc<Pool<SqliteConnectionManager>>,
+    node_id: &str,
+    from_version: u32,
+    to_version: u32,
+) -> Result<NodeDiff, String> {
+    let before = stored_version(pool.clone(), node_id, from_version)?;
+    let after = stored_version(pool, node_id, to_version)?;
+
+    Ok(NodeDiff {
+        node_id: node_id.to_string(),
+        from_version,
+        to_version,
+        changes: diff_nodes(&before, &after)?,
+    })
+}
+
+// Saves the old version of the node as a new version, so the history shows the rollback rather than losing the versions after it
+pub fn rollback_node(
+    pool: Arc<Pool<SqliteConnectionManager>>,
+    node_id: &str,
+    version: u32,
+    author: &str,
+    change_note: &str,
+) -> Result<Node, String> {
+    let mut node = stored_version(pool.clone(), node_id, version)?;
+
+    // Rolling back brings back what the node did, not who it belonged to at the time
+    if let Ok(Some(saved_node)) = fetch_node(pool.clone(), node_id) {
+        keep_placement(&mut node, &saved_node);
+    }
+
+    node.version = next_node_version(pool.clone(), node_id);
+
+    let change_note = if change_note.trim().is_empty() {
+        format!("Rolled back to version {}", version)
+    } else {
+        change_note.to_string()
+    };
+
+    insert_node(pool.clone(), node.clone())
+        .map_err(|err| format!("Unable to save node {}: {:?}", node_id, err))?;
+
+    record_version(pool, &node, author, &change_note)?;
+
+    Ok(node)
+}
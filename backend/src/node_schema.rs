@@ -0,0 +1,67 @@
+use crate::generated_types::{node_content::NodeContent as NodeContentEnum, Node};
+
+use prost::Message;
+
+// Stamped next to every node blob that is stored. Whenever stored nodes need to change before this build can use them, bump it and add an upgrade from the previous version.
+pub const NODE_SCHEMA_VERSION: u32 = 1;
+
+struct Upgrade {
+    // The upgrade turns a node of this schema version into one of the next version
+    from: u32,
+    apply: fn(&mut Node),
+}
+
+const UPGRADES: &[Upgrade] = &[Upgrade {
+    from: 0,
+    apply: number_unversioned_nodes,
+}];
+
+// Nodes stored before versions existed count as their first version, the nodes of their processes and loops included
+fn number_unversioned_nodes(node: &mut Node) {
+    if node.version == 0 {
+        node.version = 1;
+    }
+
+    let nested_process = match node
+        .node_content
+        .as_mut()
+        .and_then(|node_content| node_content.node_content.as_mut())
+    {
+        Some(NodeContentEnum::Process(process)) => Some(process),
+        Some(NodeContentEnum::Loop(contained_loop)) => contained_loop.process.as_mut(),
+        _ => None,
+    };
+
+    if let Some(process) = nested_process {
+        process.nodes.iter_mut().for_each(number_unversioned_nodes);
+    }
+}
+
+// Brings a node of an older schema version up to the current one
+pub fn upgrade_node(node: &mut Node, schema_version: u32) -> Result<(), String> {
+    if schema_version > NODE_SCHEMA_VERSION {
+        return Err(format!(
+            "The node was stored with schema version {} but this build only knows up to version {}",
+            schema_version, NODE_SCHEMA_VERSION
+        ));
+    }
+
+    for upgrade in UPGRADES
+        .iter()
+        .filter(|upgrade| upgrade.from >= schema_version)
+    {
+        (upgrade.apply)(node);
+    }
+
+    Ok(())
+}
+
+// Decodes a stored node and upgrades it to the current schema version
+pub fn decode_node(blob: &[u8], schema_version: u32) -> Result<Node, String> {
+    let mut node =
+        Node::decode(blob).map_err(|err| format!("The node can't be decoded: {}", err))?;
+
+    upgrade_node(&mut node, schema_version)?;
+
+    Ok(node)
+}
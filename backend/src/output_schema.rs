@@ -0,0 +1,381 @@
+use crate::generated_types::{Node, VariableDeclaration, VariableTypes};
+use crate::variables::{declared_type, input_declaration, type_name};
+
+use serde_json::json;
+
+// The field the model can use to report problems coming up with a response. It is always allowed but never required to hold anything.
+const ERROR_FIELD: &str = "error";
+// The field a conditional uses to ask for another iteration of its loop instead of deciding on its outputs
+const ACCUMULATOR_FIELD: &str = "accumulator";
+
+// Describes the JSON object a node expects back from the language model. It is built from the output_variables of the node along with any declarations given for them. Outputs without a declaration can hold any JSON value, like they always could.
+pub struct OutputSchema {
+    outputs: Vec<(String, Option<VariableDeclaration>)>,
+    accumulator: bool,
+}
+
+impl OutputSchema {
+    pub fn for_node(node: &Node) -> OutputSchema {
+        let outputs = node
+            .output_variables
+            .iter()
+            .map(|output_variable| {
+                let declaration = node
+                    .output_variable_declarations
+                    .iter()
+                    .find(|declaration| &declaration.name == output_variable)
+                    .cloned();
+
+                (output_variable.clone(), declaration)
+            })
+            .collect();
+
+        OutputSchema {
+            outputs,
+            accumulator: false,
+        }
+    }
+
+    // The outputs of a conditional only have to be there when it doesn't fill in the accumulator
+    pub fn for_conditional(node: &Node) -> OutputSchema {
+        OutputSchema {
+            accumulator: true,
+            ..OutputSchema::for_node(node)
+        }
+    }
+
+    // For replies that aren't turned into output variables, like the commands a command node asks for
+    pub fn for_strings(names: &[&str]) -> OutputSchema {
+        let outputs = names
+            .iter()
+            .map(|name| {
+                let declaration = VariableDeclaration {
+                    name: name.to_string(),
+                    variable_type: VariableTypes::StringVariable as i32,
+                    ..Default::default()
+                };
+
+                (name.to_string(), Some(declaration))
+            })
+            .collect();
+
+        OutputSchema {
+            outputs,
+            accumulator: false,
+        }
+    }
+
+    // Strict structured outputs need every property to have a type and every object to list its properties, which undeclared outputs and free form JSON objects don't
+    pub fn is_strict(&self) -> bool {
+        !self.accumulator
+            && self
+                .outputs
+                .iter()
+                .all(|(_, declaration)| match declaration {
+                    Some(declaration) => {
+                        declared_type(declaration) != VariableTypes::ObjectVariable
+                    }
+                    None => false,
+                })
+    }
+
+    // Every property is required and no others are allowed so that the schema can also be used for strict structured outputs. Schemas with an accumulator are never strict, their outputs are optional.
+    pub fn to_json_schema(&self) -> serde_json::Value {
+        let mut properties = serde_json::Map::new();
+        let mut required = Vec::new();
+
+        for (name, declaration) in &self.outputs {
+            let property = match declaration {
+                Some(declaration) => {
+                    let mut property = type_schema(declared_type(declaration));
+
+                    if !declaration.description.is_empty() {
+                        property["description"] = json!(declaration.description);
+                    }
+
+                    property
+                }
+                None => json!({}),
+            };
+
+            properties.insert(name.clone(), property);
+
+            if !self.accumulator {
+                required.push(name.clone());
+            }
+        }
+
+        if self.accumulator {
+            properties.insert(
+                ACCUMULATOR_FIELD.to_string(),
+                json!({
+                    "type": "string",
+                    "description": "Explains what information is still needed when there is not enough to decide on the other fields."
+                }),
+            );
+        }
+
+        properties.insert(
+            ERROR_FIELD.to_string(),
+            json!({
+                "type": "string",
+                "description": "Leave this empty unless there was a problem coming up with a response."
+            }),
+        );
+        required.push(ERROR_FIELD.to_string());
+
+        json!({
+            "type": "object",
+            "properties": properties,
+            "required": required,
+            "additionalProperties": false
+        })
+    }
+
+    // Checks that every output variable is in the response (unless a conditional filled in its accumulator instead), and that the declared ones hold the declared type. Extra fields are left alone.
+    pub fn validate(
+        &self,
+        object: &serde_json::Map<String, serde_json::Value>,
+    ) -> Result<(), String> {
+        let mut problems = Vec::new();
+
+        let accumulating = self.accumulator
+            && object
+                .get(ACCUMULATOR_FIELD)
+                .and_then(|accumulator| accumulator.as_str())
+                .is_some_and(|accumulator| !accumulator.is_empty());
+
+        for (name, declaration) in &self.outputs {
+            match (object.get(name), declaration) {
+                (Some(value), Some(declaration)) => {
+                    if !matches_type(value, declared_type(declaration)) {
+                        problems.push(format!(
+                            "\"{}\" should be {} but it was {}",
+                            name,
+                            type_name(declared_type(declaration)),
+                            value
+                        ));
+                    }
+                }
+                (Some(_), None) => {}
+                (None, _) if accumulating => {}
+                (None, _) => {
+                    problems.push(format!("\"{}\" is missing", name));
+                }
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(problems.join(", "))
+        }
+    }
+}
+
+// Describes the input variables of a node as the parameters of a tool the model can call. The model has to give every input unless it was declared optional or has a default.
+pub fn parameters_schema(node: &Node) -> serde_json::Value {
+    let mut properties = serde_json::Map::new();
+    let mut required = Vec::new();
+
+    for input_variable in &node.input_variables {
+        let declared = node
+            .input_variable_declarations
+            .iter()
+            .any(|declaration| &declaration.name == input_variable);
+
+        let declaration = input_declaration(node, input_variable);
+        let mut property = type_schema(declared_type(&declaration));
+
+        if !declaration.description.is_empty() {
+            property["description"] = json!(declaration.description);
+        }
+
+        if (!declared || declaration.required) && declaration.default_value.is_none() {
+            required.push(declaration.name.clone());
+        }
+
+        properties.insert(declaration.name, property);
+    }
+
+    json!({
+        "type": "object",
+        "properties": properties,
+        "required": required
+    })
+}
+
+fn type_schema(variable_type: VariableTypes) -> serde_json::Value {
+    match variable_type {
+        VariableTypes::StringVariable => json!({ "type": "string" }),
+        VariableTypes::NumberVariable => json!({ "type": "number" }),
+        VariableTypes::StringListVariable => json!({
+            "type": "array",
+            "items": { "type": "string" }
+        }),
+        VariableTypes::BooleanVariable => json!({ "type": "boolean" }),
+        VariableTypes::IntegerVariable => json!({ "type": "integer" }),
+        VariableTypes::ObjectVariable => json!({ "type": "object" }),
+    }
+}
+
+fn matches_type(value: &serde_json::Value, variable_type: VariableTypes) -> bool {
+    match variable_type {
+        VariableTypes::StringVariable => value.is_string(),
+        VariableTypes::NumberVariable => value.is_number(),
+        VariableTypes::StringListVariable => match value.as_array() {
+            Some(values) => values.iter().all(|value| value.is_string()),
+            None => false,
+        },
+        VariableTypes::BooleanVariable => value.is_boolean(),
+        VariableTypes::IntegerVariable => value.is_i64() || value.is_u64(),
+        VariableTypes::ObjectVariable => value.is_object(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn declaration(name: &str, variable_type: VariableTypes) -> VariableDeclaration {
+        VariableDeclaration {
+            name: name.to_string(),
+            variable_type: variable_type as i32,
+            ..Default::default()
+        }
+    }
+
+    fn node(outputs: &[&str], declarations: Vec<VariableDeclaration>) -> Node {
+        Node {
+            output_variables: outputs.iter().map(|output| output.to_string()).collect(),
+            output_variable_declarations: declarations,
+            ..Default::default()
+        }
+    }
+
+    fn object(value: serde_json::Value) -> serde_json::Map<String, serde_json::Value> {
+        value.as_object().unwrap().clone()
+    }
+
+    #[test]
+    fn undeclared_outputs_accept_any_value() {
+        let schema = OutputSchema::for_node(&node(&["anything"], Vec::new()));
+
+        for value in [
+            json!("text"),
+            json!(3),
+            json!([1, "two"]),
+            json!({ "a": 1 }),
+        ] {
+            assert!(schema
+                .validate(&object(json!({ "anything": value })))
+                .is_ok());
+        }
+
+        assert_eq!(schema.to_json_schema()["properties"]["anything"], json!({}));
+        assert!(!schema.is_strict());
+    }
+
+    #[test]
+    fn declared_outputs_must_hold_their_type() {
+        let schema = OutputSchema::for_node(&node(
+            &["count", "tags"],
+            vec![
+                declaration("count", VariableTypes::IntegerVariable),
+                declaration("tags", VariableTypes::StringListVariable),
+            ],
+        ));
+
+        assert!(schema
+            .validate(&object(json!({ "count": 2, "tags": ["a", "b"] })))
+            .is_ok());
+
+        let err = schema
+            .validate(&object(json!({ "count": 2.5, "tags": ["a", 1] })))
+            .unwrap_err();
+        assert!(err.contains("\"count\" should be an integer"));
+        assert!(err.contains("\"tags\" should be a list of strings"));
+    }
+
+    #[test]
+    fn missing_outputs_are_reported() {
+        let schema = OutputSchema::for_node(&node(&["summary", "title"], Vec::new()));
+
+        let err = schema
+            .validate(&object(json!({ "summary": "short" })))
+            .unwrap_err();
+        assert_eq!(err, "\"title\" is missing");
+    }
+
+    #[test]
+    fn the_error_field_is_always_required_but_never_validated() {
+        let schema = OutputSchema::for_node(&node(
+            &["summary"],
+            vec![declaration("summary", VariableTypes::StringVariable)],
+        ));
+        let json_schema = schema.to_json_schema();
+
+        assert_eq!(json_schema["required"], json!(["summary", "error"]));
+        assert_eq!(json_schema["properties"]["error"]["type"], json!("string"));
+        assert_eq!(json_schema["additionalProperties"], json!(false));
+
+        // The reply doesn't have to contain it
+        assert!(schema
+            .validate(&object(json!({ "summary": "short" })))
+            .is_ok());
+    }
+
+    #[test]
+    fn only_fully_typed_schemas_are_strict() {
+        let typed = node(
+            &["summary"],
+            vec![declaration("summary", VariableTypes::StringVariable)],
+        );
+        assert!(OutputSchema::for_node(&typed).is_strict());
+
+        let free_form = node(
+            &["details"],
+            vec![declaration("details", VariableTypes::ObjectVariable)],
+        );
+        assert!(!OutputSchema::for_node(&free_form).is_strict());
+    }
+
+    #[test]
+    fn conditionals_can_fill_in_the_accumulator_instead_of_their_outputs() {
+        let conditional = node(&["yes", "no"], Vec::new());
+        let schema = OutputSchema::for_conditional(&conditional);
+
+        assert!(schema
+            .validate(&object(json!({ "accumulator": "Need the date" })))
+            .is_ok());
+        assert!(schema.validate(&object(json!({ "yes": "go" }))).is_err());
+        assert!(schema
+            .validate(&object(json!({ "accumulator": "", "yes": "go" })))
+            .is_err());
+
+        let json_schema = schema.to_json_schema();
+        assert_eq!(json_schema["required"], json!(["error"]));
+        assert_eq!(
+            json_schema["properties"]["accumulator"]["type"],
+            json!("string")
+        );
+        assert!(!schema.is_strict());
+    }
+
+    #[test]
+    fn string_schemas_declare_every_field_as_a_string() {
+        let schema = OutputSchema::for_strings(&["command", "verification_command"]);
+
+        assert!(schema.is_strict());
+        assert!(schema
+            .validate(&object(
+                json!({ "command": "ls", "verification_command": "test -d ." })
+            ))
+            .is_ok());
+        assert!(schema
+            .validate(&object(
+                json!({ "command": "ls", "verification_command": 1 })
+            ))
+            .is_err());
+    }
+}
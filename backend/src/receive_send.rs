@@ -1,7 +1,9 @@
-use crate::env_vars_checker::check_env_variable_valid;
 use crate::generated_types::{self, AuthenticationMessage, Identity, Secrets};
 use crate::generated_types::{
-    body::Contents, Body, Envelope, GraphNodeInfo, Letter, UserSettings, VerbTypes,
+    body::Contents, AuditEvents, Body, BundleExport, BundleFormats, DebugActions, DebugCommand, Document, Envelope, Execution,
+    GraphNodeInfo, ImportActions, ImportReport, Letter, NodeDeletion, NodeVersionQuery, NodeVersions,
+    QuarantinedNodes, ReferencePolicies, ReferenceUpdates, SecretVariable, TeamRoles, Teams, UserSettings,
+    VerbTypes,
 };
 
 use crate::generated_types::authentication_message::Body as AuthBody;
@@ -17,13 +19,47 @@ use futures_util::future::TryFutureExt;
 
 use crate::graph::validate_nodes_in_loop;
 
+use crate::debugger::{
+    end_connection_sessions, handle_debug_command, session_finished, DebugSessions,
+};
+
 use colored::*;
 
 use std::sync::Arc;
 
-use crate::graph::{run_execution, validate_nodes_in_process};
+use crate::graph::{prepare_rerun, run_execution, validate_nodes_in_process, ExecutionContext};
+use crate::sqlite_helper_functions::{
+    authorized, check_if_user_exists, fetch_all_executions, fetch_all_nodes, fetch_execution,
+    insert_execution, insert_node, insert_user, update_node,
+};
+
+use crate::secrets::{
+    encrypt_secret, load_secrets, redact, redact_execution, restore_execution, valid_secret_name,
+    SecretValues,
+};
 use crate::sqlite_helper_functions::{
-    authorized, check_if_user_exists, fetch_all_nodes, insert_node, insert_user, update_node,
+    delete_secret, fetch_encrypted_secrets, upsert_secret, EncryptedSecret,
+};
+
+use crate::references::{
+    attach_references, next_node_version, reference_updates, references_for, resolve_execution,
+};
+use crate::audit::{is_admin, query_audit_log, record_audit, AuditActor};
+use crate::access::{
+    can_run, can_view, check_execution_access, check_execution_owner, check_history_access,
+    editable_node, execution_team, place_node, Viewer,
+};
+use crate::bundles::{export_bundle, import_bundle};
+use crate::teams::{
+    accept_invitation, allowed_to_sign_up, change_member_role, create_team, invite_member,
+    list_teams, remove_member, remove_team, update_team, withdraw_invitation,
+};
+use crate::node_deletion::delete_node;
+use crate::node_history::{diff_versions, record_version, rollback_node};
+use crate::retrieval::{collection_or_default, split_into_passages};
+use crate::sqlite_helper_functions::{
+    delete_document, fetch_documents, fetch_node_version, fetch_node_versions,
+    fetch_quarantined_nodes, insert_document, search_nodes,
 };
 
 use crate::SERVER_IDENTITY;
@@ -56,11 +92,13 @@ use tokio::sync::mpsc;
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
 pub struct LocalServerIdentity {
     pub name: String,
+    // The address the connection came from (unlike the ip_address of an Identity, which clients declare themselves)
+    pub ip_address: String,
 }
 
 impl LocalServerIdentity {
-    pub fn new(name: String) -> LocalServerIdentity {
-        LocalServerIdentity { name }
+    pub fn new(name: String, ip_address: String) -> LocalServerIdentity {
+        LocalServerIdentity { name, ip_address }
     }
 }
 
@@ -86,6 +124,12 @@ docker.ping()
 
     let mut session_ids: Vec<Session> = vec![];
 
+    // the email each connection authenticated with, used to find the secrets of the user
+    let mut user_emails: HashMap<LocalServerIdentity, String> = HashMap::new();
+
+    // executions that are paused in the debugger, keyed by their execution_id
+    let debug_sessions: DebugSessions = Arc::new(tokio::sync::Mutex::new(HashMap::new()));
+
     while let Some(msg) = client_rx.recv().await {
         println!(
             "{} {:?}",
@@ -99,6 +143,34 @@ docker.ping()
 
         println!("Client identity: {:?}", msg.0.name);
 
+        // The websocket server passes on a close message once the client is gone
+        if msg.1.is_close() {
+            let debug_sessions = debug_sessions.clone();
+            let pool = pool.clone();
+            let connection = msg.0.clone();
+            let actor = AuditActor {
+                user_email: user_emails.get(&msg.0).cloned().unwrap_or_default(),
+                session_id: String::new(),
+                source_ip: msg.0.ip_address.clone(),
+            };
+
+            // Waits for any debug command that is still running, so it is kept out of the message loop
+            tokio::spawn(async move {
+                for execution_id in end_connection_sessions(&debug_sessions, &connection).await {
+                    record_audit(
+                        &pool,
+                        &actor,
+                        VerbTypes::Execute,
+                        AuditEvents::AuditExecutionFinished,
+                        &execution_id,
+                        "Stopped in the debugger when the client disconnected",
+                    );
+                }
+            });
+
+            continue;
+        }
+
         let slice = msg.1.clone().into_data().as_slice().to_vec();
 
         let envelope: Envelope = match Envelope::decode(&*slice) {
@@ -174,6 +246,14 @@ docker.ping()
                 Contents::AuthenticationMessage(auth) => {
                     println!("{}", "Initiating authentication".green());
 
+                    let session_id = uuid::Uuid::new_v4().to_string();
+                    // Rejected attempts never get a session
+                    let rejected_actor = AuditActor {
+                        user_email: auth_email(&auth),
+                        session_id: String::new(),
+                        source_ip: msg.0.ip_address.clone(),
+                    };
+
                     match check_if_user_exists(&auth_pool, auth.clone()) {
                         Ok(user_exists) => {
                             if user_exists {
@@ -194,6 +274,21 @@ docker.ping()
                                                         msg.0.clone(),
                                                         secret.clone().user_settings.clone(),
                                                     );
+                                                    user_emails.insert(
+                                                        msg.0.clone(),
+                                                        secret.email.clone(),
+                                                    );
+                                                    record_audit(
+                                                        &pool,
+                                                        &AuditActor {
+                                                            session_id: session_id.clone(),
+                                                            ..rejected_actor.clone()
+                                                        },
+                                                        VerbTypes::Authorized,
+                                                        AuditEvents::AuditSignIn,
+                                                        &secret.email,
+                                                        "",
+                                                    );
                                                 }
                                                 _ => {
                                                     println!("Secrets not found");
@@ -202,11 +297,27 @@ docker.ping()
                                             }
                                         } else {
                                             println!("User not authorized");
+                                            record_audit(
+                                                &pool,
+                                                &rejected_actor,
+                                                VerbTypes::Authorized,
+                                                AuditEvents::AuditSignInRejected,
+                                                &rejected_actor.user_email,
+                                                "Wrong password",
+                                            );
                                             continue;
                                         }
                                     }
                                     Err(_) => {
                                         println!("user not authorized");
+                                        record_audit(
+                                            &pool,
+                                            &rejected_actor,
+                                            VerbTypes::Authorized,
+                                            AuditEvents::AuditSignInRejected,
+                                            &rejected_actor.user_email,
+                                            "Unable to check the password",
+                                        );
                                         continue;
                                     }
                                 }
@@ -215,36 +326,57 @@ docker.ping()
                                     "User does not exist. Let's create the account and session"
                                 );
 
-                                // Read the allowed emails from the file
-                                let allowed_emails =
-                                    std::fs::read_to_string("./allowed_emails.txt")
-                                        .expect("Failed to read allowed_emails.txt");
-
-                                let allowed_emails: Vec<&str> =
-                                    allowed_emails.split("\n").collect();
-
-                                let any_email = check_env_variable_valid("ALLOW_ANY_EMAIL", vec!("TRUE".to_string())).is_ok();
-
                                 match auth.clone().body.unwrap() {
                                     AuthBody::Secrets(secret) => {
                                         println!("Email: {}", secret.email);
                                         println!("Password length: {}", secret.password.len());
 
                                         // Check if user's email is in the allowed list
-                                        if allowed_emails.contains(&secret.email.as_str()) | any_email {
+                                        if allowed_to_sign_up(&secret.email) {
                                             // Create the user and session
                                             match insert_user(&auth_pool, auth.clone()) {
                                                 Ok(_) => {
                                                     println!("User created and session started");
+                                                    user_emails.insert(
+                                                        msg.0.clone(),
+                                                        secret.email.clone(),
+                                                    );
+                                                    record_audit(
+                                                        &pool,
+                                                        &AuditActor {
+                                                            session_id: session_id.clone(),
+                                                            ..rejected_actor.clone()
+                                                        },
+                                                        VerbTypes::Authorized,
+                                                        AuditEvents::AuditSignUp,
+                                                        &secret.email,
+                                                        "",
+                                                    );
                                                     // Here you might want to initiate a session or take other actions
                                                 }
                                                 Err(e) => {
                                                     println!("Failed to create user: {:?}", e);
+                                                    record_audit(
+                                                        &pool,
+                                                        &rejected_actor,
+                                                        VerbTypes::Authorized,
+                                                        AuditEvents::AuditSignInRejected,
+                                                        &secret.email,
+                                                        "Unable to create the user",
+                                                    );
                                                     continue;
                                                 }
                                             }
                                         } else {
                                             println!("User email is not in the whitelist");
+                                            record_audit(
+                                                &pool,
+                                                &rejected_actor,
+                                                VerbTypes::Authorized,
+                                                AuditEvents::AuditSignInRejected,
+                                                &secret.email,
+                                                "Email is not allowed to sign up",
+                                            );
                                             continue;
                                         }
                                     }
@@ -264,7 +396,7 @@ docker.ping()
 
                     // create session here and add it to session id:
                     let new_session = Session {
-                        session_id: uuid::Uuid::new_v4().to_string(),
+                        session_id,
                         client_identity: envelope.clone().sender,
                         backend_identity: envelope.clone().receiver,
                     };
@@ -383,12 +515,28 @@ docker.ping()
         // loop through the letters and handle each one
         for letter in envelope.clone().letters {
             // println!("Message content: {:?}", letter);
-            let verb: VerbTypes = VerbTypes::try_from(letter.verb).unwrap();
             let sender: Identity = envelope.clone().sender.unwrap();
             let receiver: generated_types::Identity = envelope.clone().receiver.unwrap();
             let wrapped_content = letter.body.clone();
             let verification_id = envelope.clone().verification_id;
             let session: Session = envelope.clone().session.unwrap();
+            let verb: VerbTypes = match VerbTypes::try_from(letter.verb) {
+                Ok(verb) => verb,
+                Err(_) => {
+                    println!("{} {:?}", "Unknown verb:".red(), letter.verb);
+
+                    let envelope = error_reply(
+                        &sender,
+                        &receiver,
+                        &verification_id,
+                        &session,
+                        format!("{} is not a known verb", letter.verb),
+                    );
+
+                    send_message(&tx, msg.0.clone(), envelope).await;
+                    continue;
+                }
+            };
             let user_settings = match runtime_settings.get(&msg.0.clone()) {
                 Some(settings) => settings.clone(),
                 None => {
@@ -401,6 +549,14 @@ docker.ping()
                 }
             };
 
+            // The user and their team roles, which decide what the letter is allowed to do
+            let viewer = Viewer::load(pool.clone(), user_emails.get(&msg.0));
+            let actor = AuditActor {
+                user_email: viewer.email.clone(),
+                session_id: session.session_id.clone(),
+                source_ip: msg.0.ip_address.clone(),
+            };
+
             let content: Contents = match wrapped_content {
                 None => {
                     println!("{} {:?}", "No contents found:".red(), letter);
@@ -424,6 +580,23 @@ docker.ping()
                             };
 
                             mutable_node.node_info = Some(new_node_info.clone());
+                            mutable_node.version = 1;
+
+                            if let Err(err) = place_node(&mut mutable_node, None, &viewer) {
+                                let envelope = error_reply(
+                                    &sender,
+                                    &receiver,
+                                    &verification_id,
+                                    &session,
+                                    err,
+                                );
+
+                                send_message(&tx, msg.0.clone(), envelope).await;
+                                continue;
+                            }
+
+                            // the note only belongs in the version history
+                            let change_note = std::mem::take(&mut mutable_node.change_note);
 
                             let body = Body {
                                 contents: Some(Contents::Node(mutable_node.clone())),
@@ -439,6 +612,21 @@ docker.ping()
                                 Ok(_) => {
                                     println!("Node inserted successfully");
 
+                                    record_node_version(
+                                        &pool,
+                                        &mutable_node,
+                                        user_emails.get(&msg.0),
+                                        &change_note,
+                                    );
+                                    record_audit(
+                                        &pool,
+                                        &actor,
+                                        verb,
+                                        AuditEvents::AuditNodeCreated,
+                                        &new_node_info.id,
+                                        "",
+                                    );
+
                                     let response_object = Envelope {
                                         sender: Some(receiver.clone()),
                                         receiver: Some(sender.clone()),
@@ -451,11 +639,70 @@ docker.ping()
                                 }
                                 Err(err) => {
                                     println!("Error inserting node: {:?}", err);
+
+                                    let envelope = error_reply(
+                                        &sender,
+                                        &receiver,
+                                        &verification_id,
+                                        &session,
+                                        format!("Unable to save node {}: {:?}", new_node_info.id, err),
+                                    );
+
+                                    send_message(&tx, msg.0.clone(), envelope).await;
                                 }
                             }
                         }
                         VerbTypes::Update => {
-                            let updated_node = node.clone();
+                            let node_id = node.node_info.clone().unwrap_or_default().id;
+
+                            let saved_node = match editable_node(pool.clone(), &node_id, &viewer) {
+                                Ok(Some(saved_node)) => saved_node,
+                                Ok(None) => {
+                                    let envelope = error_reply(
+                                        &sender,
+                                        &receiver,
+                                        &verification_id,
+                                        &session,
+                                        format!("There is no node {} to update", node_id),
+                                    );
+
+                                    send_message(&tx, msg.0.clone(), envelope).await;
+                                    continue;
+                                }
+                                Err(err) => {
+                                    let envelope = error_reply(
+                                        &sender,
+                                        &receiver,
+                                        &verification_id,
+                                        &session,
+                                        err,
+                                    );
+
+                                    send_message(&tx, msg.0.clone(), envelope).await;
+                                    continue;
+                                }
+                            };
+
+                            let mut updated_node = node.clone();
+
+                            if let Err(err) =
+                                place_node(&mut updated_node, Some(&saved_node), &viewer)
+                            {
+                                let envelope = error_reply(
+                                    &sender,
+                                    &receiver,
+                                    &verification_id,
+                                    &session,
+                                    err,
+                                );
+
+                                send_message(&tx, msg.0.clone(), envelope).await;
+                                continue;
+                            }
+
+                            updated_node.version = next_node_version(pool.clone(), &node_id);
+
+                            let change_note = std::mem::take(&mut updated_node.change_note);
 
                             let body = Body {
                                 contents: Some(Contents::Node(updated_node.clone())),
@@ -470,6 +717,21 @@ docker.ping()
                                 Ok(_) => {
                                     println!("Node updated successfully");
 
+                                    record_node_version(
+                                        &pool,
+                                        &updated_node,
+                                        user_emails.get(&msg.0),
+                                        &change_note,
+                                    );
+                                    record_audit(
+                                        &pool,
+                                        &actor,
+                                        verb,
+                                        AuditEvents::AuditNodeUpdated,
+                                        &node_id,
+                                        "",
+                                    );
+
                                     let updated_envelope = Envelope {
                                         sender: Some(receiver.clone()),
                                         receiver: Some(sender.clone()),
@@ -482,6 +744,24 @@ docker.ping()
                                 }
                                 Err(err) => {
                                     println!("Error updating node: {:?}", err);
+
+                                    // The node can be deleted in between being looked up and being saved
+                                    let error_message = match err {
+                                        rusqlite::Error::QueryReturnedNoRows => {
+                                            format!("There is no node {} to update", node_id)
+                                        }
+                                        err => format!("Unable to update node {}: {:?}", node_id, err),
+                                    };
+
+                                    let envelope = error_reply(
+                                        &sender,
+                                        &receiver,
+                                        &verification_id,
+                                        &session,
+                                        error_message,
+                                    );
+
+                                    send_message(&tx, msg.0.clone(), envelope).await;
                                 }
                             }
                         }
@@ -501,7 +781,7 @@ docker.ping()
 
                             match fetch_all_nodes(pool.clone()) {
                                 Ok(nodes) => {
-                                    for node in &nodes {
+                                    for node in nodes.iter().filter(|node| can_view(node, &viewer)) {
                                         // println!("Found node: {:?}", node);
 
                                         let body = Body {
@@ -527,12 +807,82 @@ docker.ping()
                                 }
                             }
                         }
-                        VerbTypes::Execute => todo!(),
+                        VerbTypes::Replace => {
+                            let letter = replace_node(pool.clone(), node, &viewer, &actor);
+
+                            let envelope = Envelope {
+                                letters: vec![letter],
+                                sender: Some(receiver.clone()),
+                                receiver: Some(sender.clone()),
+                                verification_id: verification_id.clone(),
+                                session: Some(session.clone()),
+                            };
+
+                            send_message(&tx, msg.0.clone(), envelope).await;
+                        }
+                        VerbTypes::Delete => {
+                            let deletion = NodeDeletion {
+                                node_id: node.node_info.clone().unwrap_or_default().id,
+                                ..Default::default()
+                            };
+
+                            let envelope = Envelope {
+                                letters: vec![node_deletion_letter(pool.clone(), deletion, &viewer, &actor)],
+                                sender: Some(receiver.clone()),
+                                receiver: Some(sender.clone()),
+                                verification_id: verification_id.clone(),
+                                session: Some(session.clone()),
+                            };
+
+                            send_message(&tx, msg.0.clone(), envelope).await;
+                        }
+                        VerbTypes::Execute => {
+                            let envelope = error_reply(
+                                &sender,
+                                &receiver,
+                                &verification_id,
+                                &session,
+                                "Nodes are run by sending the execution details of their process with the Execute verb".to_string(),
+                            );
+
+                            send_message(&tx, msg.0.clone(), envelope).await;
+                        }
                         _ => {
                             println!("{} {:?}", "Verb not supported for node:".red(), verb);
+
+                            let envelope = error_reply(
+                                &sender,
+                                &receiver,
+                                &verification_id,
+                                &session,
+                                format!("Nodes don't support the {:?} verb", verb),
+                            );
+
+                            send_message(&tx, msg.0.clone(), envelope).await;
                         }
                     }
                 }
+                Contents::NodeDeletion(deletion) => {
+                    let letter = match verb {
+                        VerbTypes::Delete => {
+                            node_deletion_letter(pool.clone(), deletion, &viewer, &actor)
+                        }
+                        _ => system_error_letter(format!(
+                            "Node deletions only support the Delete verb, not {:?}",
+                            verb
+                        )),
+                    };
+
+                    let envelope = Envelope {
+                        letters: vec![letter],
+                        sender: Some(receiver.clone()),
+                        receiver: Some(sender.clone()),
+                        verification_id: verification_id.clone(),
+                        session: Some(session.clone()),
+                    };
+
+                    send_message(&tx, msg.0.clone(), envelope).await;
+                }
                 Contents::AuthenticationMessage(_auth) => {
                     match verb {
                         _ => {
@@ -550,14 +900,50 @@ docker.ping()
                             let outer_node_info = nodes_to_process.containing_node_info.clone();
 
                             let nodes = nodes_to_process.nodes.clone();
-                            match validate_nodes_in_process(nodes, outer_node_info.unwrap()) {
-                                Ok(mutable_node) => {
+                            match validate_nodes_in_process(
+                                nodes,
+                                outer_node_info.unwrap(),
+                                nodes_to_process.conversation.clone(),
+                            ) {
+                                Ok(mut mutable_node) => {
                                     println!("Nodes validated successfully");
 
+                                    let policy = ReferencePolicies::try_from(
+                                        nodes_to_process.reference_policy,
+                                    )
+                                    .unwrap_or(ReferencePolicies::Pinned);
+
+                                    attach_references(
+                                        &mut mutable_node,
+                                        references_for(&nodes_to_process.nodes, policy),
+                                    );
+                                    let node_id =
+                                        mutable_node.node_info.clone().unwrap_or_default().id;
+
+                                    // Validating always makes a new node, which belongs to whoever validated it
+                                    mutable_node.owner_email = viewer.email.clone();
+
+                                    mutable_node.version = next_node_version(pool.clone(), &node_id);
+
                                     match insert_node(pool.clone(), mutable_node.clone()) {
                                         Ok(_) => {
                                             println!("Node inserted successfully");
 
+                                            record_node_version(
+                                                &pool,
+                                                &mutable_node,
+                                                user_emails.get(&msg.0),
+                                                "",
+                                            );
+                                            record_audit(
+                                                &pool,
+                                                &actor,
+                                                verb,
+                                                AuditEvents::AuditNodeCreated,
+                                                &node_id,
+                                                "",
+                                            );
+
                                             // we construct a new letter with the new mutable_node:
 
                                             let body = Body {
@@ -583,6 +969,16 @@ docker.ping()
                                         }
                                         Err(err) => {
                                             println!("Error inserting node: {:?}", err);
+
+                                            let envelope = error_reply(
+                                                &sender,
+                                                &receiver,
+                                                &verification_id,
+                                                &session,
+                                                format!("Unable to save node {}: {:?}", node_id, err),
+                                            );
+
+                                            send_message(&tx, msg.0.clone(), envelope).await;
                                         }
                                     }
 
@@ -601,6 +997,16 @@ docker.ping()
                                 "Verb not supported for node:".red(),
                                 letter.clone()
                             );
+
+                            let envelope = error_reply(
+                                &sender,
+                                &receiver,
+                                &verification_id,
+                                &session,
+                                format!("Validating nodes doesn't support the {:?} verb", verb),
+                            );
+
+                            send_message(&tx, msg.0.clone(), envelope).await;
                         }
                     }
                 }
@@ -611,13 +1017,43 @@ docker.ping()
 
                             let nodes = nodes_to_loop.nodes.clone();
                             match validate_nodes_in_loop(nodes, outer_node_info.unwrap()) {
-                                Ok(mutable_node) => {
+                                Ok(mut mutable_node) => {
                                     println!("Nodes validated successfully");
 
+                                    attach_references(
+                                        &mut mutable_node,
+                                        references_for(
+                                            &nodes_to_loop.nodes,
+                                            ReferencePolicies::Pinned,
+                                        ),
+                                    );
+                                    let node_id =
+                                        mutable_node.node_info.clone().unwrap_or_default().id;
+
+                                    // Validating always makes a new node, which belongs to whoever validated it
+                                    mutable_node.owner_email = viewer.email.clone();
+
+                                    mutable_node.version = next_node_version(pool.clone(), &node_id);
+
                                     match insert_node(pool.clone(), mutable_node.clone()) {
                                         Ok(_) => {
                                             println!("Node inserted successfully");
 
+                                            record_node_version(
+                                                &pool,
+                                                &mutable_node,
+                                                user_emails.get(&msg.0),
+                                                "",
+                                            );
+                                            record_audit(
+                                                &pool,
+                                                &actor,
+                                                verb,
+                                                AuditEvents::AuditNodeCreated,
+                                                &node_id,
+                                                "",
+                                            );
+
                                             // we construct a new letter with the new mutable_node:
 
                                             let body = Body {
@@ -702,82 +1138,86 @@ docker.ping()
                                 "Verb not supported for node:".red(),
                                 letter.clone()
                             );
+
+                            let envelope = error_reply(
+                                &sender,
+                                &receiver,
+                                &verification_id,
+                                &session,
+                                format!("Validating nodes doesn't support the {:?} verb", verb),
+                            );
+
+                            send_message(&tx, msg.0.clone(), envelope).await;
                         }
                     }
                 }
                 Contents::ExecutionDetails(execution) => {
                     match verb {
                         VerbTypes::Execute => {
-                            // start up the server before running the execution as the recursive function is not allowed to send between async threads.
-                            match docker
-                                .start_container(&docker_id, None::<StartContainerOptions<String>>)
-                                .await
-                            {
-                                Ok(res) => {
-                                    println!("Container started: {:?}", res);
-                                }
-                                Err(err) => {
-                                    println!("Container not started: {:?}", err);
-                                }
-                            }
-
                             // make sure the openai_api_key is set
                             match user_settings {
                                 Some(settings) => {
                                     let settings: Arc<UserSettings> = Arc::new(settings.clone());
 
-                                    match run_execution(
-                                        execution.clone(),
-                                        None,
-                                        Some(docker_id.clone()),
-                                        &docker,
-                                        settings,
-                                    )
-                                    .await
+                                    let mut execution = execution.clone();
+
+                                    // Nodes that track the latest version are brought up to date before the execution starts, then the user needs to be allowed to run all of them
+                                    if let Err(err) = resolve_execution(&pool, &mut execution)
+                                        .and_then(|_| {
+                                            check_execution_access(
+                                                pool.clone(),
+                                                &execution,
+                                                &viewer,
+                                                can_run,
+                                            )
+                                        })
                                     {
-                                        Ok((execution, _accumulator)) => {
-                                            let letter = Letter {
-                                                body: Some(Body {
-                                                    contents: Some(Contents::ExecutionDetails(
-                                                        execution,
-                                                    )),
-                                                }),
+                                        println!("{} {}", "Unable to resolve nodes:".red(), err);
+
+                                        let envelope = Envelope {
+                                            letters: vec![system_error_letter(err)],
+                                            sender: Some(receiver.clone()),
+                                            receiver: Some(sender.clone()),
+                                            verification_id: verification_id.clone(),
+                                            session: Some(session.clone()),
+                                        };
 
-                                                verb: VerbTypes::Acknowledge as i32,
-                                            };
+                                        send_message(&tx, msg.0.clone(), envelope).await;
+                                        continue;
+                                    }
 
-                                            let envelope = Envelope {
-                                                letters: vec![letter],
-                                                sender: Some(receiver.clone()),
-                                                receiver: Some(sender.clone()),
-                                                verification_id: verification_id.clone(),
-                                                session: Some(session.clone()),
-                                            };
+                                    // Every run is stored under an id of its own, whatever id the client sent along
+                                    execution.execution_id = uuid::Uuid::new_v4().to_string();
 
-                                            send_message(&tx, msg.0.clone(), envelope).await;
-                                        }
-                                        Err(error_response) => {
-                                            let letter = Letter {
-                                                body: Some(Body {
-                                                    contents: Some(Contents::ExecutionDetails(
-                                                        error_response,
-                                                    )),
-                                                }),
+                                    // keep the starting point of the execution around so that it can be rerun from any node later on
+                                    if execution.initial_variable_definitions.is_empty() {
+                                        execution.initial_variable_definitions =
+                                            execution.current_variable_definitions.clone();
+                                    }
 
-                                                verb: VerbTypes::Error as i32,
-                                            };
+                                    let context = ExecutionContext {
+                                        docker_id: Some(docker_id.clone()),
+                                        docker: docker.clone(),
+                                        user_settings: settings,
+                                        pool: pool.clone(),
+                                        secrets: user_secrets(&auth_pool, user_emails.get(&msg.0)),
+                                        user_email: user_emails.get(&msg.0).cloned(),
+                                        conversation: None,
+                                        tool_depth: 0,
+                                        process_id: "".to_string(),
+                                    };
 
-                                            let envelope = Envelope {
-                                                letters: vec![letter],
-                                                sender: Some(receiver.clone()),
-                                                receiver: Some(sender.clone()),
-                                                verification_id: verification_id.clone(),
-                                                session: Some(session.clone()),
-                                            };
+                                    let letters = execute_and_store(execution, context, &actor).await;
 
-                                            send_message(&tx, msg.0.clone(), envelope).await;
-                                        }
-                                    }
+                                    let envelope = Envelope {
+                                        letters,
+                                        sender: Some(receiver.clone()),
+                                        receiver: Some(sender.clone()),
+                                        verification_id: verification_id.clone(),
+                                        session: Some(session.clone()),
+                                    };
+
+                                    send_message(&tx, msg.0.clone(), envelope).await;
                                 }
                                 None => {
                                     println!("api key not found.. request this from the user?");
@@ -785,23 +1225,1189 @@ docker.ping()
                                 }
                             };
                         }
+                        VerbTypes::Get => {
+                            match fetch_all_executions(pool.clone(), &viewer.email) {
+                                Ok(executions) => {
+                                    let letters = executions
+                                        .into_iter()
+                                        .map(|execution| Letter {
+                                            body: Some(Body {
+                                                contents: Some(Contents::ExecutionDetails(
+                                                    execution,
+                                                )),
+                                            }),
+                                            verb: VerbTypes::Acknowledge as i32,
+                                        })
+                                        .collect();
+
+                                    let envelope = Envelope {
+                                        letters,
+                                        sender: Some(receiver.clone()),
+                                        receiver: Some(sender.clone()),
+                                        verification_id: verification_id.clone(),
+                                        session: Some(session.clone()),
+                                    };
+
+                                    send_message(&tx, msg.0.clone(), envelope).await;
+                                }
+                                Err(err) => {
+                                    println!(
+                                        "Have the following errors when attempting to pull executions from sqlite : {:?}",
+                                        err
+                                    );
+                                }
+                            }
+                        }
                         _ => {
                             println!(
                                 "{} {:?}",
                                 "Execution details not *yet* supported for this verb:".red(),
                                 verb.clone()
                             );
+
+                            let envelope = error_reply(
+                                &sender,
+                                &receiver,
+                                &verification_id,
+                                &session,
+                                format!("Execution details don't support the {:?} verb", verb),
+                            );
+
+                            send_message(&tx, msg.0.clone(), envelope).await;
+                        }
+                    }
+                }
+                Contents::RerunExecution(rerun) => {
+                    match verb {
+                        VerbTypes::Execute => {
+                            let settings: Arc<UserSettings> = match user_settings {
+                                Some(settings) => Arc::new(settings.clone()),
+                                None => {
+                                    println!("api key not found.. request this from the user?");
+                                    continue;
+                                }
+                            };
+
+                            let secrets = user_secrets(&auth_pool, user_emails.get(&msg.0));
+
+                            let prepared_execution = match fetch_execution(
+                                pool.clone(),
+                                &rerun.execution_id,
+                            ) {
+                                Ok(Some(stored_execution)) => {
+                                    check_execution_owner(
+                                        &stored_execution,
+                                        &viewer,
+                                        TeamRoles::TeamRunner,
+                                    )
+                                    .and_then(|_| {
+                                        check_execution_access(
+                                            pool.clone(),
+                                            &stored_execution.execution,
+                                            &viewer,
+                                            can_run,
+                                        )
+                                    })
+                                    .and_then(|_| {
+                                        restore_execution(&stored_execution.execution, &secrets)
+                                    })
+                                    .and_then(|execution| prepare_rerun(execution, &rerun.node_id))
+                                }
+                                Ok(None) => {
+                                    Err(format!("No execution found with id: {}", rerun.execution_id))
+                                }
+                                Err(err) => Err(format!("Unable to fetch execution: {:?}", err)),
+                            };
+
+                            let letters = match prepared_execution {
+                                Ok(execution) => {
+                                    let context = ExecutionContext {
+                                        docker_id: Some(docker_id.clone()),
+                                        docker: docker.clone(),
+                                        user_settings: settings,
+                                        pool: pool.clone(),
+                                        secrets,
+                                        user_email: user_emails.get(&msg.0).cloned(),
+                                        conversation: None,
+                                        tool_depth: 0,
+                                        process_id: "".to_string(),
+                                    };
+
+                                    execute_and_store(execution, context, &actor).await
+                                }
+                                Err(err) => {
+                                    println!("{} {}", "Unable to rerun execution:".red(), err);
+
+                                    vec![Letter {
+                                        body: Some(Body {
+                                            contents: Some(Contents::Errors(
+                                                generated_types::SystemError {
+                                                    error_message: err,
+                                                    originator: SERVER_IDENTITY.get().cloned(),
+                                                },
+                                            )),
+                                        }),
+                                        verb: VerbTypes::Error as i32,
+                                    }]
+                                }
+                            };
+
+                            let envelope = Envelope {
+                                letters,
+                                sender: Some(receiver.clone()),
+                                receiver: Some(sender.clone()),
+                                verification_id: verification_id.clone(),
+                                session: Some(session.clone()),
+                            };
+
+                            send_message(&tx, msg.0.clone(), envelope).await;
+                        }
+                        _ => {
+                            println!(
+                                "{} {:?}",
+                                "Reruns are only supported for the Execute verb:".red(),
+                                verb.clone()
+                            );
+
+                            let envelope = error_reply(
+                                &sender,
+                                &receiver,
+                                &verification_id,
+                                &session,
+                                format!("Reruns only support the Execute verb, not {:?}", verb),
+                            );
+
+                            send_message(&tx, msg.0.clone(), envelope).await;
+                        }
+                    }
+                }
+                Contents::DebugCommand(debug_command) => {
+                    match verb {
+                        VerbTypes::Execute => {
+                            // the container needs to be running in case the debugger steps into a command node
+                            match docker
+                                .start_container(&docker_id, None::<StartContainerOptions<String>>)
+                                .await
+                            {
+                                Ok(res) => {
+                                    println!("Container started: {:?}", res);
+                                }
+                                Err(err) => {
+                                    println!("Container not started: {:?}", err);
+                                }
+                            }
+
+                            let settings: Arc<UserSettings> = match user_settings {
+                                Some(settings) => Arc::new(settings.clone()),
+                                None => {
+                                    println!("api key not found.. request this from the user?");
+                                    continue;
+                                }
+                            };
+
+                            let secrets = user_secrets(&auth_pool, user_emails.get(&msg.0));
+
+                            let context = ExecutionContext {
+                                docker_id: Some(docker_id.clone()),
+                                docker: docker.clone(),
+                                user_settings: settings,
+                                pool: pool.clone(),
+                                secrets: secrets.clone(),
+                                user_email: user_emails.get(&msg.0).cloned(),
+                                conversation: None,
+                                tool_depth: 0,
+                                process_id: "".to_string(),
+                            };
+
+                            // A step runs the node it is on, which can take a while, so the command is run outside of the message loop
+                            let debug_sessions = debug_sessions.clone();
+                            let tx = tx.clone();
+                            let connection = msg.0.clone();
+                            let actor = actor.clone();
+                            let debug_command = debug_command.clone();
+                            let sender = sender.clone();
+                            let receiver = receiver.clone();
+                            let verification_id = verification_id.clone();
+                            let session = session.clone();
+
+                            tokio::spawn(async move {
+                                let letter = debug_command_letter(
+                                    &debug_sessions,
+                                    &connection,
+                                    debug_command,
+                                    &context,
+                                    &actor,
+                                    verb,
+                                )
+                                .await;
+
+                                let envelope = Envelope {
+                                    letters: vec![letter],
+                                    sender: Some(receiver.clone()),
+                                    receiver: Some(sender.clone()),
+                                    verification_id: verification_id.clone(),
+                                    session: Some(session.clone()),
+                                };
+
+                                send_message(&tx, connection, envelope).await;
+                            });
+                        }
+                        _ => {
+                            println!(
+                                "{} {:?}",
+                                "Debug commands are only supported for the Execute verb:".red(),
+                                verb.clone()
+                            );
+
+                            let envelope = error_reply(
+                                &sender,
+                                &receiver,
+                                &verification_id,
+                                &session,
+                                format!("Debug commands only support the Execute verb, not {:?}", verb),
+                            );
+
+                            send_message(&tx, msg.0.clone(), envelope).await;
                         }
                     }
                 }
+                Contents::SecretVariable(secret_variable) => {
+                    let letters = match user_emails.get(&msg.0) {
+                        Some(email) => {
+                            handle_secret_variable(&auth_pool, email, secret_variable, verb)
+                        }
+                        None => vec![system_error_letter(
+                            "Secrets can only be used once the user is known".to_string(),
+                        )],
+                    };
+
+                    let envelope = Envelope {
+                        letters,
+                        sender: Some(receiver.clone()),
+                        receiver: Some(sender.clone()),
+                        verification_id: verification_id.clone(),
+                        session: Some(session.clone()),
+                    };
+
+                    send_message(&tx, msg.0.clone(), envelope).await;
+                }
+                Contents::NodeVersionQuery(query) => {
+                    let envelope = Envelope {
+                        letters: handle_node_version_query(pool.clone(), &viewer, &actor, query, verb),
+                        sender: Some(receiver.clone()),
+                        receiver: Some(sender.clone()),
+                        verification_id: verification_id.clone(),
+                        session: Some(session.clone()),
+                    };
+
+                    send_message(&tx, msg.0.clone(), envelope).await;
+                }
+                Contents::BundleExport(bundle_export) => {
+                    let letter = match verb {
+                        VerbTypes::Get => {
+                            let format = BundleFormats::try_from(bundle_export.format)
+                                .unwrap_or(BundleFormats::BundleProtobuf);
+
+                            match export_bundle(
+                                pool.clone(),
+                                &bundle_export.node_id,
+                                format,
+                                &viewer,
+                            ) {
+                                Ok(bundle) => Letter {
+                                    body: Some(Body {
+                                        contents: Some(Contents::BundleExport(BundleExport {
+                                            bundle,
+                                            ..bundle_export
+                                        })),
+                                    }),
+                                    verb: VerbTypes::Acknowledge as i32,
+                                },
+                                Err(err) => {
+                                    println!("{} {}", "Unable to export bundle:".red(), err);
+                                    system_error_letter(err)
+                                }
+                            }
+                        }
+                        _ => system_error_letter(format!(
+                            "Bundle exports only support the Get verb, not {:?}",
+                            verb
+                        )),
+                    };
+
+                    let envelope = Envelope {
+                        letters: vec![letter],
+                        sender: Some(receiver.clone()),
+                        receiver: Some(sender.clone()),
+                        verification_id: verification_id.clone(),
+                        session: Some(session.clone()),
+                    };
+
+                    send_message(&tx, msg.0.clone(), envelope).await;
+                }
+                Contents::BundleImport(bundle_import) => {
+                    // Validate only reports what would change, Create imports the bundle
+                    let letter = match verb {
+                        VerbTypes::Validate | VerbTypes::Create => match import_bundle(
+                            pool.clone(),
+                            &bundle_import,
+                            &viewer,
+                            verb == VerbTypes::Create,
+                        ) {
+                            Ok(report) => {
+                                if report.committed {
+                                    record_imported_nodes(&pool, &actor, verb, &report);
+                                }
+
+                                Letter {
+                                    body: Some(Body {
+                                        contents: Some(Contents::ImportReport(report)),
+                                    }),
+                                    verb: VerbTypes::Acknowledge as i32,
+                                }
+                            }
+                            Err(err) => {
+                                println!("{} {}", "Unable to import bundle:".red(), err);
+                                system_error_letter(err)
+                            }
+                        },
+                        _ => system_error_letter(format!(
+                            "Bundle imports only support the Validate and Create verbs, not {:?}",
+                            verb
+                        )),
+                    };
+
+                    let envelope = Envelope {
+                        letters: vec![letter],
+                        sender: Some(receiver.clone()),
+                        receiver: Some(sender.clone()),
+                        verification_id: verification_id.clone(),
+                        session: Some(session.clone()),
+                    };
+
+                    send_message(&tx, msg.0.clone(), envelope).await;
+                }
+                Contents::NodeSearch(node_search) => {
+                    let letter = match verb {
+                        VerbTypes::Get => match search_nodes(
+                            pool.clone(),
+                            &node_search,
+                            &viewer.email,
+                        ) {
+                            Ok(results) => Letter {
+                                body: Some(Body {
+                                    contents: Some(Contents::NodeSearchResults(results)),
+                                }),
+                                verb: VerbTypes::Acknowledge as i32,
+                            },
+                            Err(err) => {
+                                println!("{} {:?}", "Unable to search nodes:".red(), err);
+                                system_error_letter(format!("Unable to search nodes: {:?}", err))
+                            }
+                        },
+                        _ => system_error_letter(format!(
+                            "Node searches only support the Get verb, not {:?}",
+                            verb
+                        )),
+                    };
+
+                    let envelope = Envelope {
+                        letters: vec![letter],
+                        sender: Some(receiver.clone()),
+                        receiver: Some(sender.clone()),
+                        verification_id: verification_id.clone(),
+                        session: Some(session.clone()),
+                    };
+
+                    send_message(&tx, msg.0.clone(), envelope).await;
+                }
+                Contents::QuarantinedNodes(_) => {
+                    let letter = match verb {
+                        // The owner of a node that can't be read is unknown, so only admins get to see them
+                        VerbTypes::Get if !is_admin(&viewer.email) => system_error_letter(
+                            "Only admins can look through the quarantined nodes".to_string(),
+                        ),
+                        VerbTypes::Get => match fetch_quarantined_nodes(pool.clone()) {
+                            Ok(nodes) => Letter {
+                                body: Some(Body {
+                                    contents: Some(Contents::QuarantinedNodes(QuarantinedNodes {
+                                        nodes,
+                                    })),
+                                }),
+                                verb: VerbTypes::Acknowledge as i32,
+                            },
+                            Err(err) => {
+                                println!("{} {:?}", "Unable to fetch quarantined nodes:".red(), err);
+                                system_error_letter(format!(
+                                    "Unable to fetch quarantined nodes: {:?}",
+                                    err
+                                ))
+                            }
+                        },
+                        _ => system_error_letter(format!(
+                            "Quarantined nodes don't support the {:?} verb",
+                            verb
+                        )),
+                    };
+
+                    let envelope = Envelope {
+                        letters: vec![letter],
+                        sender: Some(receiver.clone()),
+                        receiver: Some(sender.clone()),
+                        verification_id: verification_id.clone(),
+                        session: Some(session.clone()),
+                    };
+
+                    send_message(&tx, msg.0.clone(), envelope).await;
+                }
+                Contents::ReferenceUpdates(_) => {
+                    let letter = match verb {
+                        VerbTypes::Get => match reference_updates(
+                            pool.clone(),
+                            &viewer,
+                        ) {
+                            Ok(updates) => Letter {
+                                body: Some(Body {
+                                    contents: Some(Contents::ReferenceUpdates(ReferenceUpdates {
+                                        updates,
+                                    })),
+                                }),
+                                verb: VerbTypes::Acknowledge as i32,
+                            },
+                            Err(err) => {
+                                println!("{} {}", "Unable to check for updates:".red(), err);
+                                system_error_letter(err)
+                            }
+                        },
+                        _ => system_error_letter(format!(
+                            "Reference updates don't support the {:?} verb",
+                            verb
+                        )),
+                    };
+
+                    let envelope = Envelope {
+                        letters: vec![letter],
+                        sender: Some(receiver.clone()),
+                        receiver: Some(sender.clone()),
+                        verification_id: verification_id.clone(),
+                        session: Some(session.clone()),
+                    };
+
+                    send_message(&tx, msg.0.clone(), envelope).await;
+                }
+                Contents::Team(team) => {
+                    let result = match verb {
+                        VerbTypes::Create => create_team(pool.clone(), &viewer, &team),
+                        VerbTypes::Update => update_team(pool.clone(), &viewer, &team),
+                        VerbTypes::Delete => remove_team(pool.clone(), &viewer, &team),
+                        VerbTypes::Get => list_teams(pool.clone(), &viewer.email),
+                        _ => Err(format!("Teams don't support the {:?} verb", verb)),
+                    };
+
+                    let envelope = Envelope {
+                        letters: vec![teams_letter(result)],
+                        sender: Some(receiver.clone()),
+                        receiver: Some(sender.clone()),
+                        verification_id: verification_id.clone(),
+                        session: Some(session.clone()),
+                    };
+
+                    send_message(&tx, msg.0.clone(), envelope).await;
+                }
+                Contents::TeamInvitation(invitation) => {
+                    let result = match verb {
+                        VerbTypes::Create => invite_member(pool.clone(), &viewer, &invitation),
+                        VerbTypes::Update => accept_invitation(pool.clone(), &viewer, &invitation),
+                        VerbTypes::Delete => {
+                            withdraw_invitation(pool.clone(), &viewer, &invitation)
+                        }
+                        _ => Err(format!(
+                            "Team invitations don't support the {:?} verb",
+                            verb
+                        )),
+                    };
+
+                    let envelope = Envelope {
+                        letters: vec![teams_letter(result)],
+                        sender: Some(receiver.clone()),
+                        receiver: Some(sender.clone()),
+                        verification_id: verification_id.clone(),
+                        session: Some(session.clone()),
+                    };
+
+                    send_message(&tx, msg.0.clone(), envelope).await;
+                }
+                Contents::TeamMember(member) => {
+                    let result = match verb {
+                        VerbTypes::Update => change_member_role(pool.clone(), &viewer, &member),
+                        VerbTypes::Delete => remove_member(pool.clone(), &viewer, &member),
+                        _ => Err(format!("Team members don't support the {:?} verb", verb)),
+                    };
+
+                    let envelope = Envelope {
+                        letters: vec![teams_letter(result)],
+                        sender: Some(receiver.clone()),
+                        receiver: Some(sender.clone()),
+                        verification_id: verification_id.clone(),
+                        session: Some(session.clone()),
+                    };
+
+                    send_message(&tx, msg.0.clone(), envelope).await;
+                }
+                Contents::AuditQuery(query) => {
+                    let letter = match verb {
+                        VerbTypes::Get => match query_audit_log(pool.clone(), &viewer.email, &query) {
+                            Ok(audit_log) => Letter {
+                                body: Some(Body {
+                                    contents: Some(Contents::AuditLog(audit_log)),
+                                }),
+                                verb: VerbTypes::Acknowledge as i32,
+                            },
+                            Err(err) => {
+                                println!("{} {}", "Audit query failed:".red(), err);
+                                system_error_letter(err)
+                            }
+                        },
+                        _ => system_error_letter(format!(
+                            "Audit queries only support the Get verb, not {:?}",
+                            verb
+                        )),
+                    };
+
+                    let envelope = Envelope {
+                        letters: vec![letter],
+                        sender: Some(receiver.clone()),
+                        receiver: Some(sender.clone()),
+                        verification_id: verification_id.clone(),
+                        session: Some(session.clone()),
+                    };
+
+                    send_message(&tx, msg.0.clone(), envelope).await;
+                }
+                Contents::Document(document) => {
+                    let letters = match user_emails.get(&msg.0) {
+                        Some(email) => handle_document(pool.clone(), email, document, verb),
+                        None => vec![system_error_letter(
+                            "Documents can only be used once the user is known".to_string(),
+                        )],
+                    };
+
+                    let envelope = Envelope {
+                        letters,
+                        sender: Some(receiver.clone()),
+                        receiver: Some(sender.clone()),
+                        verification_id: verification_id.clone(),
+                        session: Some(session.clone()),
+                    };
+
+                    send_message(&tx, msg.0.clone(), envelope).await;
+                }
                 _ => {
                     println!("{}", "Not yet implemented".red());
+
+                    let envelope = error_reply(
+                        &sender,
+                        &receiver,
+                        &verification_id,
+                        &session,
+                        format!("The {:?} verb isn't supported for this content", verb),
+                    );
+
+                    send_message(&tx, msg.0.clone(), envelope).await;
                 }
             }
         }
     }
 }
 
+fn system_error_letter(error_message: String) -> Letter {
+    Letter {
+        body: Some(Body {
+            contents: Some(Contents::Errors(generated_types::SystemError {
+                error_message,
+                originator: SERVER_IDENTITY.get().cloned(),
+            })),
+        }),
+        verb: VerbTypes::Error as i32,
+    }
+}
+
+// The email the user signed in or up with, if the message has one
+fn auth_email(auth: &AuthenticationMessage) -> String {
+    match &auth.body {
+        Some(AuthBody::Secrets(secret)) => secret.email.clone(),
+        _ => String::new(),
+    }
+}
+
+// Every change to a team is answered with the teams of the user as they are afterwards
+fn teams_letter(result: Result<Teams, String>) -> Letter {
+    match result {
+        Ok(teams) => Letter {
+            body: Some(Body {
+                contents: Some(Contents::Teams(teams)),
+            }),
+            verb: VerbTypes::Acknowledge as i32,
+        },
+        Err(err) => {
+            println!("{} {}", "Team request failed:".red(), err);
+            system_error_letter(err)
+        }
+    }
+}
+
+// The secrets of the user are decrypted right before an execution starts. A user without any secrets (or whose secrets can't be read) runs with an empty set.
+fn user_secrets(
+    auth_pool: &Arc<Pool<SqliteConnectionManager>>,
+    email: Option<&String>,
+) -> Arc<SecretValues> {
+    let secrets = match email {
+        Some(email) => match load_secrets(auth_pool, email) {
+            Ok(secrets) => secrets,
+            Err(err) => {
+                println!("{} {}", "Unable to load secrets:".red(), err);
+                HashMap::new()
+            }
+        },
+        None => HashMap::new(),
+    };
+
+    Arc::new(secrets)
+}
+
+// Create and Update both store the secret, Get lists the secrets of the user and Delete removes one. The value of a secret is never sent back.
+fn handle_secret_variable(
+    auth_pool: &Arc<Pool<SqliteConnectionManager>>,
+    email: &str,
+    secret_variable: SecretVariable,
+    verb: VerbTypes,
+) -> Vec<Letter> {
+    let acknowledge = |secret_variable: SecretVariable| Letter {
+        body: Some(Body {
+            contents: Some(Contents::SecretVariable(SecretVariable {
+                value: "".to_string(),
+                ..secret_variable
+            })),
+        }),
+        verb: VerbTypes::Acknowledge as i32,
+    };
+
+    match verb {
+        VerbTypes::Create | VerbTypes::Update => {
+            if !valid_secret_name(&secret_variable.name) {
+                return vec![system_error_letter(format!(
+                    "{} is not a valid secret name, use letters, digits and underscores only",
+                    secret_variable.name
+                ))];
+            }
+
+            let (nonce, ciphertext) =
+                match encrypt_secret(email, &secret_variable.name, &secret_variable.value) {
+                    Ok(encrypted) => encrypted,
+                    Err(err) => {
+                        println!("{} {}", "Unable to encrypt secret:".red(), err);
+                        return vec![system_error_letter(err)];
+                    }
+                };
+
+            let encrypted_secret = EncryptedSecret {
+                name: secret_variable.name.clone(),
+                description: secret_variable.description.clone(),
+                nonce,
+                ciphertext,
+            };
+
+            match upsert_secret(auth_pool, email, &encrypted_secret) {
+                Ok(_) => vec![acknowledge(secret_variable)],
+                Err(err) => {
+                    println!("{} {:?}", "Unable to store secret:".red(), err);
+                    vec![system_error_letter("Unable to store secret".to_string())]
+                }
+            }
+        }
+        VerbTypes::Delete => match delete_secret(auth_pool, email, &secret_variable.name) {
+            Ok(true) => vec![acknowledge(secret_variable)],
+            Ok(false) => vec![system_error_letter(format!(
+                "No secret named {}",
+                secret_variable.name
+            ))],
+            Err(err) => {
+                println!("{} {:?}", "Unable to delete secret:".red(), err);
+                vec![system_error_letter("Unable to delete secret".to_string())]
+            }
+        },
+        VerbTypes::Get => match fetch_encrypted_secrets(auth_pool, email) {
+            Ok(secrets) => secrets
+                .into_iter()
+                .map(|secret| {
+                    acknowledge(SecretVariable {
+                        name: secret.name,
+                        value: "".to_string(),
+                        description: secret.description,
+                    })
+                })
+                .collect(),
+            Err(err) => {
+                println!("{} {:?}", "Unable to fetch secrets:".red(), err);
+                vec![system_error_letter("Unable to fetch secrets".to_string())]
+            }
+        },
+        _ => vec![system_error_letter(format!(
+            "Secrets don't support the {:?} verb",
+            verb
+        ))],
+    }
+}
+
+// Swaps the reply around so it goes back to whoever sent the letter
+fn error_reply(
+    sender: &Identity,
+    receiver: &Identity,
+    verification_id: &str,
+    session: &Session,
+    error_message: String,
+) -> Envelope {
+    Envelope {
+        letters: vec![system_error_letter(error_message)],
+        sender: Some(receiver.clone()),
+        receiver: Some(sender.clone()),
+        verification_id: verification_id.to_string(),
+        session: Some(session.clone()),
+    }
+}
+
+fn node_deletion_letter(
+    pool: Arc<Pool<SqliteConnectionManager>>,
+    deletion: NodeDeletion,
+    viewer: &Viewer,
+    actor: &AuditActor,
+) -> Letter {
+    match delete_node(pool.clone(), &deletion, viewer) {
+        Ok(deleted_node_ids) => {
+            println!("{} {:?}", "Deleted nodes:".green(), deleted_node_ids);
+
+            let detail = if deletion.soft_delete {
+                "Soft delete"
+            } else {
+                ""
+            };
+            for node_id in &deleted_node_ids {
+                record_audit(
+                    &pool,
+                    actor,
+                    VerbTypes::Delete,
+                    AuditEvents::AuditNodeDeleted,
+                    node_id,
+                    detail,
+                );
+            }
+
+            Letter {
+                body: Some(Body {
+                    contents: Some(Contents::NodeDeletion(NodeDeletion {
+                        deleted_node_ids,
+                        ..deletion
+                    })),
+                }),
+                verb: VerbTypes::Acknowledge as i32,
+            }
+        }
+        Err(err) => {
+            println!("{} {}", "Unable to delete node:".red(), err);
+            system_error_letter(err)
+        }
+    }
+}
+
+// Unlike an update, a replacement swaps out everything about the node (its type included) while keeping its id and owner. The node has to exist already.
+fn replace_node(
+    pool: Arc<Pool<SqliteConnectionManager>>,
+    node: generated_types::Node,
+    viewer: &Viewer,
+    actor: &AuditActor,
+) -> Letter {
+    let node_id = node.node_info.clone().unwrap_or_default().id;
+
+    let saved_node = match editable_node(pool.clone(), &node_id, viewer) {
+        Ok(Some(saved_node)) => saved_node,
+        Ok(None) => return system_error_letter(format!("There is no node {} to replace", node_id)),
+        Err(err) => return system_error_letter(err),
+    };
+
+    let mut replacement = node;
+    if let Err(err) = place_node(&mut replacement, Some(&saved_node), viewer) {
+        return system_error_letter(err);
+    }
+    replacement.version = next_node_version(pool.clone(), &node_id);
+    let change_note = std::mem::take(&mut replacement.change_note);
+
+    if let Err(err) = insert_node(pool.clone(), replacement.clone()) {
+        return system_error_letter(format!("Unable to replace node {}: {:?}", node_id, err));
+    }
+
+    println!("{} {}", "Node replaced:".green(), node_id);
+
+    record_node_version(&pool, &replacement, Some(&viewer.email), &change_note);
+    record_audit(
+        &pool,
+        actor,
+        VerbTypes::Replace,
+        AuditEvents::AuditNodeReplaced,
+        &node_id,
+        "",
+    );
+
+    Letter {
+        body: Some(Body {
+            contents: Some(Contents::Node(replacement)),
+        }),
+        verb: VerbTypes::Acknowledge as i32,
+    }
+}
+
+// Only the nodes that were added or changed by the import end up in the audit log
+fn record_imported_nodes(
+    pool: &Arc<Pool<SqliteConnectionManager>>,
+    actor: &AuditActor,
+    verb: VerbTypes,
+    report: &ImportReport,
+) {
+    for change in &report.changes {
+        let detail = match ImportActions::try_from(change.action) {
+            Ok(ImportActions::ImportAdd) => "Added from a bundle",
+            Ok(ImportActions::ImportUpdate) => "Updated from a bundle",
+            _ => continue,
+        };
+
+        record_audit(
+            pool,
+            actor,
+            verb,
+            AuditEvents::AuditNodeImported,
+            &change.node.clone().unwrap_or_default().id,
+            detail,
+        );
+    }
+}
+
+// Failing to record a version doesn't undo the save, it is only reported
+fn record_node_version(
+    pool: &Arc<Pool<SqliteConnectionManager>>,
+    node: &generated_types::Node,
+    author: Option<&String>,
+    change_note: &str,
+) {
+    let author = author.cloned().unwrap_or_default();
+
+    if let Err(err) = record_version(pool.clone(), node, &author, change_note) {
+        println!("{} {}", "Unable to record node version:".red(), err);
+    }
+}
+
+fn handle_node_version_query(
+    pool: Arc<Pool<SqliteConnectionManager>>,
+    viewer: &Viewer,
+    actor: &AuditActor,
+    query: NodeVersionQuery,
+    verb: VerbTypes,
+) -> Vec<Letter> {
+    let acknowledge = |contents: Contents| Letter {
+        body: Some(Body {
+            contents: Some(contents),
+        }),
+        verb: VerbTypes::Acknowledge as i32,
+    };
+
+    if let Err(err) = check_history_access(
+        pool.clone(),
+        &query.node_id,
+        viewer,
+        verb == VerbTypes::Rollback,
+    ) {
+        return vec![system_error_letter(err)];
+    }
+
+    let result = match verb {
+        VerbTypes::ListVersions => fetch_node_versions(pool, &query.node_id)
+            .map(|versions| Contents::NodeVersions(NodeVersions { versions }))
+            .map_err(|err| format!("Unable to fetch versions: {:?}", err)),
+        VerbTypes::GetVersion => match fetch_node_version(pool, &query.node_id, query.version) {
+            Ok(Some(node_version)) => Ok(Contents::NodeVersion(node_version)),
+            Ok(None) => Err(format!(
+                "Node {} has no version {}",
+                query.node_id, query.version
+            )),
+            Err(err) => Err(format!("Unable to fetch version: {:?}", err)),
+        },
+        VerbTypes::Diff => {
+            diff_versions(pool, &query.node_id, query.version, query.other_version)
+                .map(Contents::NodeDiff)
+        }
+        VerbTypes::Rollback => rollback_node(
+            pool.clone(),
+            &query.node_id,
+            query.version,
+            &viewer.email,
+            &query.change_note,
+        )
+        .map(|node| {
+            record_audit(
+                &pool,
+                actor,
+                verb,
+                AuditEvents::AuditNodeRolledBack,
+                &query.node_id,
+                &format!("Rolled back to version {}", query.version),
+            );
+            Contents::Node(node)
+        }),
+        _ => Err(format!(
+            "Node version queries don't support the {:?} verb",
+            verb
+        )),
+    };
+
+    match result {
+        Ok(contents) => vec![acknowledge(contents)],
+        Err(err) => {
+            println!("{} {}", "Node version query failed:".red(), err);
+            vec![system_error_letter(err)]
+        }
+    }
+}
+
+// Create and Update (re)index the document for retrieve nodes, Get lists the documents of the user and Delete removes one along with its passages
+fn handle_document(
+    pool: Arc<Pool<SqliteConnectionManager>>,
+    email: &str,
+    document: Document,
+    verb: VerbTypes,
+) -> Vec<Letter> {
+    let acknowledge = |document: Document| Letter {
+        body: Some(Body {
+            contents: Some(Contents::Document(document)),
+        }),
+        verb: VerbTypes::Acknowledge as i32,
+    };
+
+    match verb {
+        VerbTypes::Create | VerbTypes::Update => {
+            let mut document = document;
+
+            if document.document_id.is_empty() {
+                document.document_id = uuid::Uuid::new_v4().to_string();
+            }
+            document.collection = collection_or_default(&document.collection);
+
+            let passages = split_into_passages(&document.content);
+
+            match insert_document(pool, email, &document, &passages) {
+                Ok(_) => {
+                    println!(
+                        "{} {} ({} passages)",
+                        "Indexed document:".green(),
+                        document.title,
+                        passages.len()
+                    );
+                    vec![acknowledge(document)]
+                }
+                Err(err) => {
+                    println!("{} {:?}", "Unable to store document:".red(), err);
+                    vec![system_error_letter("Unable to store document".to_string())]
+                }
+            }
+        }
+        VerbTypes::Delete => match delete_document(pool, email, &document.document_id) {
+            Ok(true) => vec![acknowledge(document)],
+            Ok(false) => vec![system_error_letter(format!(
+                "No document with id {}",
+                document.document_id
+            ))],
+            Err(err) => {
+                println!("{} {:?}", "Unable to delete document:".red(), err);
+                vec![system_error_letter("Unable to delete document".to_string())]
+            }
+        },
+        VerbTypes::Get => match fetch_documents(pool, email) {
+            Ok(documents) => documents.into_iter().map(acknowledge).collect(),
+            Err(err) => {
+                println!("{} {:?}", "Unable to fetch documents:".red(), err);
+                vec![system_error_letter("Unable to fetch documents".to_string())]
+            }
+        },
+        _ => vec![system_error_letter(format!(
+            "Documents don't support the {:?} verb",
+            verb
+        ))],
+    }
+}
+
+// Runs a debug command and records when the execution being debugged starts and finishes. Returns the letter with the new state of the session (or the reason the command failed).
+async fn debug_command_letter(
+    debug_sessions: &DebugSessions,
+    connection: &LocalServerIdentity,
+    debug_command: DebugCommand,
+    context: &ExecutionContext,
+    actor: &AuditActor,
+    verb: VerbTypes,
+) -> Letter {
+    // Finishing is only recorded once, by the command that ran the last node or stopped the session before that
+    let finished_before = session_finished(debug_sessions, &debug_command.execution_id).await;
+
+    match handle_debug_command(debug_sessions, connection, debug_command.clone(), context).await {
+        Ok(mut debug_state) => {
+            let audit_event = match DebugActions::try_from(debug_command.action) {
+                Ok(DebugActions::StartDebugging) => Some((
+                    AuditEvents::AuditExecutionStarted,
+                    "Started in the debugger",
+                )),
+                Ok(DebugActions::StopDebugging) if !finished_before => Some((
+                    AuditEvents::AuditExecutionFinished,
+                    "Stopped in the debugger before it finished",
+                )),
+                Ok(DebugActions::StopDebugging) => None,
+                _ if debug_state.finished && !finished_before => Some((
+                    AuditEvents::AuditExecutionFinished,
+                    "Finished in the debugger",
+                )),
+                _ => None,
+            };
+
+            if let Some((event, detail)) = audit_event {
+                record_audit(
+                    &context.pool,
+                    actor,
+                    verb,
+                    event,
+                    &debug_state
+                        .execution
+                        .clone()
+                        .unwrap_or_default()
+                        .execution_id,
+                    detail,
+                );
+            }
+
+            debug_state.execution = debug_state
+                .execution
+                .map(|execution| redact_execution(&execution, &context.secrets));
+            debug_state.accumulator = redact(&debug_state.accumulator, &context.secrets);
+
+            Letter {
+                body: Some(Body {
+                    contents: Some(Contents::DebugState(debug_state)),
+                }),
+                verb: VerbTypes::Acknowledge as i32,
+            }
+        }
+        Err(err) => {
+            let err = redact(&err, &context.secrets);
+
+            println!("{} {}", "Debug command failed:".red(), err);
+
+            let system_error = generated_types::SystemError {
+                error_message: err,
+                originator: SERVER_IDENTITY.get().cloned(),
+            };
+
+            Letter {
+                body: Some(Body {
+                    contents: Some(Contents::Errors(system_error)),
+                }),
+                verb: VerbTypes::Error as i32,
+            }
+        }
+    }
+}
+
+// Runs the execution and stores the result so that it can be looked at (and rerun) later. Returns the letters that should be sent back to the client: the execution itself along with the reason it failed (if it did).
+async fn execute_and_store(
+    execution: Execution,
+    context: ExecutionContext,
+    actor: &AuditActor,
+) -> Vec<Letter> {
+    // start up the server before running the execution as the recursive function is not allowed to send between async threads.
+    if let Some(docker_id) = &context.docker_id {
+        match context
+            .docker
+            .start_container(docker_id, None::<StartContainerOptions<String>>)
+            .await
+        {
+            Ok(res) => {
+                println!("Container started: {:?}", res);
+            }
+            Err(err) => {
+                println!("Container not started: {:?}", err);
+            }
+        }
+    }
+
+    let secrets = context.secrets.clone();
+
+    let execution_id = execution.execution_id.clone();
+    record_audit(
+        &context.pool,
+        actor,
+        VerbTypes::Execute,
+        AuditEvents::AuditExecutionStarted,
+        &execution_id,
+        "",
+    );
+
+    let (stored_execution, error_message) = match run_execution(execution, None, &context).await {
+        Ok((execution, _accumulator)) => (execution, None),
+        Err((error_response, err)) => (error_response, Some(err)),
+    };
+
+    // Nothing that leaves this function (the stored execution included) may contain a secret value
+    let stored_execution = redact_execution(&stored_execution, &secrets);
+    let error_message = error_message.map(|err| redact(&err, &secrets));
+
+    let viewer = Viewer::load(context.pool.clone(), context.user_email.as_ref());
+    let team_id = execution_team(context.pool.clone(), &stored_execution, &viewer);
+
+    if let Err(err) =
+        insert_execution(context.pool.clone(), &stored_execution, &viewer.email, &team_id)
+    {
+        println!("Error storing execution: {:?}", err);
+    }
+
+    record_audit(
+        &context.pool,
+        actor,
+        VerbTypes::Execute,
+        AuditEvents::AuditExecutionFinished,
+        &execution_id,
+        error_message.as_deref().unwrap_or_default(),
+    );
+
+    match error_message {
+        None => vec![Letter {
+            body: Some(Body {
+                contents: Some(Contents::ExecutionDetails(stored_execution)),
+            }),
+            verb: VerbTypes::Acknowledge as i32,
+        }],
+        Some(err) => vec![
+            Letter {
+                body: Some(Body {
+                    contents: Some(Contents::ExecutionDetails(stored_execution)),
+                }),
+                verb: VerbTypes::Error as i32,
+            },
+            Letter {
+                body: Some(Body {
+                    contents: Some(Contents::Errors(generated_types::SystemError {
+                        error_message: err,
+                        originator: SERVER_IDENTITY.get().cloned(),
+                    })),
+                }),
+                verb: VerbTypes::Error as i32,
+            },
+        ],
+    }
+}
+
 pub async fn send_message(
     tx: &UnboundedSender<(LocalServerIdentity, tokio_tungstenite::tungstenite::Message)>,
     identity: LocalServerIdentity,
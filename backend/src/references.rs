@@ -0,0 +1,312 @@
+use crate::access::{can_view, Viewer};
+use crate::generated_types::{
+    node_content::NodeContent as NodeContentEnum, Execution, GraphNodeInfo, Node, NodeContent,
+    NodeReference, Process, ReferencePolicies, ReferenceUpdate,
+};
+use crate::graph::{validate_nodes_in_loop, validate_nodes_in_process};
+use crate::sqlite_helper_functions::{fetch_all_nodes, fetch_node, latest_recorded_version};
+
+use colored::*;
+use r2d2::Pool;
+use r2d2_sqlite::SqliteConnectionManager;
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+// The version a node gets when it is saved: one higher than the version already in the database (or 1 for a new node). The history is checked too since a soft deleted node is no longer fetched but its versions are still taken.
+pub fn next_node_version(pool: Arc<Pool<SqliteConnectionManager>>, node_id: &str) -> u32 {
+    let stored_version = match fetch_node(pool.clone(), node_id) {
+        Ok(Some(stored_node)) => stored_node.version,
+        _ => 0,
+    };
+
+    let recorded_version = latest_recorded_version(pool, node_id).unwrap_or(0);
+
+    stored_version.max(recorded_version) + 1
+}
+
+// References every node of a new process at the version it was copied from
+pub fn references_for(nodes: &[Node], policy: ReferencePolicies) -> Vec<NodeReference> {
+    nodes
+        .iter()
+        .map(|node| NodeReference {
+            node_id: node.node_info.clone().unwrap_or_default().id,
+            version: node.version,
+            policy: policy as i32,
+        })
+        .collect()
+}
+
+// Stores the references in the process (or loop) the node contains
+pub fn attach_references(node: &mut Node, references: Vec<NodeReference>) {
+    match node
+        .node_content
+        .as_mut()
+        .and_then(|node_content| node_content.node_content.as_mut())
+    {
+        Some(NodeContentEnum::Process(process)) => process.node_references = references,
+        Some(NodeContentEnum::Loop(contained_loop)) => {
+            if let Some(process) = contained_loop.process.as_mut() {
+                process.node_references = references;
+            }
+        }
+        _ => {}
+    }
+}
+
+fn node_id(node: &Node) -> String {
+    node.node_info.clone().unwrap_or_default().id
+}
+
+fn contained_process(node: &Node) -> Option<(Process, bool)> {
+    match node
+        .node_content
+        .clone()
+        .and_then(|node_content| node_content.node_content)
+    {
+        Some(NodeContentEnum::Process(process)) => Some((process, false)),
+        Some(NodeContentEnum::Loop(contained_loop)) => {
+            contained_loop.process.map(|process| (process, true))
+        }
+        _ => None,
+    }
+}
+
+// Swaps every node that tracks the latest version for the latest saved version, including the nodes of nested processes and loops. Returns None when the process is already up to date.
+pub fn resolve_process(
+    pool: &Arc<Pool<SqliteConnectionManager>>,
+    process: &Process,
+) -> Result<Option<Process>, String> {
+    match resolve_contained_process(pool, process, false)? {
+        Some(validated) => match contained_process(&validated) {
+            Some((resolved, _)) => Ok(Some(resolved)),
+            None => Err("Validation did not produce a process".to_string()),
+        },
+        None => Ok(None),
+    }
+}
+
+// Swaps in the latest version of every node the process tracks. An execution that hasn't started yet starts at the first node of the (possibly reordered) process.
+pub fn resolve_execution(
+    pool: &Arc<Pool<SqliteConnectionManager>>,
+    execution: &mut Execution,
+) -> Result<(), String> {
+    let process = match &execution.process {
+        Some(process) => process,
+        None => return Ok(()),
+    };
+
+    let at_start = execution.current_node.is_none()
+        || execution.current_node.as_ref() == process.topological_order.first();
+
+    if let Some(resolved) = resolve_process(pool, process)? {
+        if at_start {
+            execution.current_node = resolved.topological_order.first().cloned();
+        }
+        execution.process = Some(resolved);
+    }
+
+    Ok(())
+}
+
+// The graph and topological order are rebuilt when anything changed since a newer node can have different variables. Returns the node validation builds around the resolved process.
+fn resolve_contained_process(
+    pool: &Arc<Pool<SqliteConnectionManager>>,
+    process: &Process,
+    is_loop: bool,
+) -> Result<Option<Node>, String> {
+    let mut nodes = process.nodes.clone();
+    let mut references = process.node_references.clone();
+    let mut changed = false;
+
+    for reference in references.iter_mut() {
+        if ReferencePolicies::try_from(reference.policy) != Ok(ReferencePolicies::TrackLatest) {
+            continue;
+        }
+
+        let latest = match fetch_node(pool.clone(), &reference.node_id) {
+            Ok(Some(latest)) => latest,
+            Ok(None) => {
+                println!(
+                    "{} {}",
+                    "Keeping the local copy of a node that is no longer saved:".yellow(),
+                    reference.node_id
+                );
+                continue;
+            }
+            Err(err) => {
+                return Err(format!(
+                    "Unable to look up node {}: {:?}",
+                    reference.node_id, err
+                ))
+            }
+        };
+
+        if latest.version <= reference.version {
+            continue;
+        }
+
+        if let Some(position) = nodes
+            .iter()
+            .position(|node| node_id(node) == reference.node_id)
+        {
+            println!(
+                "{} {} (version {} to {})",
+                "Updating node:".green(),
+                latest.node_info.clone().unwrap_or_default().name,
+                reference.version,
+                latest.version
+            );
+
+            nodes[position] = latest.clone();
+            reference.version = latest.version;
+            changed = true;
+        }
+    }
+
+    for node in nodes.iter_mut() {
+        if let Some(resolved_node) = resolve_node(pool, node)? {
+            *node = resolved_node;
+            changed = true;
+        }
+    }
+
+    if !changed {
+        return Ok(None);
+    }
+
+    let mut validated = if is_loop {
+        validate_nodes_in_loop(nodes, GraphNodeInfo::default())
+    } else {
+        validate_nodes_in_process(
+            nodes,
+            GraphNodeInfo::default(),
+            process.conversation.clone(),
+        )
+    }
+    .map_err(|err| format!("The process no longer fits together: {}", err))?;
+
+    if let Some(NodeContentEnum::Process(resolved)) = validated
+        .node_content
+        .as_mut()
+        .and_then(|node_content| node_content.node_content.as_mut())
+    {
+        resolved.node_references = references;
+    }
+
+    Ok(Some(validated))
+}
+
+// Resolves the process (or loop) inside of the node. The node keeps its id, name and version but takes on the variables of the resolved process.
+fn resolve_node(
+    pool: &Arc<Pool<SqliteConnectionManager>>,
+    node: &Node,
+) -> Result<Option<Node>, String> {
+    let (process, is_loop) = match contained_process(node) {
+        Some(contained) => contained,
+        None => return Ok(None),
+    };
+
+    let validated = match resolve_contained_process(pool, &process, is_loop)? {
+        Some(validated) => validated,
+        None => return Ok(None),
+    };
+
+    let resolved_process = match contained_process(&validated) {
+        Some((resolved_process, _)) => resolved_process,
+        None => return Err("Validation did not produce a process".to_string()),
+    };
+
+    let mut resolved_node = node.clone();
+
+    resolved_node.node_content = Some(NodeContent {
+        node_content: Some(
+            match node
+                .node_content
+                .clone()
+                .and_then(|node_content| node_content.node_content)
+            {
+                Some(NodeContentEnum::Loop(mut contained_loop)) => {
+                    contained_loop.process = Some(resolved_process.clone());
+                    NodeContentEnum::Loop(contained_loop)
+                }
+                _ => NodeContentEnum::Process(resolved_process.clone()),
+            },
+        ),
+    });
+
+    // The variables a process exposes are worked out from its nodes
+    resolved_node.input_variables = validated.input_variables;
+    resolved_node.output_variables = validated.output_variables;
+    resolved_node.input_variable_declarations = validated.input_variable_declarations;
+    resolved_node.output_variable_declarations = validated.output_variable_declarations;
+
+    Ok(Some(resolved_node))
+}
+
+fn collect_updates(
+    process_info: &GraphNodeInfo,
+    process: &Process,
+    latest_versions: &HashMap<String, (GraphNodeInfo, u32)>,
+    updates: &mut Vec<ReferenceUpdate>,
+) {
+    for reference in &process.node_references {
+        if let Some((node_info, latest_version)) = latest_versions.get(&reference.node_id) {
+            if *latest_version > reference.version {
+                updates.push(ReferenceUpdate {
+                    process: Some(process_info.clone()),
+                    node: Some(node_info.clone()),
+                    current_version: reference.version,
+                    latest_version: *latest_version,
+                    policy: reference.policy,
+                });
+            }
+        }
+    }
+
+    for node in &process.nodes {
+        if let Some((nested_process, _)) = contained_process(node) {
+            collect_updates(
+                &node.node_info.clone().unwrap_or_default(),
+                &nested_process,
+                latest_versions,
+                updates,
+            );
+        }
+    }
+}
+
+// Lists every saved process (or nested process) that uses an older version of a saved node than the latest one
+pub fn reference_updates(
+    pool: Arc<Pool<SqliteConnectionManager>>,
+    viewer: &Viewer,
+) -> Result<Vec<ReferenceUpdate>, String> {
+    let nodes: Vec<Node> = fetch_all_nodes(pool)
+        .map_err(|err| format!("Unable to fetch nodes: {:?}", err))?
+        .into_iter()
+        .filter(|node| can_view(node, viewer))
+        .collect();
+
+    let latest_versions: HashMap<String, (GraphNodeInfo, u32)> = nodes
+        .iter()
+        .map(|node| {
+            let node_info = node.node_info.clone().unwrap_or_default();
+            (node_info.id.clone(), (node_info, node.version))
+        })
+        .collect();
+
+    let mut updates = Vec::new();
+
+    for node in &nodes {
+        if let Some((process, _)) = contained_process(node) {
+            collect_updates(
+                &node.node_info.clone().unwrap_or_default(),
+                &process,
+                &latest_versions,
+                &mut updates,
+            );
+        }
+    }
+
+    Ok(updates)
+}
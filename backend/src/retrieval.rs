@@ -0,0 +1,164 @@
+use crate::generated_types::{
+    node_content::NodeContent as NodeContentEnum, value::ValueType, AtomicExecutionLog, Node,
+    Retrieve, StringList, Value,
+};
+use crate::graph::ExecutionContext;
+use crate::sqlite_helper_functions::{fts_query, search_passages};
+use crate::templating::value_to_json;
+
+use colored::*;
+
+use std::collections::HashMap;
+
+pub const DEFAULT_COLLECTION: &str = "default";
+const DEFAULT_TOP_K: u32 = 3;
+// Passages are built out of whole paragraphs (or sentences for very long paragraphs) until they reach about this many characters
+const PASSAGE_LENGTH: usize = 800;
+
+pub fn collection_or_default(collection: &str) -> String {
+    if collection.trim().is_empty() {
+        DEFAULT_COLLECTION.to_string()
+    } else {
+        collection.trim().to_string()
+    }
+}
+
+// Splits the content of a document into passages of roughly PASSAGE_LENGTH characters, keeping paragraphs together where possible
+pub fn split_into_passages(content: &str) -> Vec<String> {
+    let mut pieces: Vec<String> = Vec::new();
+
+    for paragraph in content.split("\n\n") {
+        let paragraph = paragraph.trim();
+
+        if paragraph.is_empty() {
+            continue;
+        }
+
+        if paragraph.chars().count() <= PASSAGE_LENGTH {
+            pieces.push(paragraph.to_string());
+            continue;
+        }
+
+        // Long paragraphs are broken up at the end of their sentences
+        let mut sentence = String::new();
+        for character in paragraph.chars() {
+            sentence.push(character);
+            if matches!(character, '.' | '!' | '?') {
+                pieces.push(sentence.trim().to_string());
+                sentence = String::new();
+            }
+        }
+        if !sentence.trim().is_empty() {
+            pieces.push(sentence.trim().to_string());
+        }
+    }
+
+    let mut passages: Vec<String> = Vec::new();
+    let mut current = String::new();
+
+    for piece in pieces {
+        if !current.is_empty() && current.chars().count() + piece.chars().count() > PASSAGE_LENGTH {
+            passages.push(current);
+            current = String::new();
+        }
+
+        if !current.is_empty() {
+            current.push_str("\n\n");
+        }
+        current.push_str(&piece);
+    }
+
+    if !current.is_empty() {
+        passages.push(current);
+    }
+
+    passages
+}
+
+fn query_text(value: &Value) -> String {
+    match &value.value_type {
+        Some(ValueType::StringValue(s)) => s.clone(),
+        Some(ValueType::StringList(string_list)) => string_list.values.join(" "),
+        _ => value_to_json(value).to_string(),
+    }
+}
+
+pub fn handle_retrieve(
+    node: &Node,
+    variable_definitions: &mut HashMap<String, Value>,
+    context: &ExecutionContext,
+) -> Result<AtomicExecutionLog, String> {
+    let retrieve: Retrieve = match node
+        .node_content
+        .clone()
+        .and_then(|node_content| node_content.node_content)
+    {
+        Some(NodeContentEnum::Retrieve(retrieve)) => retrieve,
+        _ => return Err("The retrieve node doesn't contain any retrieve settings".to_string()),
+    };
+
+    let email = match &context.user_email {
+        Some(email) => email,
+        None => return Err("Documents can only be searched once the user is known".to_string()),
+    };
+
+    let query_variable = match node.input_variables.first() {
+        Some(query_variable) => query_variable,
+        None => return Err("A retrieve node needs an input variable holding the query".to_string()),
+    };
+
+    let output_variable = match node.output_variables.first() {
+        Some(output_variable) => output_variable,
+        None => return Err("A retrieve node needs an output variable for the passages".to_string()),
+    };
+
+    let query = match variable_definitions.get(query_variable) {
+        Some(value) => query_text(value),
+        None => {
+            return Err(format!(
+                "The query variable {} is not defined",
+                query_variable
+            ))
+        }
+    };
+
+    let collection = collection_or_default(&retrieve.collection);
+
+    let top_k = if retrieve.top_k == 0 {
+        DEFAULT_TOP_K
+    } else {
+        retrieve.top_k
+    };
+
+    // Passages containing any of the words of the query
+    let passages = match fts_query(&query, " OR ", false) {
+        Some(fts_query) => {
+            search_passages(context.pool.clone(), email, &collection, &fts_query, top_k)
+                .map_err(|err| format!("Unable to search the documents: {:?}", err))?
+        }
+        None => Vec::new(),
+    };
+
+    println!(
+        "{} {} passages from {}",
+        "Retrieved".green(),
+        passages.len(),
+        collection
+    );
+
+    let value = Value {
+        value_type: Some(ValueType::StringList(StringList { values: passages })),
+    };
+
+    variable_definitions.insert(output_variable.clone(), value.clone());
+
+    let mut response = HashMap::new();
+    response.insert(output_variable.clone(), value);
+
+    Ok(AtomicExecutionLog {
+        prompt: format!("Search {} for: {}", collection, query),
+        response,
+        node_info: node.node_info.clone(),
+        cache_hit: false,
+    })
+}
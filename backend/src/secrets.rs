@@ -0,0 +1,394 @@
+use crate::generated_types::{value::ValueType, AtomicExecutionLog, Execution, StringList, Value};
+use crate::sqlite_helper_functions::fetch_encrypted_secrets;
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use r2d2::Pool;
+use r2d2_sqlite::SqliteConnectionManager;
+use regex::Regex;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::rand::{SecureRandom, SystemRandom};
+
+use std::collections::HashMap;
+use std::env;
+use std::sync::Arc;
+
+// Base64 encoded 32 byte key used to encrypt the secrets of every user
+const ENCRYPTION_KEY_VARIABLE: &str = "SECRETS_ENCRYPTION_KEY";
+
+// The decrypted secrets of a user keyed by their name. These only ever live in memory while an execution is running.
+pub type SecretValues = HashMap<String, String>;
+
+fn encryption_key() -> Result<LessSafeKey, String> {
+    let encoded = env::var(ENCRYPTION_KEY_VARIABLE)
+        .map_err(|_| format!("{} is not set", ENCRYPTION_KEY_VARIABLE))?;
+
+    let key_bytes = STANDARD
+        .decode(encoded.trim())
+        .map_err(|err| format!("{} is not valid base64: {}", ENCRYPTION_KEY_VARIABLE, err))?;
+
+    let unbound_key = UnboundKey::new(&AES_256_GCM, &key_bytes)
+        .map_err(|_| format!("{} must be exactly 32 bytes", ENCRYPTION_KEY_VARIABLE))?;
+
+    Ok(LessSafeKey::new(unbound_key))
+}
+
+// The owner and name are bound to the ciphertext so that a stored secret can't be moved to another user (or renamed) without failing to decrypt
+fn associated_data(email: &str, name: &str) -> Vec<u8> {
+    format!("{}\n{}", email, name).into_bytes()
+}
+
+// Returns the nonce and the ciphertext
+pub fn encrypt_secret(email: &str, name: &str, value: &str) -> Result<(Vec<u8>, Vec<u8>), String> {
+    let key = encryption_key()?;
+
+    let mut nonce_bytes = [0u8; NONCE_LEN];
+    SystemRandom::new()
+        .fill(&mut nonce_bytes)
+        .map_err(|_| "Unable to generate a nonce".to_string())?;
+
+    let mut in_out = value.as_bytes().to_vec();
+
+    key.seal_in_place_append_tag(
+        Nonce::assume_unique_for_key(nonce_bytes),
+        Aad::from(associated_data(email, name)),
+        &mut in_out,
+    )
+    .map_err(|_| format!("Unable to encrypt secret {}", name))?;
+
+    Ok((nonce_bytes.to_vec(), in_out))
+}
+
+pub fn decrypt_secret(
+    email: &str,
+    name: &str,
+    nonce: &[u8],
+    ciphertext: &[u8],
+) -> Result<String, String> {
+    let key = encryption_key()?;
+
+    let nonce_bytes: [u8; NONCE_LEN] = nonce
+        .try_into()
+        .map_err(|_| format!("The stored nonce for secret {} is invalid", name))?;
+
+    let mut in_out = ciphertext.to_vec();
+
+    let plaintext = key
+        .open_in_place(
+            Nonce::assume_unique_for_key(nonce_bytes),
+            Aad::from(associated_data(email, name)),
+            &mut in_out,
+        )
+        .map_err(|_| format!("Unable to decrypt secret {}", name))?;
+
+    String::from_utf8(plaintext.to_vec()).map_err(|_| format!("Secret {} is not valid text", name))
+}
+
+// Secrets are handed to commands as environment variables so their names have to work as one
+pub fn valid_secret_name(name: &str) -> bool {
+    let mut characters = name.chars();
+
+    match characters.next() {
+        Some(first) if first.is_ascii_alphabetic() || first == '_' => {
+            characters.all(|character| character.is_ascii_alphanumeric() || character == '_')
+        }
+        _ => false,
+    }
+}
+
+pub fn load_secrets(
+    auth_pool: &Arc<Pool<SqliteConnectionManager>>,
+    email: &str,
+) -> Result<SecretValues, String> {
+    let encrypted_secrets = fetch_encrypted_secrets(auth_pool, email)
+        .map_err(|err| format!("Unable to fetch secrets: {:?}", err))?;
+
+    let mut secrets = HashMap::new();
+
+    for secret in encrypted_secrets {
+        let value = decrypt_secret(email, &secret.name, &secret.nonce, &secret.ciphertext)?;
+        secrets.insert(secret.name, value);
+    }
+
+    Ok(secrets)
+}
+
+// The environment variables a command runs with, one NAME=value entry per secret
+pub fn secret_environment(secrets: &SecretValues) -> Vec<String> {
+    secrets
+        .iter()
+        .map(|(name, value)| format!("{}={}", name, value))
+        .collect()
+}
+
+fn placeholder(name: &str) -> String {
+    format!("[REDACTED:{}]", name)
+}
+
+// Replaces every secret value in the text with a placeholder naming the secret
+pub fn redact(text: &str, secrets: &SecretValues) -> String {
+    let mut sorted_secrets: Vec<(&String, &String)> = secrets
+        .iter()
+        .filter(|(_, value)| !value.is_empty())
+        .collect();
+
+    // Longer secrets go first so that a secret containing another one is replaced as a whole
+    sorted_secrets.sort_by_key(|(_, value)| std::cmp::Reverse(value.len()));
+
+    let mut redacted = text.to_string();
+
+    for (name, value) in sorted_secrets {
+        redacted = redacted.replace(value.as_str(), &placeholder(name));
+    }
+
+    redacted
+}
+
+// Whether any secret value appears in the text
+pub fn contains_secret(text: &str, secrets: &SecretValues) -> bool {
+    secrets
+        .values()
+        .any(|value| !value.is_empty() && text.contains(value.as_str()))
+}
+
+pub fn redact_value(value: &Value, secrets: &SecretValues) -> Value {
+    let value_type = match &value.value_type {
+        Some(ValueType::StringValue(s)) => Some(ValueType::StringValue(redact(s, secrets))),
+        Some(ValueType::JsonValue(s)) => Some(ValueType::JsonValue(redact(s, secrets))),
+        Some(ValueType::StringList(string_list)) => Some(ValueType::StringList(StringList {
+            values: string_list
+                .values
+                .iter()
+                .map(|item| redact(item, secrets))
+                .collect(),
+        })),
+        other => other.clone(),
+    };
+
+    Value { value_type }
+}
+
+fn redact_values(
+    values: &HashMap<String, Value>,
+    secrets: &SecretValues,
+) -> HashMap<String, Value> {
+    values
+        .iter()
+        .map(|(name, value)| (name.clone(), redact_value(value, secrets)))
+        .collect()
+}
+
+// Everything in an execution that could have picked up a secret value while it ran: the variables and the prompts and responses in its history
+pub fn redact_execution(execution: &Execution, secrets: &SecretValues) -> Execution {
+    if secrets.is_empty() {
+        return execution.clone();
+    }
+
+    let mut redacted = execution.clone();
+
+    redacted.current_variable_definitions =
+        redact_values(&execution.current_variable_definitions, secrets);
+    redacted.initial_variable_definitions =
+        redact_values(&execution.initial_variable_definitions, secrets);
+
+    redacted.atomic_history = execution
+        .atomic_history
+        .iter()
+        .map(|log| redact_log(log, secrets))
+        .collect();
+
+    redacted
+}
+
+// The history of an execution is shown to the user and fed back to the model (in conversations and tool calls), so every log is redacted before it goes in
+pub fn redact_log(log: &AtomicExecutionLog, secrets: &SecretValues) -> AtomicExecutionLog {
+    AtomicExecutionLog {
+        prompt: redact(&log.prompt, secrets),
+        response: redact_values(&log.response, secrets),
+        ..log.clone()
+    }
+}
+
+// The opposite of redact: puts the secret values back in place of their placeholders. Fails when the text names a secret the user doesn't have (anymore), since running with the placeholder would silently give the wrong result.
+pub fn restore(text: &str, secrets: &SecretValues) -> Result<String, String> {
+    let placeholders = Regex::new(r"\[REDACTED:([A-Za-z_][A-Za-z0-9_]*)\]").unwrap();
+
+    if let Some(missing) = placeholders
+        .captures_iter(text)
+        .map(|captures| captures[1].to_string())
+        .find(|name| !secrets.contains_key(name))
+    {
+        return Err(format!(
+            "The secret {} was used but isn't set anymore",
+            missing
+        ));
+    }
+
+    Ok(placeholders
+        .replace_all(text, |captures: &regex::Captures| {
+            secrets[&captures[1]].clone()
+        })
+        .into_owned())
+}
+
+fn restore_value(value: &Value, secrets: &SecretValues) -> Result<Value, String> {
+    let value_type = match &value.value_type {
+        Some(ValueType::StringValue(s)) => Some(ValueType::StringValue(restore(s, secrets)?)),
+        Some(ValueType::JsonValue(s)) => Some(ValueType::JsonValue(restore(s, secrets)?)),
+        Some(ValueType::StringList(string_list)) => Some(ValueType::StringList(StringList {
+            values: string_list
+                .values
+                .iter()
+                .map(|item| restore(item, secrets))
+                .collect::<Result<_, _>>()?,
+        })),
+        other => other.clone(),
+    };
+
+    Ok(Value { value_type })
+}
+
+fn restore_values(
+    values: &HashMap<String, Value>,
+    secrets: &SecretValues,
+) -> Result<HashMap<String, Value>, String> {
+    values
+        .iter()
+        .map(|(name, value)| Ok((name.clone(), restore_value(value, secrets)?)))
+        .collect()
+}
+
+// Stored executions are redacted. Before one is run again the secret values go back into everything redact_execution touched, otherwise the rerun would be fed the placeholders.
+pub fn restore_execution(
+    execution: &Execution,
+    secrets: &SecretValues,
+) -> Result<Execution, String> {
+    let mut restored = execution.clone();
+
+    restored.current_variable_definitions =
+        restore_values(&execution.current_variable_definitions, secrets)?;
+    restored.initial_variable_definitions =
+        restore_values(&execution.initial_variable_definitions, secrets)?;
+
+    for log in restored.atomic_history.iter_mut() {
+        log.prompt = restore(&log.prompt, secrets)?;
+        log.response = restore_values(&log.response, secrets)?;
+    }
+
+    Ok(restored)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secrets(pairs: &[(&str, &str)]) -> SecretValues {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    fn string_value(s: &str) -> Value {
+        Value {
+            value_type: Some(ValueType::StringValue(s.to_string())),
+        }
+    }
+
+    // Every test uses the same key so it doesn't matter which one sets it first
+    fn set_encryption_key() {
+        env::set_var(ENCRYPTION_KEY_VARIABLE, STANDARD.encode([7u8; 32]));
+    }
+
+    #[test]
+    fn longer_secrets_are_redacted_first() {
+        let secrets = secrets(&[("SHORT", "abc"), ("LONG", "abcdef"), ("EMPTY", "")]);
+
+        assert_eq!(
+            redact("token abcdef and abc", &secrets),
+            "token [REDACTED:LONG] and [REDACTED:SHORT]"
+        );
+    }
+
+    #[test]
+    fn restore_undoes_redact() {
+        let secrets = secrets(&[("API_KEY", "sk-123"), ("API_KEY_2", "sk-123456")]);
+        let text = "curl -H 'Authorization: sk-123456' -d sk-123";
+
+        let redacted = redact(text, &secrets);
+        assert!(!contains_secret(&redacted, &secrets));
+        assert_eq!(restore(&redacted, &secrets).unwrap(), text);
+    }
+
+    #[test]
+    fn restore_fails_on_secrets_that_are_gone() {
+        let secrets = secrets(&[("KEPT", "value")]);
+
+        let err = restore("[REDACTED:KEPT] [REDACTED:REMOVED]", &secrets).unwrap_err();
+        assert!(err.contains("REMOVED"));
+
+        // Text that only looks like a placeholder is left alone
+        assert_eq!(
+            restore("[REDACTED:not a name]", &secrets).unwrap(),
+            "[REDACTED:not a name]"
+        );
+    }
+
+    #[test]
+    fn restore_execution_undoes_redact_execution() {
+        let secrets = secrets(&[("TOKEN", "t0k3n")]);
+
+        let mut execution = Execution {
+            execution_id: "execution".to_string(),
+            ..Default::default()
+        };
+        execution
+            .current_variable_definitions
+            .insert("header".to_string(), string_value("Bearer t0k3n"));
+        execution
+            .initial_variable_definitions
+            .insert("token".to_string(), string_value("t0k3n"));
+        execution.atomic_history.push(AtomicExecutionLog {
+            prompt: "Call with t0k3n".to_string(),
+            response: execution.current_variable_definitions.clone(),
+            ..Default::default()
+        });
+
+        let redacted = redact_execution(&execution, &secrets);
+        assert_eq!(
+            redacted.atomic_history[0].prompt,
+            "Call with [REDACTED:TOKEN]"
+        );
+        assert_eq!(
+            redacted.current_variable_definitions["header"],
+            string_value("Bearer [REDACTED:TOKEN]")
+        );
+
+        assert_eq!(restore_execution(&redacted, &secrets).unwrap(), execution);
+    }
+
+    #[test]
+    fn secrets_decrypt_for_their_owner_and_name_only() {
+        set_encryption_key();
+
+        let (nonce, ciphertext) = encrypt_secret("ada@example.com", "TOKEN", "t0k3n").unwrap();
+
+        assert_eq!(
+            decrypt_secret("ada@example.com", "TOKEN", &nonce, &ciphertext).unwrap(),
+            "t0k3n"
+        );
+        assert!(decrypt_secret("bob@example.com", "TOKEN", &nonce, &ciphertext).is_err());
+        assert!(decrypt_secret("ada@example.com", "OTHER", &nonce, &ciphertext).is_err());
+        assert!(
+            decrypt_secret("ada@example.com", "TOKEN", &[0u8; NONCE_LEN], &ciphertext).is_err()
+        );
+    }
+
+    #[test]
+    fn secret_names_have_to_work_as_environment_variables() {
+        assert!(valid_secret_name("API_KEY"));
+        assert!(valid_secret_name("_private2"));
+        assert!(!valid_secret_name("2FA"));
+        assert!(!valid_secret_name("API-KEY"));
+        assert!(!valid_secret_name(""));
+    }
+}
@@ -4,7 +4,11 @@ use std::env;
 impl UserSettings {
     pub fn new() -> Option<UserSettings> {
         let openai_api_key = env::var("OPENAI_API_KEY").unwrap();
+        let language_model = env::var("LANGUAGE_MODEL").unwrap_or_default();
 
-        Some(UserSettings { openai_api_key })
+        Some(UserSettings {
+            openai_api_key,
+            language_model,
+        })
     }
 }
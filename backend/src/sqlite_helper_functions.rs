@@ -1,11 +1,19 @@
 use crate::generated_types::authentication_message::Body as AuthBody;
-use crate::generated_types::{AuthenticationMessage, Node, Secrets};
+use crate::generated_types::{
+    AuditEntry, AuditQuery, AuthenticationMessage, Document, Execution, Node, NodeSearch,
+    NodeSearchResults, NodeVersion, QuarantinedNode, Secrets, Team, TeamInvitation, TeamMember,
+    TeamRoles, Value, Visibility,
+};
+use crate::node_schema::{decode_node, NODE_SCHEMA_VERSION};
+use crate::migrations::{run_migrations, AUTH_MIGRATIONS, MAIN_MIGRATIONS};
 use prost::Message;
 use r2d2::Pool;
 use r2d2_sqlite::SqliteConnectionManager;
 use rusqlite::{params, Connection, Result};
+use std::collections::HashMap;
 use std::env;
 use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 extern crate bcrypt;
 
@@ -15,7 +23,7 @@ extern crate colored;
 
 use colored::*;
 
-pub fn setup_sqlite_db() -> Result<()> {
+pub fn setup_sqlite_db() -> std::result::Result<(), String> {
     println!("Setting up SQLite database...");
 
     // Get the environmental variables required:
@@ -28,16 +36,31 @@ pub fn setup_sqlite_db() -> Result<()> {
         "Opening connection to SQLite at location: {}",
         sqlite_location
     );
-    let conn = Connection::open(sqlite_location)?;
+    let mut conn = Connection::open(&sqlite_location)
+        .map_err(|err| format!("Unable to open {}: {:?}", sqlite_location, err))?;
 
-    println!("Creating nodes table...");
-    create_nodes_table(&conn)?;
+    println!("Migrating the schema...");
+    run_migrations(&mut conn, &sqlite_location, MAIN_MIGRATIONS)?;
+
+    println!("Checking stored nodes...");
+    let (upgraded, quarantined) = upgrade_stored_nodes(&mut conn)
+        .map_err(|err| format!("Unable to check the stored nodes: {:?}", err))?;
+    if upgraded > 0 {
+        println!("{} {}", "Upgraded stored nodes:".green(), upgraded);
+    }
+    if quarantined > 0 {
+        println!(
+            "{} {}",
+            "Quarantined stored nodes that can't be read:".red(),
+            quarantined
+        );
+    }
 
     println!("SQLite DB setup complete.");
     Ok(())
 }
 
-pub fn setup_sqlite_db_auth(sqlite_location: &str) -> Result<()> {
+pub fn setup_sqlite_db_auth(sqlite_location: &str) -> std::result::Result<(), String> {
     println!("Setting up SQLite database...");
 
     // Get the environmental variables required:
@@ -52,10 +75,11 @@ pub fn setup_sqlite_db_auth(sqlite_location: &str) -> Result<()> {
         "Opening connection to SQLite at location: {}",
         sqlite_location
     );
-    let conn = Connection::open(sqlite_location)?;
+    let mut conn = Connection::open(sqlite_location)
+        .map_err(|err| format!("Unable to open {}: {:?}", sqlite_location, err))?;
 
-    println!("Creating pass table...");
-    create_pass_table(&conn)?;
+    println!("Migrating the schema...");
+    run_migrations(&mut conn, sqlite_location, AUTH_MIGRATIONS)?;
 
     println!("SQLite DB setup complete.");
     Ok(())
@@ -223,28 +247,31 @@ pub fn insert_node(pool: Arc<Pool<SqliteConnectionManager>>, node: Node) -> Resu
         Ok(_) => {
             println!("Serialization successful.");
             let id = node.node_info.clone().unwrap().id;
-            let name = node.node_info.unwrap().name;
+            let name = node.node_info.clone().unwrap().name;
             let node_type = node.node_type;
             println!("Inserting serialized node into the database...");
             match
                 connection.execute(
-                    "INSERT OR REPLACE INTO nodes (id, name, type_name, serialized_node) VALUES (?1, ?2, ?3, ?4)",
-                    params![id, name, node_type, serialized_node]
+                    "INSERT OR REPLACE INTO nodes (id, name, node_type, serialized_node, schema_version, owner_email, visibility, team_id) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                    params![id, name, node_type, serialized_node, NODE_SCHEMA_VERSION, node.owner_email, node.visibility, node.team_id]
                 )
             {
                 Ok(_) => {
                     println!("Node insertion successful.");
+                    if let Err(err) = index_node(&connection, &node) {
+                        println!("{}: {:?}", "Unable to index node:".red(), err);
+                    }
                     Ok(())
                 }
                 Err(err) => {
                     println!("{}: {:?}", "Unable to insert node into db:".red(), err);
-                    Ok(())
+                    Err(err)
                 }
             }
         }
         Err(err) => {
             println!("Unable to serialize node{:?}", err);
-            Ok(())
+            Err(rusqlite::Error::ToSqlConversionFailure(Box::new(err)))
         }
     }
 }
@@ -263,27 +290,30 @@ pub fn update_node(pool: Arc<Pool<SqliteConnectionManager>>, node: &Node) -> Res
             let name = node.node_info.clone().unwrap().name;
             println!("Updating node in the database...");
             match connection.execute(
-                "UPDATE nodes SET name = ?1, serialized_node = ?2 WHERE id = ?3",
-                params![name, serialized_node, id],
+                "UPDATE nodes SET name = ?1, serialized_node = ?2, schema_version = ?3, visibility = ?4, owner_email = ?5, team_id = ?6 WHERE id = ?7 AND deleted_at IS NULL",
+                params![name, serialized_node, NODE_SCHEMA_VERSION, node.visibility, node.owner_email, node.team_id, id],
             ) {
                 Ok(count) => {
                     if count > 0 {
                         println!("Node updated successfully");
+                        if let Err(err) = index_node(&connection, node) {
+                            println!("{}: {:?}", "Unable to index node:".red(), err);
+                        }
                         Ok(())
                     } else {
                         println!("No node found with the given ID");
-                        Ok(())
+                        Err(rusqlite::Error::QueryReturnedNoRows)
                     }
                 }
                 Err(err) => {
                     println!("Unable to update node in db: {:?}", err);
-                    Ok(())
+                    Err(err)
                 }
             }
         }
         Err(err) => {
             println!("Unable to serialize node: {:?}", err);
-            Ok(())
+            Err(rusqlite::Error::ToSqlConversionFailure(Box::new(err)))
         }
     }
 }
@@ -293,14 +323,127 @@ pub fn fetch_all_nodes(pool: Arc<Pool<SqliteConnectionManager>>) -> Result<Vec<N
     let connection = pool.get().expect("Failed to get connection from pool");
     println!("Connection retrieved from pool successfully.");
     println!("Preparing SQL statement for fetching all nodes...");
-    let mut stmt = connection.prepare("SELECT serialized_node FROM nodes")?;
+    let mut stmt = connection
+        .prepare("SELECT id, serialized_node, schema_version FROM nodes WHERE deleted_at IS NULL")?;
     println!("Statement prepared successfully.");
 
     println!("Querying database and deserializing nodes...");
+    let row_iter = stmt.query_map([], |row| {
+        let id: String = row.get(0)?;
+        let blob_data: Vec<u8> = row.get(1)?;
+        let schema_version: u32 = row.get(2)?;
+        Ok((id, decode_node(blob_data.as_slice(), schema_version)))
+    })?;
+
+    // A node that can't be read is left out rather than failing the whole list. It is quarantined the next time the server starts.
+    let mut nodes = Vec::new();
+    for row in row_iter {
+        match row? {
+            (_, Ok(node)) => nodes.push(node),
+            (id, Err(err)) => println!("{} {}: {}", "Skipping unreadable node".red(), id, err),
+        }
+    }
+
+    println!("All {:?} node(s) retrieved successfully.", nodes.len());
+    Ok(nodes)
+}
+
+pub fn fetch_node(pool: Arc<Pool<SqliteConnectionManager>>, id: &str) -> Result<Option<Node>> {
+    let connection = pool.get().expect("Failed to get connection from pool");
+
+    let mut stmt = connection
+        .prepare("SELECT serialized_node, schema_version FROM nodes WHERE id = ?1 AND deleted_at IS NULL")?;
+    let mut rows = stmt.query(params![id])?;
+
+    match rows.next()? {
+        Some(row) => {
+            let blob_data: Vec<u8> = row.get(0)?;
+            match decode_node(blob_data.as_slice(), row.get(1)?) {
+                Ok(node) => Ok(Some(node)),
+                Err(err) => {
+                    println!("{}: {:?}", "Unable to deserialize node".red(), err);
+                    Ok(None)
+                }
+            }
+        }
+        None => Ok(None),
+    }
+}
+
+// Reads every stored node (soft deleted ones included). Nodes of an older schema version are upgraded and stored again, nodes that can't be read are moved to the quarantined_nodes table. Returns how many nodes were upgraded and quarantined.
+pub fn upgrade_stored_nodes(conn: &mut Connection) -> Result<(usize, usize)> {
+    let transaction = conn.transaction()?;
+
+    let rows: Vec<(String, Option<String>, u32, Vec<u8>)> = {
+        let mut stmt =
+            transaction.prepare("SELECT id, name, schema_version, serialized_node FROM nodes")?;
+        let row_iter = stmt.query_map([], |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+        })?;
+        row_iter.collect::<Result<_>>()?
+    };
+
+    let quarantined_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs() as i64)
+        .unwrap_or(0);
+
+    let mut upgraded = 0;
+    let mut quarantined = 0;
+
+    for (id, name, schema_version, blob_data) in rows {
+        match decode_node(blob_data.as_slice(), schema_version) {
+            Ok(node) if schema_version < NODE_SCHEMA_VERSION => {
+                let mut serialized_node = vec![];
+                node.encode(&mut serialized_node)
+                    .expect("Failed to serialize node");
+
+                transaction.execute(
+                    "UPDATE nodes SET serialized_node = ?1, schema_version = ?2 WHERE id = ?3",
+                    params![serialized_node, NODE_SCHEMA_VERSION, id],
+                )?;
+                upgraded += 1;
+            }
+            Ok(_) => {}
+            Err(err) => {
+                println!("{} {}: {}", "Quarantining node".red(), id, err);
+
+                transaction.execute(
+                    "INSERT INTO quarantined_nodes (node_id, name, schema_version, serialized_node, error, quarantined_at)
+                    VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                    params![id, name, schema_version, blob_data, err, quarantined_at],
+                )?;
+                transaction.execute("DELETE FROM nodes WHERE id = ?1", params![id])?;
+                unindex_node(&transaction, &id)?;
+                quarantined += 1;
+            }
+        }
+    }
+
+    transaction.commit()?;
+
+    Ok((upgraded, quarantined))
+}
+
+// Most recently quarantined first
+pub fn fetch_quarantined_nodes(
+    pool: Arc<Pool<SqliteConnectionManager>>,
+) -> Result<Vec<QuarantinedNode>> {
+    let connection = pool.get().expect("Failed to get connection from pool");
+
+    let mut stmt = connection.prepare(
+        "SELECT node_id, name, schema_version, error, quarantined_at FROM quarantined_nodes
+        ORDER BY quarantined_at DESC",
+    )?;
+
     let node_iter = stmt.query_map([], |row| {
-        let blob_data: Vec<u8> = row.get(0)?;
-        let node = Node::decode(blob_data.as_slice()).expect("Failed to deserialize node");
-        Ok(node)
+        Ok(QuarantinedNode {
+            node_id: row.get(0)?,
+            name: row.get::<_, Option<String>>(1)?.unwrap_or_default(),
+            schema_version: row.get(2)?,
+            error: row.get(3)?,
+            quarantined_at: row.get(4)?,
+        })
     })?;
 
     let mut nodes = Vec::new();
@@ -308,6 +451,1330 @@ pub fn fetch_all_nodes(pool: Arc<Pool<SqliteConnectionManager>>) -> Result<Vec<N
         nodes.push(node?);
     }
 
-    println!("All {:?} node(s) retrieved successfully.", nodes.len());
     Ok(nodes)
 }
+
+// The tables search_nodes filters on. They are kept up to date whenever a node is saved.
+pub fn create_node_search_tables(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS node_tags (
+            node_id TEXT NOT NULL,
+            tag TEXT NOT NULL,
+            PRIMARY KEY (node_id, tag)
+        );
+        CREATE INDEX IF NOT EXISTS node_tags_by_tag ON node_tags (tag);
+        CREATE TABLE IF NOT EXISTS node_variables (
+            node_id TEXT NOT NULL,
+            variable TEXT NOT NULL,
+            is_output INTEGER NOT NULL,
+            PRIMARY KEY (node_id, variable, is_output)
+        );
+        CREATE INDEX IF NOT EXISTS node_variables_by_variable ON node_variables (variable, is_output);
+        CREATE VIRTUAL TABLE IF NOT EXISTS node_text USING fts5(
+            node_id UNINDEXED,
+            name,
+            description
+        );
+        CREATE INDEX IF NOT EXISTS nodes_by_name ON nodes (name);",
+    )
+}
+
+// The users a shared node is visible to, besides its owner
+pub fn create_node_shares_table(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS node_shares (
+            node_id TEXT NOT NULL,
+            email TEXT NOT NULL,
+            PRIMARY KEY (node_id, email)
+        );
+        CREATE INDEX IF NOT EXISTS node_shares_by_email ON node_shares (email);",
+    )
+}
+
+pub fn normalize_tag(tag: &str) -> String {
+    tag.trim().to_lowercase()
+}
+
+pub fn unindex_node(conn: &Connection, node_id: &str) -> Result<()> {
+    conn.execute("DELETE FROM node_tags WHERE node_id = ?1", params![node_id])?;
+    conn.execute("DELETE FROM node_variables WHERE node_id = ?1", params![node_id])?;
+    conn.execute("DELETE FROM node_text WHERE node_id = ?1", params![node_id])?;
+    conn.execute("DELETE FROM node_shares WHERE node_id = ?1", params![node_id])?;
+    Ok(())
+}
+
+pub fn index_node(conn: &Connection, node: &Node) -> Result<()> {
+    let node_info = node.node_info.clone().unwrap_or_default();
+
+    unindex_node(conn, &node_info.id)?;
+
+    for tag in node.tags.iter().map(|tag| normalize_tag(tag)) {
+        if !tag.is_empty() {
+            conn.execute(
+                "INSERT OR IGNORE INTO node_tags (node_id, tag) VALUES (?1, ?2)",
+                params![node_info.id, tag],
+            )?;
+        }
+    }
+
+    for (variables, is_output) in [(&node.input_variables, false), (&node.output_variables, true)] {
+        for variable in variables {
+            conn.execute(
+                "INSERT OR IGNORE INTO node_variables (node_id, variable, is_output) VALUES (?1, ?2, ?3)",
+                params![node_info.id, variable, is_output],
+            )?;
+        }
+    }
+
+    conn.execute(
+        "INSERT INTO node_text (node_id, name, description) VALUES (?1, ?2, ?3)",
+        params![node_info.id, node_info.name, node_info.description],
+    )?;
+
+    for email in &node.shared_with {
+        conn.execute(
+            "INSERT OR IGNORE INTO node_shares (node_id, email) VALUES (?1, ?2)",
+            params![node_info.id, email.trim().to_lowercase()],
+        )?;
+    }
+
+    Ok(())
+}
+
+// Turns free text into an FTS5 query, joining its words with the given operator (" OR " for any of them, " " for all of them). Every word is quoted so that characters with a meaning in the FTS5 syntax can't break the query. With prefix_last the last word may only be the start of a word.
+pub fn fts_query(text: &str, join: &str, prefix_last: bool) -> Option<String> {
+    let terms: Vec<String> = text
+        .split(|character: char| !character.is_alphanumeric())
+        .filter(|term| !term.is_empty())
+        .map(|term| format!("\"{}\"", term.to_lowercase()))
+        .collect();
+
+    if terms.is_empty() {
+        None
+    } else if prefix_last {
+        Some(format!("{}*", terms.join(join)))
+    } else {
+        Some(terms.join(join))
+    }
+}
+
+const DEFAULT_PAGE_SIZE: u32 = 50;
+const MAX_PAGE_SIZE: u32 = 200;
+
+// Only finds the nodes the viewer can see (the same rules as access::can_view)
+pub fn search_nodes(
+    pool: Arc<Pool<SqliteConnectionManager>>,
+    search: &NodeSearch,
+    viewer_email: &str,
+) -> Result<NodeSearchResults> {
+    let connection = pool.get().expect("Failed to get connection from pool");
+
+    let mut conditions = vec!["n.deleted_at IS NULL".to_string()];
+    let mut values: Vec<Box<dyn rusqlite::ToSql>> = Vec::new();
+
+    values.push(Box::new(viewer_email.trim().to_lowercase()));
+    conditions.push(format!(
+        "((n.team_id = '' AND (n.owner_email = '' OR LOWER(n.owner_email) = ?1))
+        OR n.team_id IN (SELECT team_id FROM team_members WHERE email = ?1)
+        OR n.visibility = {}
+        OR (n.visibility = {} AND EXISTS (SELECT 1 FROM node_shares s WHERE s.node_id = n.id AND s.email = ?1)))",
+        Visibility::Public as i32,
+        Visibility::Shared as i32
+    ));
+
+    // Every word has to appear, the last one may only be the start of a word since it is probably still being typed
+    if let Some(query) = fts_query(&search.text, " ", true) {
+        values.push(Box::new(query));
+        conditions.push(format!(
+            "n.id IN (SELECT node_id FROM node_text WHERE node_text MATCH ?{})",
+            values.len()
+        ));
+    }
+
+    if !search.node_types.is_empty() {
+        let mut placeholders = Vec::new();
+        for node_type in &search.node_types {
+            values.push(Box::new(*node_type));
+            placeholders.push(format!("?{}", values.len()));
+        }
+        conditions.push(format!("n.node_type IN ({})", placeholders.join(", ")));
+    }
+
+    let mut tags: Vec<String> = search
+        .tags
+        .iter()
+        .map(|tag| normalize_tag(tag))
+        .filter(|tag| !tag.is_empty())
+        .collect();
+    tags.sort();
+    tags.dedup();
+
+    if !tags.is_empty() {
+        let mut placeholders = Vec::new();
+        for tag in &tags {
+            values.push(Box::new(tag.clone()));
+            placeholders.push(format!("?{}", values.len()));
+        }
+        conditions.push(format!(
+            "(SELECT COUNT(*) FROM node_tags t WHERE t.node_id = n.id AND t.tag IN ({})) = {}",
+            placeholders.join(", "),
+            tags.len()
+        ));
+    }
+
+    for (variable, is_output) in [(&search.input_variable, false), (&search.output_variable, true)] {
+        if !variable.is_empty() {
+            values.push(Box::new(variable.clone()));
+            conditions.push(format!(
+                "EXISTS (SELECT 1 FROM node_variables v WHERE v.node_id = n.id AND v.variable = ?{} AND v.is_output = {})",
+                values.len(),
+                is_output as i32
+            ));
+        }
+    }
+
+    let filter = conditions.join(" AND ");
+
+    let total_count: u32 = connection.query_row(
+        &format!("SELECT COUNT(*) FROM nodes n WHERE {}", filter),
+        rusqlite::params_from_iter(values.iter()),
+        |row| row.get(0),
+    )?;
+
+    let page_size = match search.page_size {
+        0 => DEFAULT_PAGE_SIZE,
+        page_size => page_size.min(MAX_PAGE_SIZE),
+    };
+
+    let mut stmt = connection.prepare(&format!(
+        "SELECT n.id, n.serialized_node, n.schema_version FROM nodes n WHERE {}
+        ORDER BY n.name COLLATE NOCASE, n.id LIMIT {} OFFSET {}",
+        filter,
+        page_size,
+        search.page as u64 * page_size as u64
+    ))?;
+
+    let row_iter = stmt.query_map(rusqlite::params_from_iter(values.iter()), |row| {
+        let id: String = row.get(0)?;
+        let blob_data: Vec<u8> = row.get(1)?;
+        let schema_version: u32 = row.get(2)?;
+        Ok((id, decode_node(blob_data.as_slice(), schema_version)))
+    })?;
+
+    let mut nodes = Vec::new();
+    for row in row_iter {
+        match row? {
+            (_, Ok(node)) => nodes.push(node),
+            (id, Err(err)) => println!("{} {}: {}", "Skipping unreadable node".red(), id, err),
+        }
+    }
+
+    Ok(NodeSearchResults {
+        nodes,
+        total_count,
+        page: search.page,
+        page_size,
+    })
+}
+
+// Every saved version of every node. Rows are only ever added so that any earlier version can be looked at (or rolled back to) later.
+pub fn create_node_versions_table(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS node_versions (
+            node_id TEXT NOT NULL,
+            version INTEGER NOT NULL,
+            author TEXT NOT NULL,
+            created_at INTEGER NOT NULL,
+            change_note TEXT NOT NULL,
+            serialized_node BLOB NOT NULL,
+            PRIMARY KEY (node_id, version)
+        )",
+        [],
+    )?;
+    Ok(())
+}
+
+// Fails if the version has already been stored, versions are never overwritten
+pub fn insert_node_version(
+    pool: Arc<Pool<SqliteConnectionManager>>,
+    node_version: &NodeVersion,
+) -> Result<()> {
+    let connection = pool.get().expect("Failed to get connection from pool");
+
+    let mut serialized_node = vec![];
+    if let Some(node) = &node_version.node {
+        node.encode(&mut serialized_node)
+            .expect("Failed to serialize node");
+    }
+
+    connection.execute(
+        "INSERT INTO node_versions (node_id, version, author, created_at, change_note, serialized_node, schema_version)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        params![
+            node_version.node_id,
+            node_version.version,
+            node_version.author,
+            node_version.created_at,
+            node_version.change_note,
+            serialized_node,
+            NODE_SCHEMA_VERSION
+        ],
+    )?;
+
+    Ok(())
+}
+
+fn row_to_node_version(row: &rusqlite::Row, with_node: bool) -> Result<NodeVersion> {
+    let node = if with_node {
+        let blob_data: Vec<u8> = row.get(5)?;
+        match decode_node(blob_data.as_slice(), row.get(6)?) {
+            Ok(node) => Some(node),
+            Err(err) => {
+                println!("{}: {:?}", "Unable to deserialize node version".red(), err);
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    Ok(NodeVersion {
+        node_id: row.get(0)?,
+        version: row.get(1)?,
+        author: row.get(2)?,
+        created_at: row.get(3)?,
+        change_note: row.get(4)?,
+        node,
+    })
+}
+
+// Newest version first. The nodes themselves are left out to keep the list small.
+pub fn fetch_node_versions(
+    pool: Arc<Pool<SqliteConnectionManager>>,
+    node_id: &str,
+) -> Result<Vec<NodeVersion>> {
+    let connection = pool.get().expect("Failed to get connection from pool");
+
+    let mut stmt = connection.prepare(
+        "SELECT node_id, version, author, created_at, change_note FROM node_versions
+        WHERE node_id = ?1 ORDER BY version DESC",
+    )?;
+
+    let version_iter = stmt.query_map(params![node_id], |row| row_to_node_version(row, false))?;
+
+    let mut versions = Vec::new();
+    for version in version_iter {
+        versions.push(version?);
+    }
+
+    Ok(versions)
+}
+
+pub fn fetch_node_version(
+    pool: Arc<Pool<SqliteConnectionManager>>,
+    node_id: &str,
+    version: u32,
+) -> Result<Option<NodeVersion>> {
+    let connection = pool.get().expect("Failed to get connection from pool");
+
+    let mut stmt = connection.prepare(
+        "SELECT node_id, version, author, created_at, change_note, serialized_node, schema_version
+        FROM node_versions WHERE node_id = ?1 AND version = ?2",
+    )?;
+    let mut rows = stmt.query(params![node_id, version])?;
+
+    match rows.next()? {
+        Some(row) => Ok(Some(row_to_node_version(row, true)?)),
+        None => Ok(None),
+    }
+}
+
+// The highest version in the history of the node, 0 when there is none. Soft deleted nodes keep their history.
+pub fn latest_recorded_version(
+    pool: Arc<Pool<SqliteConnectionManager>>,
+    node_id: &str,
+) -> Result<u32> {
+    let connection = pool.get().expect("Failed to get connection from pool");
+
+    connection.query_row(
+        "SELECT COALESCE(MAX(version), 0) FROM node_versions WHERE node_id = ?1",
+        params![node_id],
+        |row| row.get(0),
+    )
+}
+
+// Soft deletes leave a tombstone so that the node can be brought back with a rollback. Otherwise the node is removed for good. The version history is append-only, so it is kept either way.
+pub fn delete_nodes(
+    pool: Arc<Pool<SqliteConnectionManager>>,
+    node_ids: &[String],
+    soft_delete: bool,
+) -> Result<()> {
+    let mut connection = pool.get().expect("Failed to get connection from pool");
+
+    let deleted_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs() as i64)
+        .unwrap_or(0);
+
+    let transaction = connection.transaction()?;
+
+    for node_id in node_ids {
+        if soft_delete {
+            transaction.execute(
+                "UPDATE nodes SET deleted_at = ?1 WHERE id = ?2 AND deleted_at IS NULL",
+                params![deleted_at, node_id],
+            )?;
+        } else {
+            transaction.execute("DELETE FROM nodes WHERE id = ?1", params![node_id])?;
+            unindex_node(&transaction, node_id)?;
+        }
+    }
+
+    transaction.commit()?;
+
+    Ok(())
+}
+
+pub fn create_executions_table(conn: &Connection) -> Result<()> {
+    println!("Executing statement to create executions table if it does not exist...");
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS executions (
+            execution_id TEXT PRIMARY KEY,
+            created_at INTEGER,
+            serialized_execution BLOB
+        )",
+        [],
+    )?;
+    println!("Executions table created successfully.");
+    Ok(())
+}
+
+// An execution belongs to whoever ran it, and to the team whose library it was run from (if any)
+pub struct StoredExecution {
+    pub execution: Execution,
+    pub executor_email: String,
+    pub team_id: String,
+}
+
+// Fails when the id is already taken, an execution that has been stored is never written over
+pub fn insert_execution(
+    pool: Arc<Pool<SqliteConnectionManager>>,
+    execution: &Execution,
+    executor_email: &str,
+    team_id: &str,
+) -> Result<()> {
+    println!("Storing an execution...");
+    let connection = pool.get().expect("Failed to get connection from pool");
+    println!("Connection obtained from pool successfully.");
+
+    let mut serialized_execution = vec![];
+    match execution.encode(&mut serialized_execution) {
+        Ok(_) => {
+            let created_at = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|duration| duration.as_secs() as i64)
+                .unwrap_or(0);
+
+            match connection.execute(
+                "INSERT INTO executions (execution_id, created_at, serialized_execution, executor_email, team_id) VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![
+                    execution.execution_id,
+                    created_at,
+                    serialized_execution,
+                    executor_email.trim().to_lowercase(),
+                    team_id
+                ],
+            ) {
+                Ok(_) => {
+                    println!("Execution stored successfully.");
+                    Ok(())
+                }
+                Err(err) => {
+                    println!("{}: {:?}", "Unable to store execution in db:".red(), err);
+                    Err(err)
+                }
+            }
+        }
+        Err(err) => {
+            println!("Unable to serialize execution: {:?}", err);
+            Ok(())
+        }
+    }
+}
+
+pub fn fetch_execution(
+    pool: Arc<Pool<SqliteConnectionManager>>,
+    execution_id: &str,
+) -> Result<Option<StoredExecution>> {
+    println!("Fetching execution: {}", execution_id);
+    let connection = pool.get().expect("Failed to get connection from pool");
+
+    let mut stmt = connection.prepare(
+        "SELECT serialized_execution, executor_email, team_id FROM executions WHERE execution_id = ?1",
+    )?;
+    let mut rows = stmt.query(params![execution_id])?;
+
+    match rows.next()? {
+        Some(row) => {
+            let blob_data: Vec<u8> = row.get(0)?;
+            match Execution::decode(blob_data.as_slice()) {
+                Ok(execution) => Ok(Some(StoredExecution {
+                    execution,
+                    executor_email: row.get(1)?,
+                    team_id: row.get(2)?,
+                })),
+                Err(err) => {
+                    println!("{}: {:?}", "Unable to deserialize execution".red(), err);
+                    Ok(None)
+                }
+            }
+        }
+        None => Ok(None),
+    }
+}
+
+// Only the executions the user ran themselves and those of the teams they are a member of
+pub fn fetch_all_executions(
+    pool: Arc<Pool<SqliteConnectionManager>>,
+    email: &str,
+) -> Result<Vec<Execution>> {
+    println!("Attempting to retrieve all executions...");
+    let connection = pool.get().expect("Failed to get connection from pool");
+
+    let mut stmt = connection.prepare(
+        "SELECT serialized_execution FROM executions
+        WHERE (executor_email != '' AND executor_email = ?1)
+            OR (team_id != '' AND team_id IN (SELECT team_id FROM team_members WHERE email = ?1))
+        ORDER BY created_at DESC",
+    )?;
+    let blob_iter = stmt.query_map(params![email.trim().to_lowercase()], |row| {
+        row.get::<_, Vec<u8>>(0)
+    })?;
+
+    let mut executions = Vec::new();
+    for blob_data in blob_iter {
+        match Execution::decode(blob_data?.as_slice()) {
+            Ok(execution) => executions.push(execution),
+            Err(err) => {
+                println!("{}: {:?}", "Skipping execution that can't be deserialized".red(), err);
+            }
+        }
+    }
+
+    println!("All {:?} execution(s) retrieved successfully.", executions.len());
+    Ok(executions)
+}
+
+pub fn create_response_cache_table(conn: &Connection) -> Result<()> {
+    println!("Executing statement to create response cache table if it does not exist...");
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS response_cache (
+            model TEXT,
+            system_prompt TEXT,
+            prompt TEXT,
+            output_variables TEXT,
+            response TEXT,
+            created_at INTEGER,
+            PRIMARY KEY (model, system_prompt, prompt, output_variables)
+        )",
+        [],
+    )?;
+    println!("Response cache table created successfully.");
+    Ok(())
+}
+
+// Every user gets a cache of their own, a response can depend on things that differ between users even when the prompts look the same
+pub fn key_response_cache_by_owner(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        "DROP TABLE IF EXISTS response_cache;
+        CREATE TABLE response_cache (
+            owner_email TEXT NOT NULL,
+            model TEXT,
+            system_prompt TEXT,
+            prompt TEXT,
+            output_variables TEXT,
+            response TEXT,
+            created_at INTEGER,
+            PRIMARY KEY (owner_email, model, system_prompt, prompt, output_variables)
+        );",
+    )
+}
+
+pub fn fetch_cached_response(
+    pool: Arc<Pool<SqliteConnectionManager>>,
+    owner_email: &str,
+    model: &str,
+    system_prompt: &str,
+    prompt: &str,
+    output_variables: &str,
+) -> Result<Option<String>> {
+    let connection = pool.get().expect("Failed to get connection from pool");
+
+    let mut stmt = connection.prepare(
+        "SELECT response FROM response_cache WHERE owner_email = ?1 AND model = ?2 AND system_prompt = ?3 AND prompt = ?4 AND output_variables = ?5",
+    )?;
+    let mut rows = stmt.query(params![owner_email, model, system_prompt, prompt, output_variables])?;
+
+    match rows.next()? {
+        Some(row) => Ok(Some(row.get(0)?)),
+        None => Ok(None),
+    }
+}
+
+pub fn insert_cached_response(
+    pool: Arc<Pool<SqliteConnectionManager>>,
+    owner_email: &str,
+    model: &str,
+    system_prompt: &str,
+    prompt: &str,
+    output_variables: &str,
+    response: &str,
+) -> Result<()> {
+    let connection = pool.get().expect("Failed to get connection from pool");
+
+    let created_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs() as i64)
+        .unwrap_or(0);
+
+    connection.execute(
+        "INSERT OR REPLACE INTO response_cache (owner_email, model, system_prompt, prompt, output_variables, response, created_at) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        params![owner_email, model, system_prompt, prompt, output_variables, response, created_at],
+    )?;
+
+    println!("Response stored in the cache.");
+    Ok(())
+}
+
+// Secrets live in the auth database next to the passwords. Only the encrypted value is ever written to disk.
+pub fn create_secrets_table(conn: &Connection) -> Result<()> {
+    println!("Executing statement to create secrets table if it does not exist...");
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS secrets (
+            email TEXT,
+            name TEXT,
+            description TEXT,
+            nonce BLOB,
+            ciphertext BLOB,
+            updated_at INTEGER,
+            PRIMARY KEY (email, name)
+        )",
+        [],
+    )?;
+    println!("Secrets table created successfully.");
+    Ok(())
+}
+
+pub struct EncryptedSecret {
+    pub name: String,
+    pub description: String,
+    pub nonce: Vec<u8>,
+    pub ciphertext: Vec<u8>,
+}
+
+pub fn upsert_secret(
+    pool: &Arc<Pool<SqliteConnectionManager>>,
+    email: &str,
+    secret: &EncryptedSecret,
+) -> Result<()> {
+    let connection = pool.get().expect("Failed to get connection from pool");
+
+    let updated_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs() as i64)
+        .unwrap_or(0);
+
+    connection.execute(
+        "INSERT OR REPLACE INTO secrets (email, name, description, nonce, ciphertext, updated_at) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        params![
+            email,
+            secret.name,
+            secret.description,
+            secret.nonce,
+            secret.ciphertext,
+            updated_at
+        ],
+    )?;
+
+    println!("Secret {} stored.", secret.name);
+    Ok(())
+}
+
+pub fn fetch_encrypted_secrets(
+    pool: &Arc<Pool<SqliteConnectionManager>>,
+    email: &str,
+) -> Result<Vec<EncryptedSecret>> {
+    let connection = pool.get().expect("Failed to get connection from pool");
+
+    let mut stmt = connection.prepare(
+        "SELECT name, description, nonce, ciphertext FROM secrets WHERE email = ?1 ORDER BY name",
+    )?;
+
+    let secrets = stmt
+        .query_map(params![email], |row| {
+            Ok(EncryptedSecret {
+                name: row.get(0)?,
+                description: row.get(1)?,
+                nonce: row.get(2)?,
+                ciphertext: row.get(3)?,
+            })
+        })?
+        .collect::<Result<Vec<EncryptedSecret>>>()?;
+
+    Ok(secrets)
+}
+
+// Returns whether there was a secret to delete
+pub fn delete_secret(
+    pool: &Arc<Pool<SqliteConnectionManager>>,
+    email: &str,
+    name: &str,
+) -> Result<bool> {
+    let connection = pool.get().expect("Failed to get connection from pool");
+
+    let deleted = connection.execute(
+        "DELETE FROM secrets WHERE email = ?1 AND name = ?2",
+        params![email, name],
+    )?;
+
+    Ok(deleted > 0)
+}
+
+// Values written by memory nodes. The value is an encoded Value so that lists and objects come back exactly as they went in.
+pub fn create_memories_table(conn: &Connection) -> Result<()> {
+    println!("Executing statement to create memories table if it does not exist...");
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS memories (
+            email TEXT,
+            namespace TEXT,
+            key TEXT,
+            value BLOB,
+            updated_at INTEGER,
+            PRIMARY KEY (email, namespace, key)
+        )",
+        [],
+    )?;
+    println!("Memories table created successfully.");
+    Ok(())
+}
+
+pub fn write_memory(
+    pool: Arc<Pool<SqliteConnectionManager>>,
+    email: &str,
+    namespace: &str,
+    key: &str,
+    value: &Value,
+) -> Result<()> {
+    let connection = pool.get().expect("Failed to get connection from pool");
+
+    let updated_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs() as i64)
+        .unwrap_or(0);
+
+    connection.execute(
+        "INSERT OR REPLACE INTO memories (email, namespace, key, value, updated_at) VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![email, namespace, key, value.encode_to_vec(), updated_at],
+    )?;
+
+    Ok(())
+}
+
+pub fn read_memory(
+    pool: Arc<Pool<SqliteConnectionManager>>,
+    email: &str,
+    namespace: &str,
+    key: &str,
+) -> Result<Option<Value>> {
+    let connection = pool.get().expect("Failed to get connection from pool");
+
+    let mut stmt = connection
+        .prepare("SELECT value FROM memories WHERE email = ?1 AND namespace = ?2 AND key = ?3")?;
+    let mut rows = stmt.query(params![email, namespace, key])?;
+
+    match rows.next()? {
+        Some(row) => {
+            let encoded_value: Vec<u8> = row.get(0)?;
+            match Value::decode(encoded_value.as_slice()) {
+                Ok(value) => Ok(Some(value)),
+                Err(err) => {
+                    println!("{}: {:?}", "Unable to decode memory".red(), err);
+                    Ok(None)
+                }
+            }
+        }
+        None => Ok(None),
+    }
+}
+
+// Documents are kept whole in the documents table while their passages go into an FTS5 index that retrieve nodes search
+pub fn create_documents_tables(conn: &Connection) -> Result<()> {
+    println!("Executing statement to create documents tables if they do not exist...");
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS documents (
+            document_id TEXT PRIMARY KEY,
+            email TEXT,
+            collection TEXT,
+            title TEXT,
+            content TEXT,
+            created_at INTEGER
+        )",
+        [],
+    )?;
+    conn.execute(
+        "CREATE VIRTUAL TABLE IF NOT EXISTS passages USING fts5(
+            content,
+            document_id UNINDEXED,
+            email UNINDEXED,
+            collection UNINDEXED,
+            position UNINDEXED
+        )",
+        [],
+    )?;
+    println!("Documents tables created successfully.");
+    Ok(())
+}
+
+// Stores the document (replacing any earlier version with the same id) along with its passages
+pub fn insert_document(
+    pool: Arc<Pool<SqliteConnectionManager>>,
+    email: &str,
+    document: &Document,
+    passages: &[String],
+) -> Result<()> {
+    let mut connection = pool.get().expect("Failed to get connection from pool");
+
+    let created_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs() as i64)
+        .unwrap_or(0);
+
+    let transaction = connection.transaction()?;
+
+    transaction.execute(
+        "DELETE FROM passages WHERE document_id = ?1",
+        params![document.document_id],
+    )?;
+
+    transaction.execute(
+        "INSERT OR REPLACE INTO documents (document_id, email, collection, title, content, created_at) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        params![
+            document.document_id,
+            email,
+            document.collection,
+            document.title,
+            document.content,
+            created_at
+        ],
+    )?;
+
+    for (position, passage) in passages.iter().enumerate() {
+        transaction.execute(
+            "INSERT INTO passages (content, document_id, email, collection, position) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                passage,
+                document.document_id,
+                email,
+                document.collection,
+                position as i64
+            ],
+        )?;
+    }
+
+    transaction.commit()?;
+
+    println!("Document {} stored with {} passages.", document.document_id, passages.len());
+    Ok(())
+}
+
+// Returns whether there was a document to delete
+pub fn delete_document(
+    pool: Arc<Pool<SqliteConnectionManager>>,
+    email: &str,
+    document_id: &str,
+) -> Result<bool> {
+    let mut connection = pool.get().expect("Failed to get connection from pool");
+
+    let transaction = connection.transaction()?;
+
+    let deleted = transaction.execute(
+        "DELETE FROM documents WHERE document_id = ?1 AND email = ?2",
+        params![document_id, email],
+    )?;
+
+    transaction.execute(
+        "DELETE FROM passages WHERE document_id = ?1 AND email = ?2",
+        params![document_id, email],
+    )?;
+
+    transaction.commit()?;
+
+    Ok(deleted > 0)
+}
+
+pub fn fetch_documents(
+    pool: Arc<Pool<SqliteConnectionManager>>,
+    email: &str,
+) -> Result<Vec<Document>> {
+    let connection = pool.get().expect("Failed to get connection from pool");
+
+    let mut stmt = connection.prepare(
+        "SELECT document_id, collection, title, content FROM documents WHERE email = ?1 ORDER BY created_at",
+    )?;
+
+    let documents = stmt
+        .query_map(params![email], |row| {
+            Ok(Document {
+                document_id: row.get(0)?,
+                collection: row.get(1)?,
+                title: row.get(2)?,
+                content: row.get(3)?,
+            })
+        })?
+        .collect::<Result<Vec<Document>>>()?;
+
+    Ok(documents)
+}
+
+// The query has to be in FTS5 syntax, the passages come back best match first
+pub fn search_passages(
+    pool: Arc<Pool<SqliteConnectionManager>>,
+    email: &str,
+    collection: &str,
+    query: &str,
+    limit: u32,
+) -> Result<Vec<String>> {
+    let connection = pool.get().expect("Failed to get connection from pool");
+
+    let mut stmt = connection.prepare(
+        "SELECT content FROM passages WHERE passages MATCH ?1 AND email = ?2 AND collection = ?3 ORDER BY bm25(passages) LIMIT ?4",
+    )?;
+
+    let passages = stmt
+        .query_map(params![query, email, collection, limit], |row| row.get(0))?
+        .collect::<Result<Vec<String>>>()?;
+
+    Ok(passages)
+}
+
+// The library of a team is every node with its team_id
+pub fn create_teams_tables(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS teams (
+            team_id TEXT PRIMARY KEY,
+            name TEXT NOT NULL,
+            created_by TEXT NOT NULL,
+            created_at INTEGER NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS team_members (
+            team_id TEXT NOT NULL,
+            email TEXT NOT NULL,
+            role INTEGER NOT NULL,
+            joined_at INTEGER NOT NULL,
+            PRIMARY KEY (team_id, email)
+        );
+        CREATE INDEX IF NOT EXISTS team_members_by_email ON team_members (email);
+        CREATE TABLE IF NOT EXISTS team_invitations (
+            team_id TEXT NOT NULL,
+            email TEXT NOT NULL,
+            role INTEGER NOT NULL,
+            invited_by TEXT NOT NULL,
+            invited_at INTEGER NOT NULL,
+            PRIMARY KEY (team_id, email)
+        );",
+    )
+}
+
+// The team starts out with its creator as its only admin
+pub fn insert_team(
+    pool: Arc<Pool<SqliteConnectionManager>>,
+    team_id: &str,
+    name: &str,
+    admin_email: &str,
+) -> Result<()> {
+    let mut connection = pool.get().expect("Failed to get connection from pool");
+
+    let created_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs() as i64)
+        .unwrap_or(0);
+
+    let transaction = connection.transaction()?;
+
+    transaction.execute(
+        "INSERT INTO teams (team_id, name, created_by, created_at) VALUES (?1, ?2, ?3, ?4)",
+        params![team_id, name, admin_email, created_at],
+    )?;
+    transaction.execute(
+        "INSERT INTO team_members (team_id, email, role, joined_at) VALUES (?1, ?2, ?3, ?4)",
+        params![team_id, admin_email, TeamRoles::TeamAdmin as i32, created_at],
+    )?;
+
+    transaction.commit()
+}
+
+pub fn rename_team(
+    pool: Arc<Pool<SqliteConnectionManager>>,
+    team_id: &str,
+    name: &str,
+) -> Result<()> {
+    let connection = pool.get().expect("Failed to get connection from pool");
+
+    connection.execute(
+        "UPDATE teams SET name = ?1 WHERE team_id = ?2",
+        params![name, team_id],
+    )?;
+    Ok(())
+}
+
+pub fn delete_team(pool: Arc<Pool<SqliteConnectionManager>>, team_id: &str) -> Result<()> {
+    let mut connection = pool.get().expect("Failed to get connection from pool");
+
+    let transaction = connection.transaction()?;
+
+    for table in ["team_invitations", "team_members", "teams"] {
+        transaction.execute(
+            &format!("DELETE FROM {} WHERE team_id = ?1", table),
+            params![team_id],
+        )?;
+    }
+
+    transaction.commit()
+}
+
+// Soft deleted nodes don't count, they can't be brought back once the team is gone
+pub fn count_team_nodes(pool: Arc<Pool<SqliteConnectionManager>>, team_id: &str) -> Result<u32> {
+    let connection = pool.get().expect("Failed to get connection from pool");
+
+    connection.query_row(
+        "SELECT COUNT(*) FROM nodes WHERE team_id = ?1 AND deleted_at IS NULL",
+        params![team_id],
+        |row| row.get(0),
+    )
+}
+
+pub fn fetch_team_roles(
+    pool: Arc<Pool<SqliteConnectionManager>>,
+    email: &str,
+) -> Result<HashMap<String, TeamRoles>> {
+    let connection = pool.get().expect("Failed to get connection from pool");
+
+    let mut stmt = connection.prepare("SELECT team_id, role FROM team_members WHERE email = ?1")?;
+
+    let team_roles = stmt
+        .query_map(params![email.trim().to_lowercase()], |row| {
+            let role: i32 = row.get(1)?;
+            Ok((
+                row.get(0)?,
+                TeamRoles::try_from(role).unwrap_or(TeamRoles::TeamViewer),
+            ))
+        })?
+        .collect::<Result<HashMap<String, TeamRoles>>>()?;
+
+    Ok(team_roles)
+}
+
+fn row_to_team_invitation(row: &rusqlite::Row) -> Result<TeamInvitation> {
+    Ok(TeamInvitation {
+        team_id: row.get(0)?,
+        team_name: row.get(1)?,
+        email: row.get(2)?,
+        role: row.get(3)?,
+        invited_by: row.get(4)?,
+        invited_at: row.get(5)?,
+    })
+}
+
+const TEAM_INVITATION_COLUMNS: &str =
+    "i.team_id, t.name, i.email, i.role, i.invited_by, i.invited_at FROM team_invitations i JOIN teams t ON t.team_id = i.team_id";
+
+// The team with its members and its open invitations
+pub fn fetch_team(pool: Arc<Pool<SqliteConnectionManager>>, team_id: &str) -> Result<Option<Team>> {
+    let connection = pool.get().expect("Failed to get connection from pool");
+
+    let mut stmt = connection.prepare("SELECT name FROM teams WHERE team_id = ?1")?;
+    let name: String = match stmt.query(params![team_id])?.next()? {
+        Some(row) => row.get(0)?,
+        None => return Ok(None),
+    };
+
+    let mut stmt = connection.prepare(
+        "SELECT team_id, email, role, joined_at FROM team_members WHERE team_id = ?1 ORDER BY email",
+    )?;
+    let members = stmt
+        .query_map(params![team_id], |row| {
+            Ok(TeamMember {
+                team_id: row.get(0)?,
+                email: row.get(1)?,
+                role: row.get(2)?,
+                joined_at: row.get(3)?,
+            })
+        })?
+        .collect::<Result<Vec<TeamMember>>>()?;
+
+    let mut stmt = connection.prepare(&format!(
+        "SELECT {} WHERE i.team_id = ?1 ORDER BY i.email",
+        TEAM_INVITATION_COLUMNS
+    ))?;
+    let invitations = stmt
+        .query_map(params![team_id], row_to_team_invitation)?
+        .collect::<Result<Vec<TeamInvitation>>>()?;
+
+    Ok(Some(Team {
+        team_id: team_id.to_string(),
+        name,
+        members,
+        invitations,
+    }))
+}
+
+pub fn fetch_team_invitations(
+    pool: Arc<Pool<SqliteConnectionManager>>,
+    email: &str,
+) -> Result<Vec<TeamInvitation>> {
+    let connection = pool.get().expect("Failed to get connection from pool");
+
+    let mut stmt = connection.prepare(&format!(
+        "SELECT {} WHERE i.email = ?1 ORDER BY i.invited_at",
+        TEAM_INVITATION_COLUMNS
+    ))?;
+
+    let invitations = stmt
+        .query_map(params![email], row_to_team_invitation)?
+        .collect::<Result<Vec<TeamInvitation>>>()?;
+
+    Ok(invitations)
+}
+
+pub fn fetch_team_invitation(
+    pool: Arc<Pool<SqliteConnectionManager>>,
+    team_id: &str,
+    email: &str,
+) -> Result<Option<TeamInvitation>> {
+    let connection = pool.get().expect("Failed to get connection from pool");
+
+    let mut stmt = connection.prepare(&format!(
+        "SELECT {} WHERE i.team_id = ?1 AND i.email = ?2",
+        TEAM_INVITATION_COLUMNS
+    ))?;
+    let mut rows = stmt.query(params![team_id, email])?;
+
+    match rows.next()? {
+        Some(row) => Ok(Some(row_to_team_invitation(row)?)),
+        None => Ok(None),
+    }
+}
+
+// Inviting someone again replaces the earlier invitation
+pub fn upsert_team_invitation(
+    pool: Arc<Pool<SqliteConnectionManager>>,
+    invitation: &TeamInvitation,
+) -> Result<()> {
+    let connection = pool.get().expect("Failed to get connection from pool");
+
+    connection.execute(
+        "INSERT OR REPLACE INTO team_invitations (team_id, email, role, invited_by, invited_at) VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![
+            invitation.team_id,
+            invitation.email,
+            invitation.role,
+            invitation.invited_by,
+            invitation.invited_at
+        ],
+    )?;
+    Ok(())
+}
+
+// Returns whether there was an invitation to delete
+pub fn delete_team_invitation(
+    pool: Arc<Pool<SqliteConnectionManager>>,
+    team_id: &str,
+    email: &str,
+) -> Result<bool> {
+    let connection = pool.get().expect("Failed to get connection from pool");
+
+    let deleted = connection.execute(
+        "DELETE FROM team_invitations WHERE team_id = ?1 AND email = ?2",
+        params![team_id, email],
+    )?;
+    Ok(deleted > 0)
+}
+
+pub fn accept_team_invitation(
+    pool: Arc<Pool<SqliteConnectionManager>>,
+    invitation: &TeamInvitation,
+) -> Result<()> {
+    let mut connection = pool.get().expect("Failed to get connection from pool");
+
+    let joined_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs() as i64)
+        .unwrap_or(0);
+
+    let transaction = connection.transaction()?;
+
+    transaction.execute(
+        "INSERT OR REPLACE INTO team_members (team_id, email, role, joined_at) VALUES (?1, ?2, ?3, ?4)",
+        params![invitation.team_id, invitation.email, invitation.role, joined_at],
+    )?;
+    transaction.execute(
+        "DELETE FROM team_invitations WHERE team_id = ?1 AND email = ?2",
+        params![invitation.team_id, invitation.email],
+    )?;
+
+    transaction.commit()
+}
+
+pub fn update_team_member_role(
+    pool: Arc<Pool<SqliteConnectionManager>>,
+    team_id: &str,
+    email: &str,
+    role: TeamRoles,
+) -> Result<()> {
+    let connection = pool.get().expect("Failed to get connection from pool");
+
+    connection.execute(
+        "UPDATE team_members SET role = ?1 WHERE team_id = ?2 AND email = ?3",
+        params![role as i32, team_id, email],
+    )?;
+    Ok(())
+}
+
+pub fn delete_team_member(
+    pool: Arc<Pool<SqliteConnectionManager>>,
+    team_id: &str,
+    email: &str,
+) -> Result<()> {
+    let connection = pool.get().expect("Failed to get connection from pool");
+
+    connection.execute(
+        "DELETE FROM team_members WHERE team_id = ?1 AND email = ?2",
+        params![team_id, email],
+    )?;
+    Ok(())
+}
+
+// The triggers stop the application from updating or deleting entries, so the audit log can only be added to
+pub fn create_audit_log_table(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS audit_log (
+            entry_id INTEGER PRIMARY KEY AUTOINCREMENT,
+            timestamp INTEGER NOT NULL,
+            user_email TEXT NOT NULL,
+            session_id TEXT NOT NULL,
+            verb INTEGER NOT NULL,
+            event INTEGER NOT NULL,
+            object_id TEXT NOT NULL,
+            source_ip TEXT NOT NULL,
+            detail TEXT NOT NULL
+        );
+        CREATE INDEX IF NOT EXISTS audit_log_by_user ON audit_log (user_email, timestamp);
+        CREATE INDEX IF NOT EXISTS audit_log_by_object ON audit_log (object_id, timestamp);
+        CREATE TRIGGER IF NOT EXISTS audit_log_no_updates BEFORE UPDATE ON audit_log
+        BEGIN
+            SELECT RAISE(ABORT, 'The audit log is append-only');
+        END;
+        CREATE TRIGGER IF NOT EXISTS audit_log_no_deletes BEFORE DELETE ON audit_log
+        BEGIN
+            SELECT RAISE(ABORT, 'The audit log is append-only');
+        END;",
+    )
+}
+
+pub fn insert_audit_entry(pool: Arc<Pool<SqliteConnectionManager>>, entry: &AuditEntry) -> Result<()> {
+    let connection = pool.get().expect("Failed to get connection from pool");
+
+    connection.execute(
+        "INSERT INTO audit_log (timestamp, user_email, session_id, verb, event, object_id, source_ip, detail)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+        params![
+            entry.timestamp,
+            entry.user_email,
+            entry.session_id,
+            entry.verb,
+            entry.event,
+            entry.object_id,
+            entry.source_ip,
+            entry.detail
+        ],
+    )?;
+    Ok(())
+}
+
+const DEFAULT_AUDIT_PAGE_SIZE: u32 = 100;
+const MAX_AUDIT_PAGE_SIZE: u32 = 1000;
+
+// Newest first, one page at a time unless every entry is asked for. Also returns how many entries match in total.
+pub fn fetch_audit_entries(
+    pool: Arc<Pool<SqliteConnectionManager>>,
+    query: &AuditQuery,
+    every_entry: bool,
+) -> Result<(Vec<AuditEntry>, u32)> {
+    let connection = pool.get().expect("Failed to get connection from pool");
+
+    let mut conditions = vec!["1 = 1".to_string()];
+    let mut values: Vec<Box<dyn rusqlite::ToSql>> = Vec::new();
+
+    if !query.user_email.is_empty() {
+        values.push(Box::new(query.user_email.trim().to_lowercase()));
+        conditions.push(format!("LOWER(user_email) = ?{}", values.len()));
+    }
+    if !query.object_id.is_empty() {
+        values.push(Box::new(query.object_id.clone()));
+        conditions.push(format!("object_id = ?{}", values.len()));
+    }
+    if query.since > 0 {
+        values.push(Box::new(query.since));
+        conditions.push(format!("timestamp >= ?{}", values.len()));
+    }
+    if query.until > 0 {
+        values.push(Box::new(query.until));
+        conditions.push(format!("timestamp <= ?{}", values.len()));
+    }
+
+    let filter = conditions.join(" AND ");
+
+    let total_count: u32 = connection.query_row(
+        &format!("SELECT COUNT(*) FROM audit_log WHERE {}", filter),
+        rusqlite::params_from_iter(values.iter()),
+        |row| row.get(0),
+    )?;
+
+    let page = if every_entry {
+        String::new()
+    } else {
+        let page_size = match query.page_size {
+            0 => DEFAULT_AUDIT_PAGE_SIZE,
+            page_size => page_size.min(MAX_AUDIT_PAGE_SIZE),
+        };
+        format!(
+            "LIMIT {} OFFSET {}",
+            page_size,
+            query.page as u64 * page_size as u64
+        )
+    };
+
+    let mut stmt = connection.prepare(&format!(
+        "SELECT entry_id, timestamp, user_email, session_id, verb, event, object_id, source_ip, detail
+        FROM audit_log WHERE {} ORDER BY entry_id DESC {}",
+        filter, page
+    ))?;
+
+    let entries = stmt
+        .query_map(rusqlite::params_from_iter(values.iter()), |row| {
+            Ok(AuditEntry {
+                entry_id: row.get(0)?,
+                timestamp: row.get(1)?,
+                user_email: row.get(2)?,
+                session_id: row.get(3)?,
+                verb: row.get(4)?,
+                event: row.get(5)?,
+                object_id: row.get(6)?,
+                source_ip: row.get(7)?,
+                detail: row.get(8)?,
+            })
+        })?
+        .collect::<Result<Vec<AuditEntry>>>()?;
+
+    Ok((entries, total_count))
+}
@@ -0,0 +1,358 @@
+use crate::access::{same_email, Viewer};
+use crate::env_vars_checker::check_env_variable_valid;
+use crate::generated_types::{Team, TeamInvitation, TeamMember, TeamRoles, Teams};
+use crate::sqlite_helper_functions::{
+    accept_team_invitation, count_team_nodes, delete_team, delete_team_invitation,
+    delete_team_member, fetch_team, fetch_team_invitation, fetch_team_invitations,
+    fetch_team_roles, insert_team, rename_team, update_team_member_role, upsert_team_invitation,
+};
+
+use colored::*;
+use r2d2::Pool;
+use r2d2_sqlite::SqliteConnectionManager;
+
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Emails are compared in lowercase everywhere teams are concerned
+fn normalize_email(email: &str) -> String {
+    email.trim().to_lowercase()
+}
+
+// The same rule that decides who can sign up: the email is in allowed_emails.txt, or ALLOW_ANY_EMAIL is set
+pub fn allowed_to_sign_up(email: &str) -> bool {
+    if check_env_variable_valid("ALLOW_ANY_EMAIL", vec!["TRUE".to_string()]).is_ok() {
+        return true;
+    }
+
+    match std::fs::read_to_string("./allowed_emails.txt") {
+        Ok(allowed_emails) => allowed_emails.lines().any(|allowed_email| {
+            !allowed_email.trim().is_empty() && same_email(allowed_email, email)
+        }),
+        Err(err) => {
+            println!("{} {}", "Unable to read allowed_emails.txt:".red(), err);
+            false
+        }
+    }
+}
+
+fn require_role(viewer: &Viewer, team_id: &str, role: TeamRoles) -> Result<(), String> {
+    match viewer.team_roles.get(team_id) {
+        Some(team_role) if *team_role >= role => Ok(()),
+        Some(_) => Err(format!(
+            "Only {} of team {} can do that",
+            match role {
+                TeamRoles::TeamAdmin => "admins",
+                TeamRoles::TeamEditor => "editors",
+                TeamRoles::TeamRunner => "runners",
+                TeamRoles::TeamViewer => "members",
+            },
+            team_id
+        )),
+        None => Err(format!("There is no team {}", team_id)),
+    }
+}
+
+fn team_member(
+    pool: Arc<Pool<SqliteConnectionManager>>,
+    team_id: &str,
+    email: &str,
+) -> Result<TeamMember, String> {
+    fetch_team(pool, team_id)
+        .map_err(|err| format!("Unable to fetch team {}: {:?}", team_id, err))?
+        .and_then(|team| {
+            team.members
+                .into_iter()
+                .find(|member| same_email(&member.email, email))
+        })
+        .ok_or_else(|| format!("{} is not a member of team {}", email, team_id))
+}
+
+// A team can't be left without anyone to manage it
+fn check_other_admin(
+    pool: Arc<Pool<SqliteConnectionManager>>,
+    team_id: &str,
+    email: &str,
+) -> Result<(), String> {
+    let team = fetch_team(pool, team_id)
+        .map_err(|err| format!("Unable to fetch team {}: {:?}", team_id, err))?
+        .ok_or_else(|| format!("There is no team {}", team_id))?;
+
+    let other_admins = team
+        .members
+        .iter()
+        .filter(|member| member.role == TeamRoles::TeamAdmin as i32)
+        .filter(|member| !same_email(&member.email, email))
+        .count();
+
+    if other_admins == 0 {
+        Err(format!(
+            "Team {} needs at least one other admin first",
+            team.name
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+// The teams the user is a member of (only admins see the open invitations of a team) and the invitations they have yet to answer
+pub fn list_teams(pool: Arc<Pool<SqliteConnectionManager>>, email: &str) -> Result<Teams, String> {
+    let email = normalize_email(email);
+
+    let team_roles = fetch_team_roles(pool.clone(), &email)
+        .map_err(|err| format!("Unable to fetch teams: {:?}", err))?;
+
+    let mut teams = Vec::new();
+    for (team_id, role) in team_roles {
+        if let Some(mut team) = fetch_team(pool.clone(), &team_id)
+            .map_err(|err| format!("Unable to fetch team {}: {:?}", team_id, err))?
+        {
+            if role != TeamRoles::TeamAdmin {
+                team.invitations.clear();
+            }
+            teams.push(team);
+        }
+    }
+    teams.sort_by(|team, other_team| {
+        team.name
+            .to_lowercase()
+            .cmp(&other_team.name.to_lowercase())
+    });
+
+    let invitations = fetch_team_invitations(pool, &email)
+        .map_err(|err| format!("Unable to fetch invitations: {:?}", err))?;
+
+    Ok(Teams { teams, invitations })
+}
+
+pub fn create_team(
+    pool: Arc<Pool<SqliteConnectionManager>>,
+    viewer: &Viewer,
+    team: &Team,
+) -> Result<Teams, String> {
+    let name = team.name.trim();
+    if name.is_empty() {
+        return Err("Teams need a name".to_string());
+    }
+    if viewer.email.is_empty() {
+        return Err("Teams can only be created once the user is known".to_string());
+    }
+
+    let team_id = uuid::Uuid::new_v4().to_string();
+
+    insert_team(
+        pool.clone(),
+        &team_id,
+        name,
+        &normalize_email(&viewer.email),
+    )
+    .map_err(|err| format!("Unable to create team {}: {:?}", name, err))?;
+
+    println!("{} {} ({})", "Team created:".green(), name, team_id);
+
+    list_teams(pool, &viewer.email)
+}
+
+pub fn update_team(
+    pool: Arc<Pool<SqliteConnectionManager>>,
+    viewer: &Viewer,
+    team: &Team,
+) -> Result<Teams, String> {
+    require_role(viewer, &team.team_id, TeamRoles::TeamAdmin)?;
+
+    let name = team.name.trim();
+    if name.is_empty() {
+        return Err("Teams need a name".to_string());
+    }
+
+    rename_team(pool.clone(), &team.team_id, name)
+        .map_err(|err| format!("Unable to rename team {}: {:?}", team.team_id, err))?;
+
+    list_teams(pool, &viewer.email)
+}
+
+// The nodes of the library have to be moved or deleted first, otherwise nobody could get to them anymore
+pub fn remove_team(
+    pool: Arc<Pool<SqliteConnectionManager>>,
+    viewer: &Viewer,
+    team: &Team,
+) -> Result<Teams, String> {
+    require_role(viewer, &team.team_id, TeamRoles::TeamAdmin)?;
+
+    let node_count = count_team_nodes(pool.clone(), &team.team_id).map_err(|err| {
+        format!(
+            "Unable to count the nodes of team {}: {:?}",
+            team.team_id, err
+        )
+    })?;
+
+    if node_count > 0 {
+        return Err(format!(
+            "The library of team {} still has {} node(s)",
+            team.team_id, node_count
+        ));
+    }
+
+    delete_team(pool.clone(), &team.team_id)
+        .map_err(|err| format!("Unable to delete team {}: {:?}", team.team_id, err))?;
+
+    println!("{} {}", "Team deleted:".green(), team.team_id);
+
+    list_teams(pool, &viewer.email)
+}
+
+pub fn invite_member(
+    pool: Arc<Pool<SqliteConnectionManager>>,
+    viewer: &Viewer,
+    invitation: &TeamInvitation,
+) -> Result<Teams, String> {
+    require_role(viewer, &invitation.team_id, TeamRoles::TeamAdmin)?;
+
+    let email = normalize_email(&invitation.email);
+    if email.is_empty() {
+        return Err("Invitations need an email".to_string());
+    }
+    if !allowed_to_sign_up(&email) {
+        return Err(format!("{} is not allowed to sign up", email));
+    }
+    if team_member(pool.clone(), &invitation.team_id, &email).is_ok() {
+        return Err(format!("{} is already a member of the team", email));
+    }
+
+    let role = TeamRoles::try_from(invitation.role).unwrap_or(TeamRoles::TeamViewer);
+
+    upsert_team_invitation(
+        pool.clone(),
+        &TeamInvitation {
+            team_id: invitation.team_id.clone(),
+            team_name: String::new(),
+            email: email.clone(),
+            role: role as i32,
+            invited_by: normalize_email(&viewer.email),
+            invited_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|duration| duration.as_secs() as i64)
+                .unwrap_or(0),
+        },
+    )
+    .map_err(|err| format!("Unable to invite {}: {:?}", email, err))?;
+
+    println!("{} {} to {}", "Invited".green(), email, invitation.team_id);
+
+    list_teams(pool, &viewer.email)
+}
+
+// Only the invited user can accept, with the role they were invited with
+pub fn accept_invitation(
+    pool: Arc<Pool<SqliteConnectionManager>>,
+    viewer: &Viewer,
+    invitation: &TeamInvitation,
+) -> Result<Teams, String> {
+    let email = normalize_email(&viewer.email);
+
+    let stored_invitation = fetch_team_invitation(pool.clone(), &invitation.team_id, &email)
+        .map_err(|err| format!("Unable to fetch the invitation: {:?}", err))?
+        .ok_or_else(|| format!("There is no invitation to team {}", invitation.team_id))?;
+
+    accept_team_invitation(pool.clone(), &stored_invitation)
+        .map_err(|err| format!("Unable to join team {}: {:?}", invitation.team_id, err))?;
+
+    println!(
+        "{} {} joined {}",
+        "Team:".green(),
+        email,
+        stored_invitation.team_name
+    );
+
+    list_teams(pool, &viewer.email)
+}
+
+// The invited user declines the invitation, admins of the team take it back
+pub fn withdraw_invitation(
+    pool: Arc<Pool<SqliteConnectionManager>>,
+    viewer: &Viewer,
+    invitation: &TeamInvitation,
+) -> Result<Teams, String> {
+    let email = if invitation.email.is_empty() {
+        normalize_email(&viewer.email)
+    } else {
+        normalize_email(&invitation.email)
+    };
+
+    if !same_email(&email, &viewer.email) {
+        require_role(viewer, &invitation.team_id, TeamRoles::TeamAdmin)?;
+    }
+
+    let deleted = delete_team_invitation(pool.clone(), &invitation.team_id, &email)
+        .map_err(|err| format!("Unable to delete the invitation: {:?}", err))?;
+
+    if !deleted {
+        return Err(format!(
+            "There is no invitation to team {} for {}",
+            invitation.team_id, email
+        ));
+    }
+
+    list_teams(pool, &viewer.email)
+}
+
+pub fn change_member_role(
+    pool: Arc<Pool<SqliteConnectionManager>>,
+    viewer: &Viewer,
+    member: &TeamMember,
+) -> Result<Teams, String> {
+    require_role(viewer, &member.team_id, TeamRoles::TeamAdmin)?;
+
+    let stored_member = team_member(pool.clone(), &member.team_id, &member.email)?;
+    let role = TeamRoles::try_from(member.role).unwrap_or(TeamRoles::TeamViewer);
+
+    if stored_member.role == TeamRoles::TeamAdmin as i32 && role != TeamRoles::TeamAdmin {
+        check_other_admin(pool.clone(), &member.team_id, &stored_member.email)?;
+    }
+
+    update_team_member_role(pool.clone(), &member.team_id, &stored_member.email, role).map_err(
+        |err| {
+            format!(
+                "Unable to change the role of {}: {:?}",
+                stored_member.email, err
+            )
+        },
+    )?;
+
+    list_teams(pool, &viewer.email)
+}
+
+// Admins remove members, anyone can leave a team on their own
+pub fn remove_member(
+    pool: Arc<Pool<SqliteConnectionManager>>,
+    viewer: &Viewer,
+    member: &TeamMember,
+) -> Result<Teams, String> {
+    let email = if member.email.is_empty() {
+        viewer.email.clone()
+    } else {
+        member.email.clone()
+    };
+
+    if !same_email(&email, &viewer.email) {
+        require_role(viewer, &member.team_id, TeamRoles::TeamAdmin)?;
+    }
+
+    let stored_member = team_member(pool.clone(), &member.team_id, &email)?;
+
+    if stored_member.role == TeamRoles::TeamAdmin as i32 {
+        check_other_admin(pool.clone(), &member.team_id, &stored_member.email)?;
+    }
+
+    delete_team_member(pool.clone(), &member.team_id, &stored_member.email)
+        .map_err(|err| format!("Unable to remove {}: {:?}", stored_member.email, err))?;
+
+    println!(
+        "{} {} left {}",
+        "Team:".green(),
+        stored_member.email,
+        member.team_id
+    );
+
+    list_teams(pool, &viewer.email)
+}
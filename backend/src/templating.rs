@@ -0,0 +1,134 @@
+use crate::generated_types::{value::ValueType, Value};
+use crate::secrets::SecretValues;
+
+use handlebars::{handlebars_helper, no_escape, Handlebars};
+use serde_json::json;
+
+use std::collections::HashMap;
+
+const DEFAULT_JOIN_SEPARATOR: &str = ", ";
+const DEFAULT_SPLIT_SEPARATOR: &str = ",";
+
+// Renders a template (a prompt, a goal, ...) against the variable definitions and the secrets of the user. The name is only used to make error messages easier to follow.
+pub fn render_template(
+    name: &str,
+    template: &str,
+    variable_definitions: &HashMap<String, Value>,
+    secrets: &SecretValues,
+) -> Result<String, String> {
+    let mut handlebars = Handlebars::new();
+
+    // Templates are sent to language models and shells, not browsers, so nothing should be turned into HTML entities
+    handlebars.register_escape_fn(no_escape);
+
+    register_helpers(&mut handlebars);
+
+    handlebars
+        .render_template(template, &template_context(variable_definitions, secrets))
+        .map_err(|err| format!("Unable to fill in the {}: {}", name, err))
+}
+
+// Builds the context templates are rendered against. Values keep their structure so that lists can be iterated with {{#each}} and the fields of objects can be reached with dot paths. Secrets are kept apart from the variables under {{secrets.NAME}}.
+pub fn template_context(
+    variable_definitions: &HashMap<String, Value>,
+    secrets: &SecretValues,
+) -> serde_json::Value {
+    let mut context = serde_json::Map::new();
+
+    for (name, value) in variable_definitions {
+        context.insert(name.clone(), value_to_json(value));
+    }
+
+    if !secrets.is_empty() {
+        context.insert("secrets".to_string(), json!(secrets));
+    }
+
+    serde_json::Value::Object(context)
+}
+
+pub fn value_to_json(value: &Value) -> serde_json::Value {
+    match &value.value_type {
+        Some(ValueType::StringValue(s)) => json!(s),
+        // Whole numbers are rendered without a trailing ".0"
+        Some(ValueType::NumberValue(n)) if n.fract() == 0.0 && n.abs() < i64::MAX as f64 => {
+            json!(*n as i64)
+        }
+        Some(ValueType::NumberValue(n)) => json!(n),
+        Some(ValueType::StringList(string_list)) => json!(string_list.values),
+        Some(ValueType::BoolValue(b)) => json!(b),
+        Some(ValueType::IntegerValue(i)) => json!(i),
+        Some(ValueType::JsonValue(s)) => serde_json::from_str(s).unwrap_or_else(|_| json!(s)),
+        None => serde_json::Value::Null,
+    }
+}
+
+// Renders a single JSON value the way a person would write it: strings without their quotes and everything else as JSON
+fn plain_text(value: &serde_json::Value) -> String {
+    match value {
+        serde_json::Value::String(s) => s.clone(),
+        serde_json::Value::Null => "".to_string(),
+        other => other.to_string(),
+    }
+}
+
+fn is_blank(value: &serde_json::Value) -> bool {
+    match value {
+        serde_json::Value::Null => true,
+        serde_json::Value::String(s) => s.trim().is_empty(),
+        serde_json::Value::Array(values) => values.is_empty(),
+        serde_json::Value::Object(fields) => fields.is_empty(),
+        _ => false,
+    }
+}
+
+// {{join list}} or {{join list " | "}}
+handlebars_helper!(join: |list: Json, *args| {
+    let separator = args
+        .get(1)
+        .and_then(|separator| separator.as_str())
+        .unwrap_or(DEFAULT_JOIN_SEPARATOR);
+
+    match list.as_array() {
+        Some(values) => values.iter().map(plain_text).collect::<Vec<String>>().join(separator),
+        None => plain_text(list),
+    }
+});
+
+// {{json value}} renders any value (including lists and objects) as JSON
+handlebars_helper!(to_json: |value: Json| value.to_string());
+
+// {{truncate text 200}} keeps the first 200 characters
+handlebars_helper!(truncate: |text: Json, length: u64| {
+    plain_text(text).chars().take(length as usize).collect::<String>()
+});
+
+// {{default value "fallback"}} uses the fallback when the value is missing or empty
+handlebars_helper!(default: |value: Json, fallback: Json| {
+    if is_blank(value) {
+        fallback.clone()
+    } else {
+        value.clone()
+    }
+});
+
+// {{#each (split text ";")}} turns text into a list, splitting on commas unless told otherwise
+handlebars_helper!(split: |text: Json, *args| {
+    let separator = args
+        .get(1)
+        .and_then(|separator| separator.as_str())
+        .unwrap_or(DEFAULT_SPLIT_SEPARATOR);
+
+    plain_text(text)
+        .split(separator)
+        .map(|item| item.trim().to_string())
+        .filter(|item| !item.is_empty())
+        .collect::<Vec<String>>()
+});
+
+fn register_helpers(handlebars: &mut Handlebars) {
+    handlebars.register_helper("join", Box::new(join));
+    handlebars.register_helper("json", Box::new(to_json));
+    handlebars.register_helper("truncate", Box::new(truncate));
+    handlebars.register_helper("default", Box::new(default));
+    handlebars.register_helper("split", Box::new(split));
+}
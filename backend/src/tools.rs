@@ -0,0 +1,231 @@
+use crate::access::{can_run, Viewer};
+use crate::generated_types::{
+    node_content::NodeContent as NodeContentEnum, AtomicExecutionLog, Node, NodeTypes, Process,
+};
+use crate::graph::{json_object_to_values, process_to_execution, run_execution, ExecutionContext};
+use crate::llm::{request_tool_calls, tool, tool_calls_message, tool_result_message};
+use crate::output_schema::parameters_schema;
+use crate::secrets::redact;
+use crate::sqlite_helper_functions::fetch_node;
+use crate::templating::value_to_json;
+
+use async_openai::config::OpenAIConfig;
+use async_openai::types::{
+    ChatCompletionMessageToolCall, ChatCompletionRequestMessage, ChatCompletionTool,
+};
+use async_openai::Client;
+use colored::*;
+
+use std::collections::HashMap;
+
+// How many times the model gets to call tools before it has to answer
+const MAX_TOOL_ROUNDS: u32 = 5;
+// Tools can contain prompts with tools of their own. This stops a process that (indirectly) calls itself from running forever.
+const MAX_TOOL_DEPTH: u32 = 3;
+// The longest function name the model provider accepts
+const MAX_TOOL_NAME_LENGTH: usize = 64;
+
+// The processes a prompt node can call, keyed by the name the model knows them by
+pub struct PromptTools {
+    definitions: Vec<ChatCompletionTool>,
+    processes: HashMap<String, (Node, Process)>,
+}
+
+impl PromptTools {
+    pub fn is_empty(&self) -> bool {
+        self.definitions.is_empty()
+    }
+}
+
+// Function names may only contain letters, digits, underscores and dashes
+fn tool_name(node: &Node, taken: &HashMap<String, (Node, Process)>) -> String {
+    let node_info = node.node_info.clone().unwrap_or_default();
+
+    let mut name: String = node_info
+        .name
+        .chars()
+        .map(|character| {
+            if character.is_ascii_alphanumeric() || character == '_' || character == '-' {
+                character
+            } else {
+                '_'
+            }
+        })
+        .take(MAX_TOOL_NAME_LENGTH)
+        .collect();
+
+    if name.is_empty() {
+        name = "process".to_string();
+    }
+
+    // Two processes with the same name are told apart by a number at the end
+    let mut unique_name = name.clone();
+    let mut count = 2;
+    while taken.contains_key(&unique_name) {
+        let suffix = format!("_{}", count);
+        unique_name = format!(
+            "{}{}",
+            name.chars()
+                .take(MAX_TOOL_NAME_LENGTH - suffix.len())
+                .collect::<String>(),
+            suffix
+        );
+        count += 1;
+    }
+
+    unique_name
+}
+
+// Loads the saved process nodes a prompt exposes as tools
+pub fn load_tools(
+    tool_node_ids: &[String],
+    context: &ExecutionContext,
+) -> Result<PromptTools, String> {
+    let mut tools = PromptTools {
+        definitions: Vec::new(),
+        processes: HashMap::new(),
+    };
+
+    if tool_node_ids.is_empty() {
+        return Ok(tools);
+    }
+
+    if context.tool_depth >= MAX_TOOL_DEPTH {
+        return Err(format!(
+            "Tools can only call other tools {} levels deep",
+            MAX_TOOL_DEPTH
+        ));
+    }
+
+    let viewer = Viewer::load(context.pool.clone(), context.user_email.as_ref());
+
+    for tool_node_id in tool_node_ids {
+        let node = match fetch_node(context.pool.clone(), tool_node_id) {
+            Ok(Some(node)) if can_run(&node, &viewer) => node,
+            // Tools the user can't run are as good as missing
+            Ok(_) => return Err(format!("The tool {} doesn't exist", tool_node_id)),
+            Err(err) => {
+                return Err(format!(
+                    "Unable to load the tool {}: {:?}",
+                    tool_node_id, err
+                ))
+            }
+        };
+
+        let process = match (
+            NodeTypes::try_from(node.node_type),
+            node.node_content
+                .clone()
+                .and_then(|node_content| node_content.node_content),
+        ) {
+            (Ok(NodeTypes::Process), Some(NodeContentEnum::Process(process))) => process,
+            _ => return Err(format!("The tool {} is not a process", tool_node_id)),
+        };
+
+        let name = tool_name(&node, &tools.processes);
+        let description = node.node_info.clone().unwrap_or_default().description;
+
+        tools
+            .definitions
+            .push(tool(&name, &description, parameters_schema(&node)));
+        tools.processes.insert(name, (node, process));
+    }
+
+    Ok(tools)
+}
+
+// Lets the model call tools until it is ready to answer. The tool calls and their results are added to the messages so that the answer can build on them, and everything the called processes ran is added to the prompt histories.
+pub async fn call_tools(
+    client: &Client<OpenAIConfig>,
+    model: &str,
+    messages: &mut Vec<ChatCompletionRequestMessage>,
+    tools: &PromptTools,
+    prompt_histories: &mut Vec<AtomicExecutionLog>,
+    context: &ExecutionContext,
+) -> Result<(), String> {
+    for _round in 0..MAX_TOOL_ROUNDS {
+        let tool_calls =
+            request_tool_calls(client, model, messages.clone(), tools.definitions.clone())
+                .await
+                .map_err(|err| err.to_string())?;
+
+        if tool_calls.is_empty() {
+            return Ok(());
+        }
+
+        messages.push(tool_calls_message(&tool_calls).map_err(|err| err.to_string())?);
+
+        for tool_call in tool_calls {
+            let result = run_tool(&tool_call, tools, prompt_histories, context).await;
+
+            messages
+                .push(tool_result_message(&tool_call.id, &result).map_err(|err| err.to_string())?);
+        }
+    }
+
+    println!(
+        "{} {}",
+        "The model is still calling tools after rounds:".yellow(),
+        MAX_TOOL_ROUNDS
+    );
+
+    Ok(())
+}
+
+// Runs the process behind the tool call and returns what the model is told: the output variables of the process or what went wrong. Problems are handed to the model rather than failing the prompt so that it can try again or answer without the tool.
+async fn run_tool(
+    tool_call: &ChatCompletionMessageToolCall,
+    tools: &PromptTools,
+    prompt_histories: &mut Vec<AtomicExecutionLog>,
+    context: &ExecutionContext,
+) -> String {
+    let (node, process) = match tools.processes.get(&tool_call.function.name) {
+        Some(tool) => tool,
+        None => return format!("There is no tool called {}", tool_call.function.name),
+    };
+
+    let arguments = match serde_json::from_str::<serde_json::Value>(&tool_call.function.arguments) {
+        Ok(serde_json::Value::Object(arguments)) => arguments,
+        _ => return "The arguments have to be a JSON object".to_string(),
+    };
+
+    println!("{} {}", "Calling tool:".green(), tool_call.function.name);
+
+    let execution = process_to_execution(
+        json_object_to_values(&arguments),
+        process.clone(),
+        prompt_histories.clone(),
+        node.node_info.clone().unwrap_or_default().id,
+    );
+
+    // The called process doesn't take part in the conversation of the prompt that called it
+    let tool_context = ExecutionContext {
+        tool_depth: context.tool_depth + 1,
+        conversation: None,
+        ..context.clone()
+    };
+
+    match run_execution(execution, None, &tool_context).await {
+        Ok((progressed_execution, _)) => {
+            let outputs: serde_json::Map<String, serde_json::Value> = node
+                .output_variables
+                .iter()
+                .filter_map(|output_variable| {
+                    progressed_execution
+                        .current_variable_definitions
+                        .get(output_variable)
+                        .map(|value| (output_variable.clone(), value_to_json(value)))
+                })
+                .collect();
+
+            *prompt_histories = progressed_execution.atomic_history;
+
+            serde_json::Value::Object(outputs).to_string()
+        }
+        Err((_, err)) => {
+            let err = redact(&err, &context.secrets);
+            println!("{} {}", "Tool failed:".red(), err);
+            format!("The tool failed: {}", err)
+        }
+    }
+}
@@ -0,0 +1,155 @@
+use crate::generated_types::{
+    node_content::NodeContent as NodeContentEnum, AtomicExecutionLog, Node, Transform,
+    TransformLanguages, Value, VariableTypes,
+};
+use crate::graph::{json_object_to_values, ExecutionContext};
+use crate::json_path::select_value;
+use crate::secrets::{redact, SecretValues};
+use crate::templating::{render_template, template_context, value_to_json};
+use crate::variables::{apply_output_types, declared_type, output_declaration};
+
+use regex::Regex;
+
+use std::collections::HashMap;
+
+// The text a regex is matched against: strings as they are and anything else as JSON
+fn source_text(value: &Value) -> String {
+    match value_to_json(value) {
+        serde_json::Value::String(s) => s,
+        other => other.to_string(),
+    }
+}
+
+fn capture(captures: &regex::Captures) -> String {
+    captures
+        .get(1)
+        .or_else(|| captures.get(0))
+        .map(|found| found.as_str().to_string())
+        .unwrap_or_default()
+}
+
+fn evaluate_regex(
+    expression: &str,
+    text: &str,
+    every_match: bool,
+) -> Result<serde_json::Value, String> {
+    let regex = Regex::new(expression)
+        .map_err(|err| format!("{} is not a valid regular expression: {}", expression, err))?;
+
+    if every_match {
+        return Ok(serde_json::Value::Array(
+            regex
+                .captures_iter(text)
+                .map(|captures| serde_json::Value::String(capture(&captures)))
+                .collect(),
+        ));
+    }
+
+    match regex.captures(text) {
+        Some(captures) => Ok(serde_json::Value::String(capture(&captures))),
+        None => Err(format!("Nothing matches {}", expression)),
+    }
+}
+
+fn evaluate(
+    node: &Node,
+    transform: &Transform,
+    output_variable: &str,
+    expression: &str,
+    variable_definitions: &HashMap<String, Value>,
+    secrets: &SecretValues,
+) -> Result<serde_json::Value, String> {
+    match TransformLanguages::try_from(transform.language).unwrap_or(TransformLanguages::Template) {
+        TransformLanguages::Template => Ok(serde_json::Value::String(render_template(
+            output_variable,
+            expression,
+            variable_definitions,
+            secrets,
+        )?)),
+        // Secrets are left out so that a selector can't copy one into a variable
+        TransformLanguages::JsonPath => select_value(
+            &template_context(variable_definitions, &SecretValues::new()),
+            expression,
+        ),
+        TransformLanguages::Regex => {
+            let source_variable = if transform.source_variable.is_empty() {
+                match node.input_variables.first() {
+                    Some(source_variable) => source_variable.clone(),
+                    None => return Err("A regex transform needs a source variable".to_string()),
+                }
+            } else {
+                transform.source_variable.clone()
+            };
+
+            let text = match variable_definitions.get(&source_variable) {
+                Some(value) => source_text(value),
+                None => {
+                    return Err(format!(
+                        "The source variable {} is not defined",
+                        source_variable
+                    ))
+                }
+            };
+
+            let every_match = declared_type(&output_declaration(node, output_variable))
+                == VariableTypes::StringListVariable;
+
+            evaluate_regex(expression, &text, every_match)
+        }
+    }
+}
+
+pub fn handle_transform(
+    node: &Node,
+    variable_definitions: &mut HashMap<String, Value>,
+    context: &ExecutionContext,
+) -> Result<AtomicExecutionLog, String> {
+    let transform = match node
+        .node_content
+        .clone()
+        .and_then(|node_content| node_content.node_content)
+    {
+        Some(NodeContentEnum::Transform(transform)) => transform,
+        _ => return Err("The transform node doesn't contain a transform".to_string()),
+    };
+
+    let mut outputs = serde_json::Map::new();
+    let mut applied = Vec::new();
+
+    for output_variable in &node.output_variables {
+        let expression = match transform.expressions.get(output_variable) {
+            Some(expression) => expression,
+            None => {
+                return Err(format!(
+                    "There is no expression for the output variable {}",
+                    output_variable
+                ))
+            }
+        };
+
+        let value = evaluate(
+            node,
+            &transform,
+            output_variable,
+            expression,
+            variable_definitions,
+            &context.secrets,
+        )
+        .map_err(|err| format!("Unable to fill in {}: {}", output_variable, err))?;
+
+        outputs.insert(output_variable.clone(), value);
+        applied.push(format!("{} = {}", output_variable, expression));
+    }
+
+    let mut response = json_object_to_values(&outputs);
+    apply_output_types(node, &mut response);
+
+    variable_definitions.extend(response.clone());
+
+    Ok(AtomicExecutionLog {
+        prompt: redact(&applied.join("\n"), &context.secrets),
+        response,
+        node_info: node.node_info.clone(),
+        cache_hit: false,
+    })
+}
@@ -0,0 +1,222 @@
+use crate::generated_types::{
+    value::ValueType, Node, StringList, Value, VariableDeclaration, VariableTypes,
+};
+
+use std::collections::HashMap;
+
+fn find_declaration<'a>(
+    declarations: &'a [VariableDeclaration],
+    name: &str,
+) -> Option<&'a VariableDeclaration> {
+    declarations
+        .iter()
+        .find(|declaration| declaration.name == name)
+}
+
+fn undeclared(name: &str) -> VariableDeclaration {
+    VariableDeclaration {
+        name: name.to_string(),
+        variable_type: VariableTypes::StringVariable as i32,
+        description: "".to_string(),
+        default_value: None,
+        required: false,
+    }
+}
+
+// Stands in for variables without a declaration wherever a type is needed. Whether they are required is up to the caller.
+pub fn output_declaration(node: &Node, name: &str) -> VariableDeclaration {
+    find_declaration(&node.output_variable_declarations, name)
+        .cloned()
+        .unwrap_or_else(|| undeclared(name))
+}
+
+pub fn input_declaration(node: &Node, name: &str) -> VariableDeclaration {
+    find_declaration(&node.input_variable_declarations, name)
+        .cloned()
+        .unwrap_or_else(|| undeclared(name))
+}
+
+pub fn declared_type(declaration: &VariableDeclaration) -> VariableTypes {
+    VariableTypes::try_from(declaration.variable_type).unwrap_or(VariableTypes::StringVariable)
+}
+
+pub fn type_name(variable_type: VariableTypes) -> &'static str {
+    match variable_type {
+        VariableTypes::StringVariable => "a string",
+        VariableTypes::NumberVariable => "a number",
+        VariableTypes::StringListVariable => "a list of strings",
+        VariableTypes::BooleanVariable => "a boolean",
+        VariableTypes::IntegerVariable => "an integer",
+        VariableTypes::ObjectVariable => "a JSON object",
+    }
+}
+
+// Both ends of a connection have to agree on the type of the variable. Connections where either end hasn't declared the variable are always allowed.
+pub fn check_connection(producer: &Node, consumer: &Node, name: &str) -> Result<(), String> {
+    let produced = find_declaration(&producer.output_variable_declarations, name);
+    let consumed = find_declaration(&consumer.input_variable_declarations, name);
+
+    match (produced, consumed) {
+        (Some(produced), Some(consumed)) if declared_type(produced) != declared_type(consumed) => {
+            Err(format!(
+                "Node \"{}\" outputs {} as {} but node \"{}\" expects {}",
+                producer.node_info.clone().unwrap_or_default().name,
+                name,
+                type_name(declared_type(produced)),
+                consumer.node_info.clone().unwrap_or_default().name,
+                type_name(declared_type(consumed))
+            ))
+        }
+        _ => Ok(()),
+    }
+}
+
+// The declarations a process (or loop) exposes for its own input variables are taken from the first inner node that declares them
+pub fn collect_input_declarations(nodes: &[Node], names: &[String]) -> Vec<VariableDeclaration> {
+    names
+        .iter()
+        .filter_map(|name| {
+            nodes
+                .iter()
+                .find_map(|node| find_declaration(&node.input_variable_declarations, name))
+                .cloned()
+        })
+        .collect()
+}
+
+pub fn collect_output_declarations(nodes: &[Node], names: &[String]) -> Vec<VariableDeclaration> {
+    names
+        .iter()
+        .filter_map(|name| {
+            nodes
+                .iter()
+                .find_map(|node| find_declaration(&node.output_variable_declarations, name))
+                .cloned()
+        })
+        .collect()
+}
+
+// Gets the variables ready for the node to run: missing inputs are filled in with their defaults and declared inputs are converted to their declared type. Fails when a required input is missing or a value can't be converted.
+pub fn prepare_inputs(
+    node: &Node,
+    variable_definitions: &mut HashMap<String, Value>,
+) -> Result<(), String> {
+    for declaration in &node.input_variable_declarations {
+        if !node.input_variables.contains(&declaration.name) {
+            continue;
+        }
+
+        let value = match variable_definitions.get(&declaration.name) {
+            Some(value) => value.clone(),
+            None => match &declaration.default_value {
+                Some(default_value) => default_value.clone(),
+                None if declaration.required => {
+                    return Err(format!(
+                        "The required input variable {} is not defined",
+                        declaration.name
+                    ));
+                }
+                None => continue,
+            },
+        };
+
+        let converted = convert_value(&value, declared_type(declaration)).map_err(|err| {
+            format!(
+                "The input variable {} is invalid: {}",
+                declaration.name, err
+            )
+        })?;
+
+        variable_definitions.insert(declaration.name.clone(), converted);
+    }
+
+    Ok(())
+}
+
+// Converts the outputs of a node to their declared types. Outputs that can't be converted are left as they are.
+pub fn apply_output_types(node: &Node, values: &mut HashMap<String, Value>) {
+    for declaration in &node.output_variable_declarations {
+        if let Some(value) = values.get(&declaration.name) {
+            if let Ok(converted) = convert_value(value, declared_type(declaration)) {
+                values.insert(declaration.name.clone(), converted);
+            }
+        }
+    }
+}
+
+// Converts a value to the given type. Strings are parsed since that is how the frontend sends every value.
+pub fn convert_value(value: &Value, variable_type: VariableTypes) -> Result<Value, String> {
+    let value_type = match value.value_type.clone() {
+        Some(value_type) => value_type,
+        None => return Err("the value is empty".to_string()),
+    };
+
+    let converted = match (variable_type, value_type) {
+        (VariableTypes::StringVariable, ValueType::StringValue(s)) => ValueType::StringValue(s),
+        (VariableTypes::StringVariable, ValueType::JsonValue(s)) => ValueType::StringValue(s),
+        (VariableTypes::StringVariable, ValueType::NumberValue(n)) => {
+            ValueType::StringValue(n.to_string())
+        }
+        (VariableTypes::StringVariable, ValueType::IntegerValue(i)) => {
+            ValueType::StringValue(i.to_string())
+        }
+        (VariableTypes::StringVariable, ValueType::BoolValue(b)) => {
+            ValueType::StringValue(b.to_string())
+        }
+        (VariableTypes::NumberVariable, ValueType::NumberValue(n)) => ValueType::NumberValue(n),
+        (VariableTypes::NumberVariable, ValueType::IntegerValue(i)) => {
+            ValueType::NumberValue(i as f64)
+        }
+        (VariableTypes::NumberVariable, ValueType::StringValue(s)) => ValueType::NumberValue(
+            s.trim()
+                .parse::<f64>()
+                .map_err(|_| format!("\"{}\" is not a number", s))?,
+        ),
+        (VariableTypes::IntegerVariable, ValueType::IntegerValue(i)) => ValueType::IntegerValue(i),
+        (VariableTypes::IntegerVariable, ValueType::NumberValue(n)) if n.fract() == 0.0 => {
+            ValueType::IntegerValue(n as i64)
+        }
+        (VariableTypes::IntegerVariable, ValueType::StringValue(s)) => ValueType::IntegerValue(
+            s.trim()
+                .parse::<i64>()
+                .map_err(|_| format!("\"{}\" is not an integer", s))?,
+        ),
+        (VariableTypes::BooleanVariable, ValueType::BoolValue(b)) => ValueType::BoolValue(b),
+        (VariableTypes::BooleanVariable, ValueType::StringValue(s)) => {
+            match s.trim().to_lowercase().as_str() {
+                "true" | "yes" | "1" => ValueType::BoolValue(true),
+                "false" | "no" | "0" => ValueType::BoolValue(false),
+                _ => return Err(format!("\"{}\" is not a boolean", s)),
+            }
+        }
+        (VariableTypes::StringListVariable, ValueType::StringList(list)) => {
+            ValueType::StringList(list)
+        }
+        (VariableTypes::StringListVariable, ValueType::StringValue(s)) => {
+            ValueType::StringList(StringList {
+                values: s
+                    .split(',')
+                    .map(|item| item.trim().to_string())
+                    .filter(|item| !item.is_empty())
+                    .collect(),
+            })
+        }
+        (VariableTypes::ObjectVariable, ValueType::JsonValue(s))
+        | (VariableTypes::ObjectVariable, ValueType::StringValue(s)) => {
+            match serde_json::from_str::<serde_json::Value>(&s) {
+                Ok(serde_json::Value::Object(_)) => ValueType::JsonValue(s),
+                _ => return Err("the value is not a JSON object".to_string()),
+            }
+        }
+        (variable_type, _) => {
+            return Err(format!(
+                "the value can't be used as {}",
+                type_name(variable_type)
+            ));
+        }
+    };
+
+    Ok(Value {
+        value_type: Some(converted),
+    })
+}
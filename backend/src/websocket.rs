@@ -60,11 +60,9 @@ pub async fn start_websocket_server(
 
             thread_safe_request_dispatcher_clone_3
                 .lock().await
-                .insert(LocalServerIdentity::new(id.to_string()), local_tx);
+                .insert(LocalServerIdentity::new(id.to_string(), addr.ip().to_string()), local_tx);
 
-            let this_client = LocalServerIdentity {
-                name: id.to_string(),
-            };
+            let this_client = LocalServerIdentity::new(id.to_string(), addr.ip().to_string());
 
             let ws_stream = match tokio_tungstenite::accept_async(stream).await {
                 Ok(ws_stream) => ws_stream,
@@ -125,6 +123,11 @@ pub async fn start_websocket_server(
                     }
                 }
             }
+
+            // Let the message loop know the client is gone so it can clean up after it
+            if let Err(e) = client_tx.send((this_client.clone(), Message::Close(None))).await {
+                println!("Error sending message to client: {:?}", e);
+            }
         });
     }
 }